@@ -0,0 +1,4 @@
+// Embedded migrations are only re-read when this script says they changed.
+fn main() {
+    println!("cargo:rerun-if-changed=migrations");
+}
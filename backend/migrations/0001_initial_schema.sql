@@ -1,14 +1,35 @@
+CREATE TABLE IF NOT EXISTS workspace_info (
+    id INTEGER PRIMARY KEY CHECK (id = 1),
+    name VARCHAR(255) NOT NULL,
+    created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS shares (
+    id VARCHAR(64) PRIMARY KEY,
+    created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS series (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name VARCHAR(255) NOT NULL UNIQUE
+);
+
 CREATE TABLE IF NOT EXISTS seasons (
     id INTEGER PRIMARY KEY AUTOINCREMENT,
-    number INTEGER NOT NULL UNIQUE
+    series_id INTEGER NOT NULL REFERENCES series(id) ON DELETE CASCADE,
+    number INTEGER NOT NULL,
+    UNIQUE (series_id, number)
 );
 
 CREATE TABLE IF NOT EXISTS episodes (
     id INTEGER PRIMARY KEY AUTOINCREMENT,
     season_id INTEGER NOT NULL REFERENCES seasons(id) ON DELETE CASCADE,
     number INTEGER NOT NULL,
+    code VARCHAR(32) NOT NULL COLLATE NOCASE,
+    kind TEXT NOT NULL DEFAULT 'Regular',
+    sort_key INTEGER NOT NULL,
     title VARCHAR(255) NOT NULL,
-    UNIQUE (season_id, number)
+    UNIQUE (season_id, code)
 );
 
 CREATE TABLE IF NOT EXISTS speakers (
@@ -34,9 +55,11 @@ CREATE TABLE IF NOT EXISTS metadata (
     primary_emotion TEXT
 );
 
+CREATE INDEX IF NOT EXISTS idx_seasons_series_id ON seasons(series_id);
 CREATE INDEX IF NOT EXISTS idx_episodes_season_id ON episodes(season_id);
+CREATE INDEX IF NOT EXISTS idx_episodes_sort_key ON episodes(season_id, sort_key);
 CREATE INDEX IF NOT EXISTS idx_lines_season_id ON lines(season_id);
 CREATE INDEX IF NOT EXISTS idx_lines_episode_id ON lines(episode_id);
 CREATE INDEX IF NOT EXISTS idx_lines_speaker_id ON lines(speaker_id);
 CREATE INDEX IF NOT EXISTS idx_lines_content ON lines(content);
-CREATE INDEX IF NOT EXISTS idx_lines_line_number ON lines(line_number);
\ No newline at end of file
+CREATE INDEX IF NOT EXISTS idx_lines_line_number ON lines(line_number);
@@ -0,0 +1,23 @@
+-- Each line carries at most one metadata row, which goes away with the line.
+CREATE TABLE metadata_new (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    line_id INTEGER NOT NULL UNIQUE REFERENCES lines(id) ON DELETE CASCADE,
+    sentiment TEXT CHECK (sentiment IN ('Positive', 'Neutral', 'Negative')),
+    tone TEXT,
+    primary_emotion TEXT COLLATE NOCASE
+);
+
+INSERT INTO metadata_new (line_id, sentiment, tone, primary_emotion)
+SELECT m.line_id,
+       CASE WHEN m.sentiment IN ('Positive', 'Neutral', 'Negative') THEN m.sentiment END,
+       m.tone,
+       m.primary_emotion
+FROM metadata m
+JOIN lines l ON l.id = m.line_id
+WHERE m.id = (SELECT MAX(id) FROM metadata WHERE line_id = m.line_id);
+
+DROP TABLE metadata;
+ALTER TABLE metadata_new RENAME TO metadata;
+
+CREATE INDEX IF NOT EXISTS idx_metadata_sentiment ON metadata(sentiment);
+CREATE INDEX IF NOT EXISTS idx_metadata_primary_emotion ON metadata(primary_emotion);
@@ -0,0 +1,22 @@
+-- Metadata can come from several sources per line. Manual annotations and
+-- automatic taggers each keep their own row, so re-running a tagger never
+-- touches what a person entered.
+CREATE TABLE metadata_new (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    line_id INTEGER NOT NULL REFERENCES lines(id) ON DELETE CASCADE,
+    source TEXT NOT NULL DEFAULT 'manual',
+    model_version TEXT,
+    sentiment TEXT CHECK (sentiment IN ('Positive', 'Neutral', 'Negative')),
+    tone TEXT,
+    primary_emotion TEXT COLLATE NOCASE,
+    UNIQUE (line_id, source)
+);
+
+INSERT INTO metadata_new (id, line_id, source, sentiment, tone, primary_emotion)
+SELECT id, line_id, 'manual', sentiment, tone, primary_emotion FROM metadata;
+
+DROP TABLE metadata;
+ALTER TABLE metadata_new RENAME TO metadata;
+
+CREATE INDEX IF NOT EXISTS idx_metadata_sentiment ON metadata(sentiment);
+CREATE INDEX IF NOT EXISTS idx_metadata_primary_emotion ON metadata(primary_emotion);
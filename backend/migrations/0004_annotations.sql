@@ -0,0 +1,39 @@
+CREATE TABLE IF NOT EXISTS tags (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name VARCHAR(64) NOT NULL UNIQUE COLLATE NOCASE
+);
+
+CREATE TABLE IF NOT EXISTS line_tags (
+    line_id INTEGER NOT NULL REFERENCES lines(id) ON DELETE CASCADE,
+    tag_id INTEGER NOT NULL REFERENCES tags(id) ON DELETE CASCADE,
+    created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (line_id, tag_id)
+);
+
+CREATE TABLE IF NOT EXISTS notes (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    line_id INTEGER NOT NULL REFERENCES lines(id) ON DELETE CASCADE,
+    body TEXT NOT NULL,
+    created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS collections (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name VARCHAR(255) NOT NULL UNIQUE COLLATE NOCASE,
+    description TEXT,
+    created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Lines are listed in ascending position within each collection.
+CREATE TABLE IF NOT EXISTS collection_lines (
+    collection_id INTEGER NOT NULL REFERENCES collections(id) ON DELETE CASCADE,
+    line_id INTEGER NOT NULL REFERENCES lines(id) ON DELETE CASCADE,
+    position INTEGER NOT NULL,
+    PRIMARY KEY (collection_id, line_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_line_tags_tag_id ON line_tags(tag_id);
+CREATE INDEX IF NOT EXISTS idx_notes_line_id ON notes(line_id);
+CREATE INDEX IF NOT EXISTS idx_collection_lines_position ON collection_lines(collection_id, position);
+CREATE INDEX IF NOT EXISTS idx_collection_lines_line_id ON collection_lines(line_id);
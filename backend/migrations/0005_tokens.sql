@@ -0,0 +1,12 @@
+-- One row per word of each line, in order, as produced by the tokenizer.
+-- Offsets are byte positions in lines.content.
+CREATE TABLE IF NOT EXISTS tokens (
+    line_id INTEGER NOT NULL REFERENCES lines(id) ON DELETE CASCADE,
+    position INTEGER NOT NULL,
+    term TEXT NOT NULL,
+    start_offset INTEGER NOT NULL,
+    end_offset INTEGER NOT NULL,
+    PRIMARY KEY (line_id, position)
+) WITHOUT ROWID;
+
+CREATE INDEX IF NOT EXISTS idx_tokens_term ON tokens(term);
@@ -0,0 +1,19 @@
+-- Clusters of identical or near-identical lines found by the recurring-line
+-- job. The tables are rebuilt wholesale each time the job runs.
+CREATE TABLE IF NOT EXISTS recurring_clusters (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    text TEXT NOT NULL,
+    occurrence_count INTEGER NOT NULL,
+    episode_count INTEGER NOT NULL,
+    season_count INTEGER NOT NULL,
+    created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS recurring_lines (
+    line_id INTEGER PRIMARY KEY REFERENCES lines(id) ON DELETE CASCADE,
+    cluster_id INTEGER NOT NULL REFERENCES recurring_clusters(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_recurring_lines_cluster_id ON recurring_lines(cluster_id);
+CREATE INDEX IF NOT EXISTS idx_recurring_clusters_rank
+    ON recurring_clusters(occurrence_count DESC, season_count DESC);
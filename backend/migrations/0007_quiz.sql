@@ -0,0 +1,26 @@
+-- "Who said it?" quiz sessions and the questions asked in them. Choices are
+-- stored as a JSON array of speaker ids in the order they were shown.
+CREATE TABLE IF NOT EXISTS quiz_sessions (
+    id TEXT PRIMARY KEY,
+    difficulty TEXT NOT NULL CHECK (difficulty IN ('Easy', 'Medium', 'Hard')),
+    score INTEGER NOT NULL DEFAULT 0,
+    answered INTEGER NOT NULL DEFAULT 0,
+    correct INTEGER NOT NULL DEFAULT 0,
+    created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS quiz_questions (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    session_id TEXT NOT NULL REFERENCES quiz_sessions(id) ON DELETE CASCADE,
+    line_id INTEGER NOT NULL REFERENCES lines(id) ON DELETE CASCADE,
+    speaker_id INTEGER NOT NULL REFERENCES speakers(id) ON DELETE CASCADE,
+    choices TEXT NOT NULL,
+    difficulty TEXT NOT NULL,
+    distinctiveness REAL NOT NULL,
+    answer_speaker_id INTEGER REFERENCES speakers(id) ON DELETE SET NULL,
+    is_correct BOOLEAN,
+    created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    answered_at TEXT
+);
+
+CREATE INDEX IF NOT EXISTS idx_quiz_questions_session_id ON quiz_questions(session_id);
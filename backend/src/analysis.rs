@@ -0,0 +1,452 @@
+use crate::models::{DistinctiveTerm, KwicLine, KwicSort, NgramCount};
+use crate::text::{normalize, tokenize};
+use sqlx::query::{QueryAs, QueryScalar};
+use sqlx::sqlite::SqliteArguments;
+use sqlx::{FromRow, Sqlite, SqlitePool};
+use std::collections::{HashMap, HashSet};
+use std::sync::OnceLock;
+
+pub const MAX_NGRAM: usize = 4;
+/// The most matches a concordance sorted by context will read and sort.
+pub const MAX_SORTED_KWIC_MATCHES: usize = 5_000;
+
+const STOPWORD_LIST: &str = include_str!("../lexicon/stopwords.txt");
+
+/// The bundled stopword list, lowercase as stored in the token table.
+pub fn stopwords() -> &'static HashSet<&'static str> {
+    static STOPWORDS: OnceLock<HashSet<&'static str>> = OnceLock::new();
+    STOPWORDS.get_or_init(|| {
+        STOPWORD_LIST
+            .lines()
+            .map(str::trim)
+            .filter(|word| !word.is_empty() && !word.starts_with('#'))
+            .collect()
+    })
+}
+
+/// Which lines an analysis runs over. Unset fields do not filter.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LineScope {
+    pub series: Option<i64>,
+    pub season: Option<i64>,
+    pub episode: Option<i64>,
+    pub speaker: Option<i64>,
+}
+
+/// A query that [`LineScope::bind`] can bind its filter values to.
+pub trait ScopeBind: Sized {
+    fn bind_scope_value(self, value: Option<i64>) -> Self;
+}
+
+impl<'q, O> ScopeBind for QueryAs<'q, Sqlite, O, SqliteArguments<'q>> {
+    fn bind_scope_value(self, value: Option<i64>) -> Self {
+        self.bind(value)
+    }
+}
+
+impl<'q, O> ScopeBind for QueryScalar<'q, Sqlite, O, SqliteArguments<'q>> {
+    fn bind_scope_value(self, value: Option<i64>) -> Self {
+        self.bind(value)
+    }
+}
+
+impl LineScope {
+    /// Restricts `lines l JOIN seasons sn` to the scope. Bind its values with
+    /// [`LineScope::bind`] at the point the filter appears in the query.
+    pub const FILTER: &'static str = r#"
+        (? IS NULL OR sn.series_id = ?)
+        AND (? IS NULL OR l.season_id = ?)
+        AND (? IS NULL OR l.episode_id = ?)
+        AND (? IS NULL OR l.speaker_id = ?)
+    "#;
+
+    /// Binds the values [`LineScope::FILTER`] expects, in order.
+    pub fn bind<Q: ScopeBind>(&self, mut query: Q) -> Q {
+        for value in [self.series, self.season, self.episode, self.speaker] {
+            query = query.bind_scope_value(value).bind_scope_value(value);
+        }
+        query
+    }
+
+    /// True when the scope narrows the corpus below the series level.
+    pub fn is_subset(&self) -> bool {
+        self.season.is_some() || self.episode.is_some() || self.speaker.is_some()
+    }
+
+    fn corpus(&self) -> LineScope {
+        LineScope {
+            series: self.series,
+            ..LineScope::default()
+        }
+    }
+}
+
+/// Counts n-grams of `n` consecutive tokens within each line. With stopword
+/// removal, unigrams that are stopwords and longer n-grams that start or end
+/// with one are dropped. A negative `limit` returns every n-gram.
+pub async fn count_ngrams(
+    pool: &SqlitePool,
+    n: usize,
+    scope: LineScope,
+    exclude_stopwords: bool,
+    min_count: i64,
+    limit: i64,
+) -> Result<Vec<NgramCount>, sqlx::Error> {
+    let n = n.clamp(1, MAX_NGRAM);
+
+    let ngram = (1..=n)
+        .map(|i| format!("t{}.term", i))
+        .collect::<Vec<_>>()
+        .join(" || ' ' || ");
+    let joins: String = (2..=n)
+        .map(|i| {
+            format!(
+                " JOIN tokens t{i} ON t{i}.line_id = t1.line_id AND t{i}.position = t1.position + {offset}",
+                i = i,
+                offset = i - 1
+            )
+        })
+        .collect();
+
+    let mut sql = format!(
+        r#"
+        SELECT {ngram} AS ngram, COUNT(*) AS count
+        FROM tokens t1
+        {joins}
+        JOIN lines l ON l.id = t1.line_id
+        JOIN seasons sn ON sn.id = l.season_id
+        WHERE {filter}
+        "#,
+        ngram = ngram,
+        joins = joins,
+        filter = LineScope::FILTER,
+    );
+
+    let stopword_json = if exclude_stopwords {
+        let edges: &[usize] = if n == 1 { &[1] } else { &[1, n] };
+        for edge in edges {
+            sql.push_str(&format!(
+                " AND t{}.term NOT IN (SELECT value FROM json_each(?))",
+                edge
+            ));
+        }
+        let list: Vec<&str> = stopwords().iter().copied().collect();
+        Some((
+            edges.len(),
+            serde_json::to_string(&list).unwrap_or_default(),
+        ))
+    } else {
+        None
+    };
+
+    sql.push_str(" GROUP BY ngram HAVING COUNT(*) >= ? ORDER BY count DESC, ngram LIMIT ?");
+
+    let mut query = scope.bind(sqlx::query_as::<_, NgramCount>(&sql));
+    if let Some((edges, json)) = &stopword_json {
+        for _ in 0..*edges {
+            query = query.bind(json.as_str());
+        }
+    }
+
+    query.bind(min_count).bind(limit).fetch_all(pool).await
+}
+
+/// Dunning's log-likelihood (G²) for a term seen `a` times in a subcorpus of
+/// `c` tokens and `b` times in the remaining `d` tokens.
+fn log_likelihood(a: f64, b: f64, c: f64, d: f64) -> f64 {
+    let expected_a = c * (a + b) / (c + d);
+    let expected_b = d * (a + b) / (c + d);
+
+    let term = |observed: f64, expected: f64| {
+        if observed > 0.0 {
+            observed * (observed / expected).ln()
+        } else {
+            0.0
+        }
+    };
+
+    2.0 * (term(a, expected_a) + term(b, expected_b))
+}
+
+/// Ranks n-grams that are overrepresented in `scope` relative to the rest of
+/// the corpus (the whole workspace, or the scope's series) by log-likelihood.
+pub async fn distinctive_ngrams(
+    pool: &SqlitePool,
+    n: usize,
+    scope: LineScope,
+    exclude_stopwords: bool,
+    min_count: i64,
+    limit: usize,
+) -> Result<Vec<DistinctiveTerm>, sqlx::Error> {
+    let target = count_ngrams(pool, n, scope, exclude_stopwords, 1, -1).await?;
+    let corpus = count_ngrams(pool, n, scope.corpus(), exclude_stopwords, 1, -1).await?;
+
+    let target_total: i64 = target.iter().map(|ngram| ngram.count).sum();
+    let corpus_total: i64 = corpus.iter().map(|ngram| ngram.count).sum();
+    let rest_total = (corpus_total - target_total) as f64;
+    if target_total == 0 || rest_total <= 0.0 {
+        return Ok(Vec::new());
+    }
+
+    let corpus_counts: HashMap<&str, i64> = corpus
+        .iter()
+        .map(|ngram| (ngram.ngram.as_str(), ngram.count))
+        .collect();
+
+    let mut ranked: Vec<DistinctiveTerm> = target
+        .iter()
+        .filter(|ngram| ngram.count >= min_count)
+        .filter_map(|ngram| {
+            let corpus_count = corpus_counts
+                .get(ngram.ngram.as_str())
+                .copied()
+                .unwrap_or(ngram.count);
+            let a = ngram.count as f64;
+            let b = (corpus_count - ngram.count) as f64;
+            let c = target_total as f64;
+
+            // Only terms used more often here than elsewhere are distinctive.
+            if a / c <= b / rest_total {
+                return None;
+            }
+
+            Some(DistinctiveTerm {
+                ngram: ngram.ngram.clone(),
+                count: ngram.count,
+                corpus_count,
+                log_likelihood: log_likelihood(a, b, c, rest_total),
+            })
+        })
+        .collect();
+
+    ranked.sort_by(|a, b| {
+        b.log_likelihood
+            .total_cmp(&a.log_likelihood)
+            .then_with(|| a.ngram.cmp(&b.ngram))
+    });
+    ranked.truncate(limit);
+
+    Ok(ranked)
+}
+
+/// Marks where context crosses from one line into the next.
+const LINE_BREAK: &str = "/";
+
+#[derive(FromRow)]
+struct KeywordMatch {
+    line_id: i64,
+    position: i64,
+    series_id: i64,
+    season_id: i64,
+    episode_id: i64,
+}
+
+#[derive(FromRow)]
+struct EpisodeLine {
+    id: i64,
+    line_number: i32,
+    speaker_name: Option<String>,
+    content: String,
+}
+
+/// A word of an episode's running text and the line it belongs to.
+struct StreamWord<'a> {
+    line: usize,
+    text: &'a str,
+}
+
+/// Joins words with spaces, inserting a line break marker wherever
+/// neighbouring words come from different lines.
+fn join_words(words: &[StreamWord<'_>]) -> String {
+    let mut joined = String::new();
+    for (i, word) in words.iter().enumerate() {
+        if i > 0 {
+            joined.push(' ');
+            if words[i - 1].line != word.line {
+                joined.push_str(LINE_BREAK);
+                joined.push(' ');
+            }
+        }
+        joined.push_str(word.text);
+    }
+    joined
+}
+
+fn sort_key(context: &str, reverse: bool) -> Vec<String> {
+    let words = context
+        .split_whitespace()
+        .filter(|word| *word != LINE_BREAK)
+        .map(str::to_lowercase);
+    if reverse {
+        words.rev().collect()
+    } else {
+        words.collect()
+    }
+}
+
+pub enum KwicOutcome {
+    Lines(Vec<KwicLine>),
+    /// Sorting by context needs every match, and there were more than
+    /// [`MAX_SORTED_KWIC_MATCHES`].
+    TooManyMatches,
+}
+
+/// Finds occurrences of `phrase` in the scope and returns up to `limit` of
+/// them with up to `window` words of context on each side, drawn from the
+/// whole episode so context is not cut at line boundaries. Results are in
+/// broadcast order unless sorted by the left context (nearest word first) or
+/// right context.
+pub async fn keyword_in_context(
+    pool: &SqlitePool,
+    phrase: &str,
+    window: usize,
+    scope: LineScope,
+    sort: KwicSort,
+    limit: usize,
+) -> Result<KwicOutcome, sqlx::Error> {
+    let terms: Vec<String> = tokenize(phrase)
+        .iter()
+        .map(|token| normalize(token.text))
+        .collect();
+    if terms.is_empty() {
+        return Ok(KwicOutcome::Lines(Vec::new()));
+    }
+
+    let joins: String = (2..=terms.len())
+        .map(|i| {
+            format!(
+                " JOIN tokens t{i} ON t{i}.line_id = t1.line_id AND t{i}.position = t1.position + {offset} AND t{i}.term = ?",
+                i = i,
+                offset = i - 1
+            )
+        })
+        .collect();
+    let sql = format!(
+        r#"
+        SELECT t1.line_id, t1.position, sn.series_id, l.season_id, l.episode_id
+        FROM tokens t1
+        {joins}
+        JOIN lines l ON l.id = t1.line_id
+        JOIN seasons sn ON sn.id = l.season_id
+        JOIN episodes e ON e.id = l.episode_id
+        WHERE t1.term = ? AND {filter}
+        ORDER BY sn.series_id, sn.number, e.sort_key, l.line_number, t1.position
+        LIMIT ?
+        "#,
+        joins = joins,
+        filter = LineScope::FILTER,
+    );
+    // In broadcast order the first `limit` matches are the answer. Sorting by
+    // context needs them all, so read one past the cap to detect overflow.
+    let match_limit = match sort {
+        KwicSort::Position => limit,
+        KwicSort::Left | KwicSort::Right => MAX_SORTED_KWIC_MATCHES + 1,
+    };
+
+    let mut query = sqlx::query_as::<_, KeywordMatch>(&sql);
+    for term in terms.iter().skip(1) {
+        query = query.bind(term);
+    }
+    query = scope.bind(query.bind(&terms[0]));
+    let matches = query.bind(match_limit as i64).fetch_all(pool).await?;
+    if matches.len() > MAX_SORTED_KWIC_MATCHES {
+        return Ok(KwicOutcome::TooManyMatches);
+    }
+
+    let mut results = Vec::with_capacity(matches.len());
+    let mut start = 0;
+    while start < matches.len() {
+        let episode_id = matches[start].episode_id;
+        let end = start
+            + matches[start..]
+                .iter()
+                .take_while(|found| found.episode_id == episode_id)
+                .count();
+
+        let lines: Vec<EpisodeLine> = sqlx::query_as(
+            r#"
+            SELECT l.id, l.line_number, s.name AS speaker_name, l.content
+            FROM lines l
+            LEFT JOIN speakers s ON s.id = l.speaker_id
+            WHERE l.episode_id = ?
+            ORDER BY l.line_number
+            "#,
+        )
+        .bind(episode_id)
+        .fetch_all(pool)
+        .await?;
+
+        // Retokenizing gives the same positions as the stored tokens while
+        // keeping each word as it was written.
+        let mut stream = Vec::new();
+        let mut line_starts = HashMap::new();
+        for (index, line) in lines.iter().enumerate() {
+            line_starts.insert(line.id, (index, stream.len()));
+            stream.extend(tokenize(&line.content).into_iter().map(|token| StreamWord {
+                line: index,
+                text: token.text,
+            }));
+        }
+
+        for found in &matches[start..end] {
+            let Some(&(line_index, offset)) = line_starts.get(&found.line_id) else {
+                continue;
+            };
+            let at = offset + found.position as usize;
+            let after = at + terms.len();
+            if after > stream.len() {
+                continue;
+            }
+
+            let line = &lines[line_index];
+            results.push(KwicLine {
+                line_id: found.line_id,
+                series_id: found.series_id,
+                season_id: found.season_id,
+                episode_id,
+                line_number: line.line_number,
+                speaker_name: line.speaker_name.clone(),
+                left: join_words(&stream[at.saturating_sub(window)..at]),
+                keyword: join_words(&stream[at..after]),
+                right: join_words(&stream[after..(after + window).min(stream.len())]),
+            });
+        }
+
+        start = end;
+    }
+
+    match sort {
+        KwicSort::Position => {}
+        KwicSort::Left => results.sort_by_cached_key(|line| sort_key(&line.left, true)),
+        KwicSort::Right => results.sort_by_cached_key(|line| sort_key(&line.right, false)),
+    }
+    results.truncate(limit);
+
+    Ok(KwicOutcome::Lines(results))
+}
+
+fn tsv_field(value: &str) -> String {
+    value.replace(['\t', '\n', '\r'], " ")
+}
+
+/// Renders concordance lines as tab-separated values with a header row.
+pub fn kwic_to_tsv(lines: &[KwicLine]) -> String {
+    let mut tsv = String::from(
+        "line_id\tseries_id\tseason_id\tepisode_id\tline_number\tspeaker\tleft\tkeyword\tright\n",
+    );
+    for line in lines {
+        tsv.push_str(&format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+            line.line_id,
+            line.series_id,
+            line.season_id,
+            line.episode_id,
+            line.line_number,
+            tsv_field(line.speaker_name.as_deref().unwrap_or_default()),
+            tsv_field(&line.left),
+            tsv_field(&line.keyword),
+            tsv_field(&line.right),
+        ));
+    }
+    tsv
+}
@@ -0,0 +1,461 @@
+use crate::models::{Collection, CollectionExportRow, Note, Tag};
+use sqlx::SqlitePool;
+
+const MAX_TAG_LEN: usize = 64;
+const MAX_COLLECTION_NAME_LEN: usize = 255;
+const MAX_NOTE_LEN: usize = 10_000;
+
+const COLLECTION_QUERY: &str = r#"
+    SELECT c.id, c.name, c.description, c.created_at, COUNT(cl.line_id) AS line_count
+    FROM collections c
+    LEFT JOIN collection_lines cl ON cl.collection_id = c.id
+"#;
+
+/// Trims a tag name and rejects empty or oversized names. Tags may not contain
+/// whitespace so that `tag:` search terms stay unambiguous.
+pub fn validate_tag_name(name: &str) -> Result<String, String> {
+    let name = name.trim().trim_start_matches('#');
+    if name.is_empty() {
+        return Err("Tag name must not be empty".to_string());
+    }
+    if name.len() > MAX_TAG_LEN {
+        return Err(format!("Tag name must be at most {} bytes", MAX_TAG_LEN));
+    }
+    if name.chars().any(char::is_whitespace) {
+        return Err("Tag name must not contain spaces".to_string());
+    }
+    Ok(name.to_string())
+}
+
+/// Pulls `tag:name` terms out of a search phrase, returning the remaining
+/// phrase and the requested tags.
+pub fn split_tag_filters(phrase: &str) -> (String, Vec<String>) {
+    let mut tags = Vec::new();
+    let mut words = Vec::new();
+    for word in phrase.split_whitespace() {
+        match tag_filter(word) {
+            Some(tag) => tags.push(tag.to_string()),
+            None => words.push(word),
+        }
+    }
+
+    if tags.is_empty() {
+        return (phrase.to_string(), Vec::new());
+    }
+
+    (words.join(" "), tags)
+}
+
+fn tag_filter(word: &str) -> Option<&str> {
+    let prefix = word.get(..4)?;
+    let tag = &word[4..];
+    (prefix.eq_ignore_ascii_case("tag:") && !tag.is_empty()).then_some(tag)
+}
+
+pub fn validate_collection_name(name: &str) -> Result<String, String> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err("Collection name must not be empty".to_string());
+    }
+    if name.len() > MAX_COLLECTION_NAME_LEN {
+        return Err(format!(
+            "Collection name must be at most {} bytes",
+            MAX_COLLECTION_NAME_LEN
+        ));
+    }
+    Ok(name.to_string())
+}
+
+pub fn validate_note_body(body: &str) -> Result<String, String> {
+    let body = body.trim();
+    if body.is_empty() {
+        return Err("Note must not be empty".to_string());
+    }
+    if body.len() > MAX_NOTE_LEN {
+        return Err(format!("Note must be at most {} bytes", MAX_NOTE_LEN));
+    }
+    Ok(body.to_string())
+}
+
+pub async fn list_tags(pool: &SqlitePool) -> Result<Vec<Tag>, sqlx::Error> {
+    sqlx::query_as::<_, Tag>(
+        r#"
+        SELECT t.id, t.name, COUNT(lt.line_id) AS line_count
+        FROM tags t
+        LEFT JOIN line_tags lt ON lt.tag_id = t.id
+        GROUP BY t.id
+        ORDER BY t.name
+        "#,
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn line_tags(pool: &SqlitePool, line_id: i64) -> Result<Vec<Tag>, sqlx::Error> {
+    sqlx::query_as::<_, Tag>(
+        r#"
+        SELECT t.id, t.name
+        FROM tags t
+        JOIN line_tags lt ON lt.tag_id = t.id
+        WHERE lt.line_id = ?
+        ORDER BY t.name
+        "#,
+    )
+    .bind(line_id)
+    .fetch_all(pool)
+    .await
+}
+
+/// Tags a line, creating the tag if it is new. Tagging twice is a no-op.
+pub async fn add_line_tag(pool: &SqlitePool, line_id: i64, name: &str) -> Result<Tag, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+
+    sqlx::query("INSERT INTO tags (name) VALUES (?) ON CONFLICT(name) DO NOTHING")
+        .bind(name)
+        .execute(&mut *transaction)
+        .await?;
+    let tag = sqlx::query_as::<_, Tag>("SELECT id, name FROM tags WHERE name = ?")
+        .bind(name)
+        .fetch_one(&mut *transaction)
+        .await?;
+    sqlx::query("INSERT OR IGNORE INTO line_tags (line_id, tag_id) VALUES (?, ?)")
+        .bind(line_id)
+        .bind(tag.id)
+        .execute(&mut *transaction)
+        .await?;
+
+    transaction.commit().await?;
+    Ok(tag)
+}
+
+/// Removes a tag from a line, returning false if the line did not have it.
+/// Tags left without any lines are deleted.
+pub async fn remove_line_tag(
+    pool: &SqlitePool,
+    line_id: i64,
+    name: &str,
+) -> Result<bool, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+
+    let result = sqlx::query(
+        "DELETE FROM line_tags WHERE line_id = ? AND tag_id = (SELECT id FROM tags WHERE name = ?)",
+    )
+    .bind(line_id)
+    .bind(name)
+    .execute(&mut *transaction)
+    .await?;
+    sqlx::query(
+        "DELETE FROM tags WHERE name = ? AND NOT EXISTS (SELECT 1 FROM line_tags WHERE tag_id = tags.id)",
+    )
+    .bind(name)
+    .execute(&mut *transaction)
+    .await?;
+
+    transaction.commit().await?;
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn list_notes(pool: &SqlitePool, line_id: i64) -> Result<Vec<Note>, sqlx::Error> {
+    sqlx::query_as::<_, Note>(
+        "SELECT id, line_id, body, created_at, updated_at FROM notes WHERE line_id = ? ORDER BY id",
+    )
+    .bind(line_id)
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn add_note(pool: &SqlitePool, line_id: i64, body: &str) -> Result<Note, sqlx::Error> {
+    sqlx::query_as::<_, Note>(
+        "INSERT INTO notes (line_id, body) VALUES (?, ?) RETURNING id, line_id, body, created_at, updated_at",
+    )
+    .bind(line_id)
+    .bind(body)
+    .fetch_one(pool)
+    .await
+}
+
+pub async fn update_note(
+    pool: &SqlitePool,
+    note_id: i64,
+    body: &str,
+) -> Result<Option<Note>, sqlx::Error> {
+    sqlx::query_as::<_, Note>(
+        r#"
+        UPDATE notes SET body = ?, updated_at = CURRENT_TIMESTAMP
+        WHERE id = ?
+        RETURNING id, line_id, body, created_at, updated_at
+        "#,
+    )
+    .bind(body)
+    .bind(note_id)
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn delete_note(pool: &SqlitePool, note_id: i64) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query("DELETE FROM notes WHERE id = ?")
+        .bind(note_id)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn list_collections(pool: &SqlitePool) -> Result<Vec<Collection>, sqlx::Error> {
+    sqlx::query_as::<_, Collection>(&format!(
+        "{} GROUP BY c.id ORDER BY c.name",
+        COLLECTION_QUERY
+    ))
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn fetch_collection(
+    pool: &SqlitePool,
+    collection_id: i64,
+) -> Result<Option<Collection>, sqlx::Error> {
+    sqlx::query_as::<_, Collection>(&format!(
+        "{} WHERE c.id = ? GROUP BY c.id",
+        COLLECTION_QUERY
+    ))
+    .bind(collection_id)
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn create_collection(
+    pool: &SqlitePool,
+    name: &str,
+    description: Option<&str>,
+) -> Result<Collection, sqlx::Error> {
+    let id: i64 = sqlx::query_scalar(
+        "INSERT INTO collections (name, description) VALUES (?, ?) RETURNING id",
+    )
+    .bind(name)
+    .bind(description)
+    .fetch_one(pool)
+    .await?;
+
+    fetch_collection(pool, id)
+        .await?
+        .ok_or(sqlx::Error::RowNotFound)
+}
+
+/// Renames a collection or changes its description, leaving unset fields as they are.
+pub async fn update_collection(
+    pool: &SqlitePool,
+    collection_id: i64,
+    name: Option<&str>,
+    description: Option<&str>,
+) -> Result<Option<Collection>, sqlx::Error> {
+    sqlx::query(
+        "UPDATE collections SET name = COALESCE(?, name), description = COALESCE(?, description) WHERE id = ?",
+    )
+    .bind(name)
+    .bind(description)
+    .bind(collection_id)
+    .execute(pool)
+    .await?;
+
+    fetch_collection(pool, collection_id).await
+}
+
+pub async fn delete_collection(pool: &SqlitePool, collection_id: i64) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query("DELETE FROM collections WHERE id = ?")
+        .bind(collection_id)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Replaces the contents of a collection with `line_ids`, in that order.
+/// Repeated ids keep their first position.
+pub async fn set_collection_lines(
+    pool: &SqlitePool,
+    collection_id: i64,
+    line_ids: &[i64],
+) -> Result<(), sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+
+    sqlx::query("DELETE FROM collection_lines WHERE collection_id = ?")
+        .bind(collection_id)
+        .execute(&mut *transaction)
+        .await?;
+
+    for (position, line_id) in line_ids.iter().enumerate() {
+        sqlx::query(
+            "INSERT OR IGNORE INTO collection_lines (collection_id, line_id, position) VALUES (?, ?, ?)",
+        )
+        .bind(collection_id)
+        .bind(line_id)
+        .bind(position as i64)
+        .execute(&mut *transaction)
+        .await?;
+    }
+
+    transaction.commit().await
+}
+
+/// Inserts a line at `position`, or appends it when no position is given. A
+/// line already in the collection is moved rather than duplicated.
+pub async fn add_collection_line(
+    pool: &SqlitePool,
+    collection_id: i64,
+    line_id: i64,
+    position: Option<i64>,
+) -> Result<(), sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+
+    let previous: Option<i64> = sqlx::query_scalar(
+        "DELETE FROM collection_lines WHERE collection_id = ? AND line_id = ? RETURNING position",
+    )
+    .bind(collection_id)
+    .bind(line_id)
+    .fetch_optional(&mut *transaction)
+    .await?;
+    if let Some(previous) = previous {
+        sqlx::query(
+            "UPDATE collection_lines SET position = position - 1 WHERE collection_id = ? AND position > ?",
+        )
+        .bind(collection_id)
+        .bind(previous)
+        .execute(&mut *transaction)
+        .await?;
+    }
+
+    let end: i64 = sqlx::query_scalar(
+        "SELECT COALESCE(MAX(position) + 1, 0) FROM collection_lines WHERE collection_id = ?",
+    )
+    .bind(collection_id)
+    .fetch_one(&mut *transaction)
+    .await?;
+    let position = position.map_or(end, |position| position.clamp(0, end));
+
+    sqlx::query(
+        "UPDATE collection_lines SET position = position + 1 WHERE collection_id = ? AND position >= ?",
+    )
+    .bind(collection_id)
+    .bind(position)
+    .execute(&mut *transaction)
+    .await?;
+    sqlx::query("INSERT INTO collection_lines (collection_id, line_id, position) VALUES (?, ?, ?)")
+        .bind(collection_id)
+        .bind(line_id)
+        .bind(position)
+        .execute(&mut *transaction)
+        .await?;
+
+    transaction.commit().await
+}
+
+/// Removes a line from a collection, returning false if it was not there.
+pub async fn remove_collection_line(
+    pool: &SqlitePool,
+    collection_id: i64,
+    line_id: i64,
+) -> Result<bool, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+
+    let removed: Option<i64> = sqlx::query_scalar(
+        "DELETE FROM collection_lines WHERE collection_id = ? AND line_id = ? RETURNING position",
+    )
+    .bind(collection_id)
+    .bind(line_id)
+    .fetch_optional(&mut *transaction)
+    .await?;
+    if let Some(position) = removed {
+        sqlx::query(
+            "UPDATE collection_lines SET position = position - 1 WHERE collection_id = ? AND position > ?",
+        )
+        .bind(collection_id)
+        .bind(position)
+        .execute(&mut *transaction)
+        .await?;
+    }
+
+    transaction.commit().await?;
+    Ok(removed.is_some())
+}
+
+pub async fn collection_export_rows(
+    pool: &SqlitePool,
+    collection_id: i64,
+) -> Result<Vec<CollectionExportRow>, sqlx::Error> {
+    sqlx::query_as::<_, CollectionExportRow>(
+        r#"
+        SELECT
+            cl.position,
+            l.id AS line_id,
+            sr.name AS series,
+            sn.number AS season,
+            e.code AS episode,
+            e.title AS episode_title,
+            s.name AS speaker,
+            l.line_number,
+            l.content
+        FROM collection_lines cl
+        JOIN lines l ON l.id = cl.line_id
+        JOIN episodes e ON e.id = l.episode_id
+        JOIN seasons sn ON sn.id = l.season_id
+        JOIN series sr ON sr.id = sn.series_id
+        LEFT JOIN speakers s ON s.id = l.speaker_id
+        WHERE cl.collection_id = ?
+        ORDER BY cl.position
+        "#,
+    )
+    .bind(collection_id)
+    .fetch_all(pool)
+    .await
+}
+
+const EXPORT_CSV_HEADER: [&str; 9] = [
+    "position",
+    "line_id",
+    "series",
+    "season",
+    "episode",
+    "episode_title",
+    "speaker",
+    "line_number",
+    "content",
+];
+
+/// Writes export rows as CSV. The header is written even for an empty collection.
+pub fn export_rows_to_csv(rows: &[CollectionExportRow]) -> Result<Vec<u8>, csv::Error> {
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(Vec::new());
+    writer.write_record(EXPORT_CSV_HEADER)?;
+    for row in rows {
+        writer.serialize(row)?;
+    }
+    writer
+        .into_inner()
+        .map_err(|err| csv::Error::from(err.into_error()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_tag_filters_extracts_tags() {
+        let (phrase, tags) = split_tag_filters("TAG:funny hello tag:quotes");
+        assert_eq!(phrase, "hello");
+        assert_eq!(tags, vec!["funny", "quotes"]);
+
+        let (phrase, tags) = split_tag_filters("tag: hello");
+        assert_eq!(phrase, "tag: hello");
+        assert!(tags.is_empty());
+    }
+
+    #[test]
+    fn split_tag_filters_handles_non_ascii_phrases() {
+        let (phrase, tags) = split_tag_filters("café");
+        assert_eq!(phrase, "café");
+        assert!(tags.is_empty());
+
+        let (phrase, tags) = split_tag_filters("tagé résumé tag:café");
+        assert_eq!(phrase, "tagé résumé");
+        assert_eq!(tags, vec!["café"]);
+    }
+}
@@ -1,61 +1,156 @@
-use crate::db::setup_database;
-use crate::file_parser;
+use crate::analysis::{self, KwicOutcome, LineScope};
+use crate::annotations;
+use crate::auth::{
+    is_admin, require_db, validate_ttl, AuthedDb, Claims, Scope, TokenSigner, WritableDb,
+};
+use crate::cards;
+use crate::collocations::{self, AssociationOptions};
+use crate::db::{
+    backup_database, database_size, latest_schema_version, remove_database_files, setup_database,
+    BackupBusy,
+};
+use crate::file_parser::{self, parse_episode_code};
+use crate::interactions;
+use crate::markov::{self, LengthLimits, ModelCache};
+use crate::metadata;
+use crate::metrics;
 use crate::models::{
-    Episode, Line, RandomLineQuery, SearchPhrasesQuery, Season, Speaker, UserQuery,
+    AnalyzeQuery, CardQuery, CollectionLineRequest, CollectionLinesRequest, CollectionRequest,
+    CollectionUpdate, CollocationQuery, CooccurrenceQuery, Episode, EpisodeStatsQuery, ExportQuery,
+    GenerateQuery, GeneratedLines, ImportQuery, InitDbQuery, InteractionQuery, KwicQuery, Line,
+    MetadataRecord, MetadataRequest, MetricsQuery, NgramMode, NgramQuery, NoteRequest,
+    QuizAnswerRequest, QuizSessionRequest, RandomLineQuery, RandomMode, RecurringQuery,
+    SearchPhrasesQuery, Season, Series, SeriesQuery, SessionInfo, SimilarLine, SimilarLinesQuery,
+    Speaker, SpeakerMatrixQuery, StatsQuery, TagRequest, TokenRequest, Workspace, WorkspaceRequest,
 };
+use crate::quiz::{self, AnswerOutcome, QuestionOutcome};
+use crate::random::{self, LineFilter};
+use crate::recurring;
+use crate::registry::DatabaseRegistry;
+use crate::sentiment;
+use crate::similarity::{self, SimilarityFilter};
+use crate::stats;
+use crate::text;
+use crate::workspace;
 use actix_multipart::Multipart;
-use actix_web::{get, post, web, HttpResponse, Responder};
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType};
+use actix_web::middleware::from_fn;
+use actix_web::{
+    delete, get, patch, post, put, web, HttpMessage, HttpRequest, HttpResponse, Responder,
+};
 use futures_util::stream::StreamExt as _;
 use sanitize_filename::sanitize;
-use sqlx::SqlitePool;
 use std::collections::HashMap;
 use std::path::Path;
-use std::sync::Arc;
 use tokio::fs;
 use tokio::io::AsyncWriteExt;
-use tokio::sync::Mutex;
 use uuid::Uuid;
 use zip::ZipArchive;
 
-pub type DatabaseRegistry = Arc<Mutex<HashMap<String, SqlitePool>>>;
+/// Selects every column of [`Line`], including its metadata. A manual
+/// annotation takes precedence over tagger output.
+const LINE_SELECT: &str = r#"
+    SELECT
+        l.id,
+        sn.series_id,
+        l.season_id,
+        l.episode_id,
+        l.speaker_id,
+        s.name AS speaker_name,
+        l.line_number,
+        l.content,
+        m.sentiment,
+        m.tone,
+        m.primary_emotion,
+        m.source AS metadata_source
+    FROM lines l
+    JOIN seasons sn ON l.season_id = sn.id
+    LEFT JOIN speakers s ON l.speaker_id = s.id
+    LEFT JOIN metadata m ON m.id = (
+        SELECT id FROM metadata
+        WHERE line_id = l.id
+        ORDER BY source <> 'manual', source
+        LIMIT 1
+    )
+"#;
+
+const MAX_BULK_METADATA_BYTES: usize = 16 * 1024 * 1024;
 
 #[get("/init-db")]
 async fn init_db(
     db_registry: web::Data<DatabaseRegistry>,
-    schema_path: web::Data<String>,
+    signer: web::Data<TokenSigner>,
+    query: web::Query<InitDbQuery>,
 ) -> impl Responder {
-    let user_id = Uuid::new_v4().to_string();
-    let (db_pool, _db_path) = match setup_database(&user_id, schema_path.get_ref()).await {
-        Ok((pool, path)) => (pool, path),
-        Err(err) => {
-            eprintln!("Failed to set up database: {}", err);
-            return HttpResponse::InternalServerError().body("Failed to initialize database");
-        }
-    };
+    if query.scratch.unwrap_or(false) {
+        let user_id = Uuid::new_v4().to_string();
+        let (db_pool, db_path) = match setup_database(Path::new(workspace::SCRATCH_DIR), &user_id)
+            .await
+        {
+            Ok((pool, path)) => (pool, path),
+            Err(err) => {
+                eprintln!("Failed to set up database: {}", err);
+                return HttpResponse::InternalServerError().body("Failed to initialize database");
+            }
+        };
 
-    db_registry
-        .lock()
-        .await
-        .insert(user_id.clone(), db_pool.clone());
+        db_registry.insert(user_id.clone(), db_pool, db_path, true);
+        let claims = Claims::new(&user_id, Scope::Write, None);
+
+        return HttpResponse::Ok().json(serde_json::json!({
+            "user_id": user_id,
+            "token": signer.issue(&claims),
+            "expires_at": claims.exp,
+            "scratch": true,
+        }));
+    }
 
-    HttpResponse::Ok().json(serde_json::json!({ "user_id": user_id }))
+    let name = query
+        .name
+        .as_deref()
+        .unwrap_or(workspace::DEFAULT_WORKSPACE_NAME);
+
+    match register_new_workspace(&db_registry, name).await {
+        Ok(workspace) => {
+            let claims = Claims::new(&workspace.id, Scope::Write, None);
+            HttpResponse::Ok().json(serde_json::json!({
+                "user_id": workspace.id,
+                "token": signer.issue(&claims),
+                "expires_at": claims.exp,
+                "scratch": false,
+                "workspace": workspace,
+            }))
+        }
+        Err(response) => response,
+    }
 }
 
 #[get("/cleanup/{user_id}")]
 async fn cleanup_db(
     db_registry: web::Data<DatabaseRegistry>,
+    models: web::Data<ModelCache>,
     user_id: web::Path<String>,
+    WritableDb(db): WritableDb,
 ) -> impl Responder {
     let user_id = user_id.into_inner();
-    let removed = {
-        let mut registry = db_registry.lock().await;
-        registry.remove(&user_id)
-    };
 
-    if let Some(_pool) = removed {
-        let db_path = format!("./temp_dbs/{}.sqlite", user_id);
-        if let Err(err) = tokio::fs::remove_file(&db_path).await {
-            eprintln!("Failed to remove database file {}: {}", db_path, err);
+    if user_id != db.id {
+        return forbidden_database();
+    }
+
+    if workspace::is_workspace(&user_id) {
+        return HttpResponse::BadRequest()
+            .body("Persistent workspaces are removed with DELETE /workspaces/{id}");
+    }
+
+    models.invalidate(&user_id);
+    if let Some(entry) = db_registry.close(&user_id).await {
+        if let Err(err) = remove_database_files(&entry.path).await {
+            eprintln!(
+                "Failed to remove database file {}: {}",
+                entry.path.display(),
+                err
+            );
         }
         HttpResponse::Ok().body("Database cleaned up successfully")
     } else {
@@ -63,51 +158,399 @@ async fn cleanup_db(
     }
 }
 
-#[get("/search/phrases")]
-async fn search_phrases(
+async fn register_new_workspace(
+    db_registry: &DatabaseRegistry,
+    name: &str,
+) -> Result<Workspace, HttpResponse> {
+    let name = workspace::validate_name(name)
+        .map_err(|err| HttpResponse::BadRequest().json(serde_json::json!({ "error": err })))?;
+
+    let (created, db_pool) = workspace::create_workspace(&name).await.map_err(|err| {
+        eprintln!("Failed to create workspace: {}", err);
+        HttpResponse::InternalServerError().body("Failed to create workspace")
+    })?;
+
+    db_registry.insert(
+        created.id.clone(),
+        db_pool,
+        workspace::workspace_path(&created.id),
+        false,
+    );
+
+    Ok(created)
+}
+
+#[post("/workspaces")]
+async fn create_workspace(
+    db_registry: web::Data<DatabaseRegistry>,
+    signer: web::Data<TokenSigner>,
+    body: web::Json<WorkspaceRequest>,
+) -> impl Responder {
+    match register_new_workspace(&db_registry, &body.name).await {
+        Ok(workspace) => {
+            let claims = Claims::new(&workspace.id, Scope::Write, None);
+            HttpResponse::Created().json(serde_json::json!({
+                "token": signer.issue(&claims),
+                "expires_at": claims.exp,
+                "workspace": workspace,
+            }))
+        }
+        Err(response) => response,
+    }
+}
+
+#[post("/workspaces/import")]
+async fn import_workspace(
+    mut payload: Multipart,
+    db_registry: web::Data<DatabaseRegistry>,
+    signer: web::Data<TokenSigner>,
+    query: web::Query<ImportQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let name = match query.name.as_deref().map(workspace::validate_name) {
+        Some(Ok(name)) => Some(name),
+        Some(Err(err)) => {
+            return Ok(HttpResponse::BadRequest().json(serde_json::json!({ "error": err })))
+        }
+        None => None,
+    };
+
+    let temp_dir = "./temp_uploads";
+    fs::create_dir_all(temp_dir).await.map_err(|err| {
+        eprintln!("Failed to create temp directory: {}", err);
+        actix_web::error::ErrorInternalServerError("Failed to create temp directory")
+    })?;
+
+    let Some(item) = payload.next().await else {
+        return Ok(HttpResponse::BadRequest()
+            .json(serde_json::json!({ "error": "No database file was uploaded" })));
+    };
+    let mut field = item.map_err(|err| {
+        eprintln!("Error reading multipart field: {}", err);
+        actix_web::error::ErrorInternalServerError("Failed to process multipart data")
+    })?;
+
+    let filepath = format!("{}/import-{}.sqlite", temp_dir, Uuid::new_v4());
+    let mut f = fs::File::create(&filepath).await.map_err(|err| {
+        eprintln!("Failed to create file: {}", err);
+        actix_web::error::ErrorInternalServerError("Failed to create file")
+    })?;
+
+    while let Some(chunk) = field.next().await {
+        if let Ok(data) = chunk {
+            f.write_all(&data).await.map_err(|err| {
+                eprintln!("Error writing to file {}: {}", filepath, err);
+                actix_web::error::ErrorInternalServerError("Failed to write to file")
+            })?;
+        }
+    }
+    drop(f);
+
+    let imported = workspace::import_workspace(Path::new(&filepath), name.as_deref()).await;
+    // The file itself has already been moved into place when the import
+    // succeeded, but SQLite may have left its sidecar files behind.
+    if let Err(err) = remove_database_files(Path::new(&filepath)).await {
+        if err.kind() != std::io::ErrorKind::NotFound {
+            eprintln!("Failed to remove uploaded file {}: {}", filepath, err);
+        }
+    }
+
+    let (imported, db_pool) = match imported {
+        Ok(imported) => imported,
+        Err(err) => {
+            eprintln!("Failed to import workspace: {}", err);
+            return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+                "error": format!("Not a valid workspace database: {}", err),
+            })));
+        }
+    };
+
+    db_registry.insert(
+        imported.id.clone(),
+        db_pool,
+        workspace::workspace_path(&imported.id),
+        false,
+    );
+
+    let claims = Claims::new(&imported.id, Scope::Write, None);
+    Ok(HttpResponse::Created().json(serde_json::json!({
+        "token": signer.issue(&claims),
+        "expires_at": claims.exp,
+        "workspace": imported,
+    })))
+}
+
+#[get("/workspaces")]
+async fn list_workspaces(
+    req: HttpRequest,
+    db_registry: web::Data<DatabaseRegistry>,
+) -> impl Responder {
+    if !is_admin(&req) {
+        return forbidden_admin();
+    }
+
+    let ids = match workspace::workspace_ids().await {
+        Ok(ids) => ids,
+        Err(err) => {
+            eprintln!("Error listing workspaces: {}", err);
+            return HttpResponse::InternalServerError().body("Error listing workspaces");
+        }
+    };
+
+    let mut workspaces = Vec::with_capacity(ids.len());
+    for id in ids {
+        let workspace = match db_registry.get(&id) {
+            Some(pool) => workspace::fetch_workspace(&id, &pool).await,
+            None => workspace::read_workspace(&id).await,
+        };
+        match workspace {
+            Ok(workspace) => workspaces.push(workspace),
+            Err(err) => eprintln!("Error reading workspace {}: {}", id, err),
+        }
+    }
+    workspaces.sort_by(|a, b| (&a.created_at, &a.id).cmp(&(&b.created_at, &b.id)));
+
+    HttpResponse::Ok().json(workspaces)
+}
+
+/// Mints a token for a workspace on the admin's behalf. This is how a
+/// workspace whose write tokens have all expired is recovered.
+#[post("/workspaces/{id}/tokens")]
+async fn admin_issue_token(
+    req: HttpRequest,
+    id: web::Path<String>,
+    signer: web::Data<TokenSigner>,
+    body: web::Json<TokenRequest>,
+) -> impl Responder {
+    if !is_admin(&req) {
+        return forbidden_admin();
+    }
+
+    let id = id.into_inner();
+    if !workspace::is_workspace(&id) {
+        return HttpResponse::NotFound()
+            .json(serde_json::json!({ "error": "Workspace not found" }));
+    }
+    let ttl_secs = match validate_ttl(body.ttl_secs) {
+        Ok(ttl_secs) => ttl_secs,
+        Err(err) => return bad_request(err),
+    };
+
+    let claims = Claims::new(&id, body.scope, ttl_secs);
+    HttpResponse::Created().json(serde_json::json!({
+        "token": signer.issue(&claims),
+        "scope": claims.scope,
+        "expires_at": claims.exp,
+    }))
+}
+
+#[patch("/workspaces/{id}")]
+async fn rename_workspace(
+    id: web::Path<String>,
+    body: web::Json<WorkspaceRequest>,
+    WritableDb(db): WritableDb,
+) -> impl Responder {
+    let id = id.into_inner();
+    if id != db.id {
+        return forbidden_database();
+    }
+
+    let name = match workspace::validate_name(&body.name) {
+        Ok(name) => name,
+        Err(err) => return HttpResponse::BadRequest().json(serde_json::json!({ "error": err })),
+    };
+
+    if !workspace::is_workspace(&id) {
+        return HttpResponse::NotFound()
+            .json(serde_json::json!({ "error": "Workspace not found" }));
+    }
+
+    match workspace::rename_workspace(&id, &db.pool, &name).await {
+        Ok(workspace) => HttpResponse::Ok().json(workspace),
+        Err(err) => {
+            eprintln!("Error renaming workspace {}: {}", id, err);
+            HttpResponse::InternalServerError().body("Error renaming workspace")
+        }
+    }
+}
+
+#[delete("/workspaces/{id}")]
+async fn delete_workspace(
+    db_registry: web::Data<DatabaseRegistry>,
+    models: web::Data<ModelCache>,
+    id: web::Path<String>,
+    WritableDb(db): WritableDb,
+) -> impl Responder {
+    let id = id.into_inner();
+    if id != db.id {
+        return forbidden_database();
+    }
+
+    if !workspace::is_workspace(&id) {
+        return HttpResponse::NotFound()
+            .json(serde_json::json!({ "error": "Workspace not found" }));
+    }
+
+    db_registry.revoke_shares_for(&id);
+    db_registry.close(&id).await;
+    models.invalidate(&id);
+
+    match workspace::delete_workspace(&id).await {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(err) => {
+            eprintln!("Failed to delete workspace {}: {}", id, err);
+            HttpResponse::InternalServerError().body("Failed to delete workspace")
+        }
+    }
+}
+
+#[post("/workspaces/{id}/shares")]
+async fn create_share(
     db_registry: web::Data<DatabaseRegistry>,
-    query: web::Query<SearchPhrasesQuery>,
-    user_query: web::Query<UserQuery>,
+    id: web::Path<String>,
+    WritableDb(db): WritableDb,
 ) -> impl Responder {
-    let user_id = &user_query.user_id;
+    let id = id.into_inner();
+    if id != db.id {
+        return forbidden_database();
+    }
 
-    if user_id.is_empty() {
+    if !workspace::is_workspace(&id) {
         return HttpResponse::BadRequest()
-            .json(serde_json::json!({ "error": "User ID is required" }));
+            .json(serde_json::json!({ "error": "Only persistent workspaces can be shared" }));
     }
 
-    let db_pool = match get_db_pool(db_registry, &user_query.user_id).await {
-        Some(pool) => pool,
-        None => {
-            return HttpResponse::NotFound()
-                .json(serde_json::json!({ "error": "Database not found for user" }))
+    match workspace::create_share(&db.pool).await {
+        Ok(share) => {
+            db_registry.publish_share(share.id.clone(), id);
+            HttpResponse::Created().json(share)
+        }
+        Err(err) => {
+            eprintln!("Error creating share for workspace {}: {}", id, err);
+            HttpResponse::InternalServerError().body("Error creating share")
+        }
+    }
+}
+
+#[get("/workspaces/{id}/shares")]
+async fn list_shares(id: web::Path<String>, WritableDb(db): WritableDb) -> impl Responder {
+    if id.into_inner() != db.id {
+        return forbidden_database();
+    }
+
+    match workspace::list_shares(&db.pool).await {
+        Ok(shares) => HttpResponse::Ok().json(shares),
+        Err(err) => {
+            eprintln!("Error listing shares for workspace {}: {}", db.id, err);
+            HttpResponse::InternalServerError().body("Error listing shares")
+        }
+    }
+}
+
+#[delete("/workspaces/{id}/shares/{share_id}")]
+async fn revoke_share(
+    db_registry: web::Data<DatabaseRegistry>,
+    path: web::Path<(String, String)>,
+    WritableDb(db): WritableDb,
+) -> impl Responder {
+    let (id, share_id) = path.into_inner();
+    if id != db.id {
+        return forbidden_database();
+    }
+
+    match workspace::revoke_share(&db.pool, &share_id).await {
+        Ok(true) => {
+            db_registry.revoke_share(&share_id);
+            HttpResponse::NoContent().finish()
+        }
+        Ok(false) => {
+            HttpResponse::NotFound().json(serde_json::json!({ "error": "Share not found" }))
+        }
+        Err(err) => {
+            eprintln!("Error revoking share {}: {}", share_id, err);
+            HttpResponse::InternalServerError().body("Error revoking share")
+        }
+    }
+}
+
+/// Exchanges a public share ID for a read-only token on the shared workspace.
+#[get("/shared/{share_id}")]
+async fn open_share(
+    db_registry: web::Data<DatabaseRegistry>,
+    signer: web::Data<TokenSigner>,
+    share_id: web::Path<String>,
+) -> impl Responder {
+    let share_id = share_id.into_inner();
+    let not_found =
+        || HttpResponse::NotFound().json(serde_json::json!({ "error": "Share not found" }));
+
+    let Some(workspace_id) = db_registry.share_target(&share_id) else {
+        return not_found();
+    };
+    let Some(pool) = db_registry.get_or_reopen(&workspace_id).await else {
+        return not_found();
+    };
+
+    match workspace::fetch_workspace(&workspace_id, &pool).await {
+        Ok(workspace) => HttpResponse::Ok().json(serde_json::json!({
+            "token": signer.issue(&Claims::for_share(&workspace_id, &share_id)),
+            "scope": Scope::Read,
+            "workspace": workspace,
+        })),
+        Err(err) => {
+            eprintln!("Error reading shared workspace {}: {}", workspace_id, err);
+            HttpResponse::InternalServerError().body("Error opening share")
         }
+    }
+}
+
+#[post("/tokens")]
+async fn issue_token(
+    signer: web::Data<TokenSigner>,
+    body: web::Json<TokenRequest>,
+    WritableDb(db): WritableDb,
+) -> impl Responder {
+    let ttl_secs = match validate_ttl(body.ttl_secs) {
+        Ok(ttl_secs) => ttl_secs,
+        Err(err) => return bad_request(err),
     };
+    let claims = Claims::new(&db.id, body.scope, ttl_secs);
+    HttpResponse::Created().json(serde_json::json!({
+        "token": signer.issue(&claims),
+        "scope": claims.scope,
+        "expires_at": claims.exp,
+    }))
+}
+
+fn forbidden_database() -> HttpResponse {
+    HttpResponse::Forbidden()
+        .json(serde_json::json!({ "error": "Token does not grant access to this database" }))
+}
+
+fn forbidden_admin() -> HttpResponse {
+    HttpResponse::Forbidden().json(serde_json::json!({ "error": "Admin token required" }))
+}
+
+#[get("/search/phrases")]
+async fn search_phrases(query: web::Query<SearchPhrasesQuery>, db: AuthedDb) -> impl Responder {
+    let db_pool = db.pool;
 
-    let phrase = query.phrase.clone().unwrap_or_default();
+    let (phrase, tags) =
+        annotations::split_tag_filters(query.phrase.as_deref().unwrap_or_default());
+    let series = query.series;
     let season = query.season;
     let episode = query.episode;
     let speaker = query.speaker;
     let context_lines = query.context.unwrap_or(0);
 
-    let mut sql_query = String::from(
-        r#"
-        SELECT 
-            l.id, 
-            l.season_id, 
-            l.episode_id, 
-            l.speaker_id, 
-            s.name AS speaker_name, 
-            l.line_number, 
-            l.content
-        FROM lines l
-        LEFT JOIN speakers s ON l.speaker_id = s.id
-        WHERE l.content LIKE ?
-        "#,
-    );
+    let mut sql_query = format!("{} WHERE l.content LIKE ?", LINE_SELECT);
     let phrase_query = format!("%{}%", phrase);
     let mut params: Vec<Box<dyn std::fmt::Display>> = vec![Box::new(phrase_query)];
 
+    if let Some(series_id) = series {
+        sql_query.push_str(" AND sn.series_id = ?");
+        params.push(Box::new(series_id));
+    }
+
     if let Some(season_id) = season {
         sql_query.push_str(" AND l.season_id = ?");
         params.push(Box::new(season_id));
@@ -123,6 +566,28 @@ async fn search_phrases(
         params.push(Box::new(speaker_id));
     }
 
+    for tag in tags {
+        sql_query.push_str(
+            " AND EXISTS (SELECT 1 FROM line_tags lt JOIN tags t ON t.id = lt.tag_id WHERE lt.line_id = l.id AND t.name = ?)",
+        );
+        params.push(Box::new(tag));
+    }
+
+    if let Some(sentiment) = query.sentiment {
+        sql_query.push_str(" AND m.sentiment = ?");
+        params.push(Box::new(sentiment.as_str()));
+    }
+
+    if let Some(tone) = query.tone.clone() {
+        sql_query.push_str(" AND m.tone = ? COLLATE NOCASE");
+        params.push(Box::new(tone));
+    }
+
+    if let Some(emotion) = query.emotion.clone() {
+        sql_query.push_str(" AND m.primary_emotion = ?");
+        params.push(Box::new(emotion));
+    }
+
     let mut query_builder = sqlx::query_as::<_, Line>(&sql_query);
 
     for param in params {
@@ -141,22 +606,12 @@ async fn search_phrases(
         let mut results_with_context = vec![];
 
         for line in results {
-            let context_query = r#"
-                SELECT 
-                    l.id, 
-                    l.season_id, 
-                    l.episode_id, 
-                    l.speaker_id, 
-                    s.name AS speaker_name, 
-                    l.line_number, 
-                    l.content
-                FROM lines l
-                LEFT JOIN speakers s ON l.speaker_id = s.id
-                WHERE l.episode_id = ? AND l.line_number BETWEEN ? AND ?
-                ORDER BY l.line_number
-            "#;
-
-            let context: Vec<Line> = sqlx::query_as(context_query)
+            let context_query = format!(
+                "{} WHERE l.episode_id = ? AND l.line_number BETWEEN ? AND ? ORDER BY l.line_number",
+                LINE_SELECT
+            );
+
+            let context: Vec<Line> = sqlx::query_as(&context_query)
                 .bind(line.episode_id)
                 .bind(line.line_number - context_lines)
                 .bind(line.line_number + context_lines)
@@ -173,98 +628,170 @@ async fn search_phrases(
     HttpResponse::Ok().json(results)
 }
 
+/// Picks a random line. `seed` makes the pick repeatable, `mode=quote_of_the_day`
+/// seeds it from the date (today in UTC, or `date`), and `n` returns that many
+/// distinct lines as a list instead of a single line.
 #[get("/random-line")]
-async fn get_random_line(
-    db_registry: web::Data<DatabaseRegistry>,
-    query: web::Query<RandomLineQuery>,
-    user_query: web::Query<UserQuery>,
-) -> impl Responder {
-    let db_pool = match get_db_pool(db_registry, &user_query.user_id).await {
-        Some(pool) => pool,
-        None => {
-            return HttpResponse::BadRequest()
-                .json(serde_json::json!({ "error": "User ID is required" }))
+async fn get_random_line(query: web::Query<RandomLineQuery>, db: AuthedDb) -> impl Responder {
+    let db_pool = db.pool;
+
+    let filter = LineFilter {
+        series: query.series,
+        season: query.season,
+        episode: query.episode,
+        speaker: query.speaker,
+        min_words: query.min_words,
+        max_words: query.max_words,
+        require_speaker: query.require_speaker.unwrap_or(false),
+        exclude_asides: query.exclude_asides.unwrap_or(false),
+    };
+    let seed = match query.mode.unwrap_or_default() {
+        RandomMode::Random => query.seed,
+        RandomMode::QuoteOfTheDay => {
+            let date = query.date.clone().unwrap_or_else(random::today);
+            Some(random::seed_for_date(&date))
         }
     };
+    if query
+        .n
+        .is_some_and(|n| !(1..=random::MAX_SAMPLE).contains(&n))
+    {
+        return bad_request(format!("n must be between 1 and {}", random::MAX_SAMPLE));
+    }
 
-    let mut sql_query = String::from(
-        r#"
-        SELECT 
-            l.id, 
-            l.season_id,
-            l.episode_id,
-            l.speaker_id,
-            s.name AS speaker_name,
-            l.line_number,
-            l.content
-        FROM lines l
-        LEFT JOIN speakers s ON l.speaker_id = s.id
-        "#,
+    let mut rng = random::rng(seed);
+    let ids = match random::sample_line_ids(&db_pool, &filter, &mut rng, query.n.unwrap_or(1)).await
+    {
+        Ok(ids) => ids,
+        Err(err) => {
+            eprintln!("Error sampling random lines: {:?}", err);
+            return HttpResponse::InternalServerError().body("Error fetching random line");
+        }
+    };
+
+    let sql_query = format!(
+        "{} WHERE l.id IN (SELECT value FROM json_each(?))",
+        LINE_SELECT
     );
+    let lines = match sqlx::query_as::<_, Line>(&sql_query)
+        .bind(serde_json::to_string(&ids).unwrap_or_default())
+        .fetch_all(&db_pool)
+        .await
+    {
+        Ok(lines) => lines,
+        Err(err) => {
+            eprintln!("Error fetching random line: {:?}", err);
+            return HttpResponse::InternalServerError().body("Error fetching random line");
+        }
+    };
 
-    let mut conditions = Vec::new();
-    let mut params = Vec::new();
+    let mut lines: HashMap<i64, Line> = lines.into_iter().map(|line| (line.id, line)).collect();
+    let mut sample: Vec<Line> = ids.iter().filter_map(|id| lines.remove(id)).collect();
 
-    if let Some(season_id) = query.season {
-        conditions.push("l.season_id = ?");
-        params.push(season_id);
-    }
-    if let Some(episode_id) = query.episode {
-        conditions.push("l.episode_id = ?");
-        params.push(episode_id);
+    if query.n.is_some() {
+        return HttpResponse::Ok().json(sample);
     }
-    if let Some(speaker_id) = query.speaker {
-        conditions.push("l.speaker_id = ?");
-        params.push(speaker_id);
+    match sample.pop() {
+        Some(line) => HttpResponse::Ok().json(line),
+        None => HttpResponse::NotFound().body("No matching line found"),
     }
+}
 
-    if !conditions.is_empty() {
-        sql_query.push_str(" WHERE ");
-        sql_query.push_str(&conditions.join(" AND "));
+#[get("/generate")]
+async fn generate_lines(
+    query: web::Query<GenerateQuery>,
+    models: web::Data<ModelCache>,
+    db: AuthedDb,
+) -> impl Responder {
+    let order = query.order.unwrap_or(markov::DEFAULT_ORDER);
+    if !(1..=markov::MAX_ORDER).contains(&order) {
+        return bad_request(format!("order must be between 1 and {}", markov::MAX_ORDER));
     }
-
-    sql_query.push_str(" ORDER BY RANDOM() LIMIT 1;");
-
-    let mut query_builder = sqlx::query_as::<_, Line>(&sql_query);
-    for param in params {
-        query_builder = query_builder.bind(param);
+    let n = query.n.unwrap_or(1);
+    if !(1..=markov::MAX_LINES).contains(&n) {
+        return bad_request(format!("n must be between 1 and {}", markov::MAX_LINES));
+    }
+    let limits = LengthLimits {
+        min_words: query.min_words.unwrap_or(1),
+        max_words: query.max_words.unwrap_or(30),
+    };
+    if limits.max_words > markov::MAX_WORDS || limits.min_words > limits.max_words {
+        return bad_request(format!(
+            "Word limits must satisfy min_words <= max_words <= {}",
+            markov::MAX_WORDS
+        ));
     }
 
-    match query_builder.fetch_one(&db_pool).await {
-        Ok(line) => HttpResponse::Ok().json(line),
+    let speaker = match markov::find_speaker(&db.pool, &query.speaker).await {
+        Ok(Some(speaker)) => speaker,
+        Ok(None) => {
+            return HttpResponse::NotFound().body(format!("Speaker {} not found", query.speaker))
+        }
         Err(err) => {
-            eprintln!("Error fetching random line: {:?}", err);
-            HttpResponse::NotFound().body("No matching line found")
+            eprintln!("Error finding speaker {}: {}", query.speaker, err);
+            return HttpResponse::InternalServerError().body("Error generating lines");
         }
+    };
+
+    let model = match models
+        .get_or_train(&db.id, &db.pool, speaker.id, order)
+        .await
+    {
+        Ok(model) => model,
+        Err(err) => {
+            eprintln!("Error training model for speaker {}: {}", speaker.id, err);
+            return HttpResponse::InternalServerError().body("Error generating lines");
+        }
+    };
+
+    if model.is_empty() {
+        return HttpResponse::NotFound().body(format!(
+            "Speaker {} has no lines to learn from",
+            speaker.name
+        ));
     }
+
+    // Unseeded requests still report a seed so a good result can be replayed.
+    let seed = query.seed.unwrap_or_else(rand::random);
+    let lines = model.generate(&mut random::rng(Some(seed)), n, limits);
+    if lines.is_empty() {
+        return HttpResponse::Conflict().json(serde_json::json!({
+            "error": "Not enough lines from this speaker to generate a new one",
+        }));
+    }
+
+    HttpResponse::Ok().json(GeneratedLines {
+        speaker,
+        order,
+        seed,
+        lines,
+    })
 }
 
-#[get("/transcripts/{season_num}/{episode_num}")]
+#[get("/transcripts/{season_num}/{episode_code}")]
 async fn get_transcript(
-    db_registry: web::Data<DatabaseRegistry>,
-    path: web::Path<(i64, i32)>,
-    user_query: web::Query<UserQuery>,
+    path: web::Path<(i64, String)>,
+    series_query: web::Query<SeriesQuery>,
+    db: AuthedDb,
 ) -> impl Responder {
-    let user_id = &user_query.user_id;
+    let db_pool = db.pool;
 
-    if user_id.is_empty() {
-        return HttpResponse::BadRequest()
-            .json(serde_json::json!({ "error": "User ID is required" }));
-    }
-
-    let db_pool = match get_db_pool(db_registry, &user_query.user_id).await {
-        Some(pool) => pool,
+    let (season_num, episode_code) = path.into_inner();
+    let episode_code = match parse_episode_code(&episode_code) {
+        Some((_, _, code)) => code,
         None => {
-            return HttpResponse::NotFound()
-                .json(serde_json::json!({ "error": "Database not found for user" }))
+            return HttpResponse::BadRequest()
+                .body(format!("Invalid episode code: {}", episode_code))
         }
     };
 
-    let (season_num, episode_num) = path.into_inner();
+    let series = series_query.series;
 
     let season_exists: i64 = sqlx::query_scalar!(
-        "SELECT EXISTS(SELECT 1 FROM seasons WHERE number = ?)",
-        season_num
+        "SELECT EXISTS(SELECT 1 FROM seasons WHERE number = ? AND (? IS NULL OR series_id = ?))",
+        season_num,
+        series,
+        series
     )
     .fetch_one(&db_pool)
     .await
@@ -274,37 +801,35 @@ async fn get_transcript(
         return HttpResponse::NotFound().body(format!("Season {} not found", season_num));
     }
 
-    let episode_exists = sqlx::query_scalar!(
-        "SELECT EXISTS(SELECT 1 FROM episodes WHERE number = ?)",
-        episode_num
+    let episode_ids: Vec<i64> = sqlx::query_scalar(
+        "SELECT e.id FROM episodes e JOIN seasons sn ON e.season_id = sn.id WHERE sn.number = ? AND e.code = ? AND (? IS NULL OR sn.series_id = ?)",
     )
-    .fetch_one(&db_pool)
+    .bind(season_num)
+    .bind(&episode_code)
+    .bind(series)
+    .bind(series)
+    .fetch_all(&db_pool)
     .await
-    .unwrap_or(0);
+    .unwrap_or_default();
+
+    let episode_id = match episode_ids.as_slice() {
+        [] => return HttpResponse::NotFound().body(format!("Episode {} not found", episode_code)),
+        [episode_id] => *episode_id,
+        _ => {
+            return HttpResponse::BadRequest().body(format!(
+                "Episode {} exists in several series; specify a series",
+                episode_code
+            ))
+        }
+    };
 
-    if episode_exists == 0 {
-        return HttpResponse::NotFound().body(format!("Episode {} not found", episode_num));
-    }
+    let query = format!(
+        "{} WHERE l.episode_id = ? ORDER BY l.line_number",
+        LINE_SELECT
+    );
 
-    let query = r#"
-    SELECT 
-        l.id, 
-        l.season_id, 
-        l.episode_id, 
-        l.speaker_id, 
-        s.name AS speaker_name, 
-        l.line_number, 
-        l.content
-    FROM lines l
-    LEFT JOIN speakers s ON l.speaker_id = s.id
-    JOIN episodes e ON l.episode_id = e.id
-    JOIN seasons sn ON e.season_id = sn.id
-    WHERE sn.number = ? AND e.number = ?
-    "#;
-
-    let transcript = sqlx::query_as::<_, Line>(query)
-        .bind(season_num)
-        .bind(episode_num)
+    let transcript = sqlx::query_as::<_, Line>(&query)
+        .bind(episode_id)
         .fetch_all(&db_pool)
         .await;
 
@@ -317,29 +842,35 @@ async fn get_transcript(
     }
 }
 
-#[get("/seasons")]
-async fn get_seasons(
-    db_registry: web::Data<DatabaseRegistry>,
-    user_query: web::Query<UserQuery>,
-) -> impl Responder {
-    let user_id = &user_query.user_id;
-
-    if user_id.is_empty() {
-        return HttpResponse::BadRequest()
-            .json(serde_json::json!({ "error": "User ID is required" }));
-    }
+#[get("/series")]
+async fn get_series(db: AuthedDb) -> impl Responder {
+    let db_pool = db.pool;
 
-    let db_pool = match get_db_pool(db_registry, &user_query.user_id).await {
-        Some(pool) => pool,
-        None => {
-            return HttpResponse::NotFound()
-                .json(serde_json::json!({ "error": "Database not found for user" }))
+    let series = match sqlx::query_as::<_, Series>("SELECT * FROM series ORDER BY name")
+        .fetch_all(&db_pool)
+        .await
+    {
+        Ok(data) => data,
+        Err(err) => {
+            eprintln!("Error fetching series: {}", err);
+            return HttpResponse::InternalServerError().body("Error fetching series");
         }
     };
 
-    let seasons = match sqlx::query_as::<_, Season>("SELECT * FROM seasons")
-        .fetch_all(&db_pool)
-        .await
+    HttpResponse::Ok().json(series)
+}
+
+#[get("/seasons")]
+async fn get_seasons(series_query: web::Query<SeriesQuery>, db: AuthedDb) -> impl Responder {
+    let db_pool = db.pool;
+
+    let seasons = match sqlx::query_as::<_, Season>(
+        "SELECT * FROM seasons WHERE (? IS NULL OR series_id = ?) ORDER BY series_id, number",
+    )
+    .bind(series_query.series)
+    .bind(series_query.series)
+    .fetch_all(&db_pool)
+    .await
     {
         Ok(data) => data,
         Err(err) => {
@@ -352,28 +883,23 @@ async fn get_seasons(
 }
 
 #[get("/speakers")]
-async fn get_speakers(
-    db_registry: web::Data<DatabaseRegistry>,
-    user_query: web::Query<UserQuery>,
-) -> impl Responder {
-    let user_id = &user_query.user_id;
+async fn get_speakers(series_query: web::Query<SeriesQuery>, db: AuthedDb) -> impl Responder {
+    let db_pool = db.pool;
 
-    if user_id.is_empty() {
-        return HttpResponse::BadRequest()
-            .json(serde_json::json!({ "error": "User ID is required" }));
-    }
-
-    let db_pool = match get_db_pool(db_registry, &user_query.user_id).await {
-        Some(pool) => pool,
-        None => {
-            return HttpResponse::NotFound()
-                .json(serde_json::json!({ "error": "Database not found for user" }))
-        }
-    };
-
-    let speakers = match sqlx::query_as::<_, Speaker>("SELECT * FROM speakers")
-        .fetch_all(&db_pool)
-        .await
+    let speakers = match sqlx::query_as::<_, Speaker>(
+        r#"
+        SELECT * FROM speakers sp
+        WHERE ? IS NULL OR EXISTS (
+            SELECT 1 FROM lines l
+            JOIN seasons sn ON l.season_id = sn.id
+            WHERE l.speaker_id = sp.id AND sn.series_id = ?
+        )
+        "#,
+    )
+    .bind(series_query.series)
+    .bind(series_query.series)
+    .fetch_all(&db_pool)
+    .await
     {
         Ok(data) => data,
         Err(err) => {
@@ -385,31 +911,175 @@ async fn get_speakers(
     HttpResponse::Ok().json(speakers)
 }
 
-#[get("/seasons/{season_id}/episodes")]
-async fn get_episodes(
-    db_registry: web::Data<DatabaseRegistry>,
-    season_id: web::Path<i64>,
-    user_query: web::Query<UserQuery>,
+#[get("/speakers/stats")]
+async fn get_all_speaker_stats(
+    series_query: web::Query<SeriesQuery>,
+    db: AuthedDb,
 ) -> impl Responder {
-    let user_id = &user_query.user_id;
+    match stats::speaker_stats(&db.pool, series_query.series, None).await {
+        Ok(speaker_stats) => HttpResponse::Ok().json(speaker_stats),
+        Err(err) => {
+            eprintln!("Error computing speaker stats: {}", err);
+            HttpResponse::InternalServerError().body("Error computing speaker stats")
+        }
+    }
+}
 
-    if user_id.is_empty() {
-        return HttpResponse::BadRequest()
-            .json(serde_json::json!({ "error": "User ID is required" }));
+#[get("/speakers/{speaker_id}/stats")]
+async fn get_speaker_stats(
+    path: web::Path<i64>,
+    series_query: web::Query<SeriesQuery>,
+    db: AuthedDb,
+) -> impl Responder {
+    let speaker_id = path.into_inner();
+
+    match stats::speaker_stats(&db.pool, series_query.series, Some(speaker_id)).await {
+        Ok(mut speaker_stats) => match speaker_stats.pop() {
+            Some(found) => HttpResponse::Ok().json(found),
+            None => HttpResponse::NotFound().body(format!("No lines for speaker {}", speaker_id)),
+        },
+        Err(err) => {
+            eprintln!("Error computing stats for speaker {}: {}", speaker_id, err);
+            HttpResponse::InternalServerError().body("Error computing speaker stats")
+        }
     }
+}
 
-    let db_pool = match get_db_pool(db_registry, &user_query.user_id).await {
-        Some(pool) => pool,
-        None => {
-            return HttpResponse::NotFound()
-                .json(serde_json::json!({ "error": "Database not found for user" }))
+const DEFAULT_TOP_SPEAKERS: i64 = 10;
+const MAX_TOP_SPEAKERS: i64 = 100;
+const DEFAULT_MATRIX_SPEAKERS: i64 = 20;
+const MAX_MATRIX_SPEAKERS: i64 = 200;
+
+/// Lexical richness and readability per speaker, overall and per season.
+#[get("/speakers/metrics")]
+async fn get_all_speaker_metrics(query: web::Query<MetricsQuery>, db: AuthedDb) -> impl Responder {
+    match metrics::speaker_metrics(
+        &db.pool,
+        query.series,
+        query.season,
+        None,
+        query.min_lines.unwrap_or(1),
+    )
+    .await
+    {
+        Ok(speaker_metrics) => HttpResponse::Ok().json(speaker_metrics),
+        Err(err) => {
+            eprintln!("Error computing speaker metrics: {}", err);
+            HttpResponse::InternalServerError().body("Error computing speaker metrics")
         }
-    };
+    }
+}
+
+#[get("/speakers/{speaker_id}/metrics")]
+async fn get_speaker_metrics(
+    path: web::Path<i64>,
+    query: web::Query<MetricsQuery>,
+    db: AuthedDb,
+) -> impl Responder {
+    let speaker_id = path.into_inner();
+
+    match metrics::speaker_metrics(&db.pool, query.series, query.season, Some(speaker_id), 1).await
+    {
+        Ok(mut speaker_metrics) => match speaker_metrics.pop() {
+            Some(found) => HttpResponse::Ok().json(found),
+            None => HttpResponse::NotFound().body(format!("No lines for speaker {}", speaker_id)),
+        },
+        Err(err) => {
+            eprintln!(
+                "Error computing metrics for speaker {}: {}",
+                speaker_id, err
+            );
+            HttpResponse::InternalServerError().body("Error computing speaker metrics")
+        }
+    }
+}
+
+/// Lexical richness and readability of all dialogue in a season.
+#[get("/seasons/{season_id}/metrics")]
+async fn get_season_metrics(path: web::Path<i64>, db: AuthedDb) -> impl Responder {
+    let season_id = path.into_inner();
+
+    match metrics::season_metrics(&db.pool, season_id).await {
+        Ok(Some(season_metrics)) => HttpResponse::Ok().json(season_metrics),
+        Ok(None) => HttpResponse::NotFound().body(format!("No dialogue in season {}", season_id)),
+        Err(err) => {
+            eprintln!("Error computing metrics for season {}: {}", season_id, err);
+            HttpResponse::InternalServerError().body("Error computing season metrics")
+        }
+    }
+}
+
+#[get("/stats")]
+async fn get_corpus_stats(query: web::Query<StatsQuery>, db: AuthedDb) -> impl Responder {
+    let top = query
+        .top
+        .unwrap_or(DEFAULT_TOP_SPEAKERS)
+        .clamp(1, MAX_TOP_SPEAKERS);
+    match stats::corpus_stats(&db.pool, query.series, query.season, top).await {
+        Ok(corpus_stats) => HttpResponse::Ok().json(corpus_stats),
+        Err(err) => {
+            eprintln!("Error computing corpus stats: {}", err);
+            HttpResponse::InternalServerError().body("Error computing corpus stats")
+        }
+    }
+}
+
+#[get("/stats/speaker-matrix")]
+async fn get_speaker_matrix(query: web::Query<SpeakerMatrixQuery>, db: AuthedDb) -> impl Responder {
+    let speakers = query
+        .speakers
+        .unwrap_or(DEFAULT_MATRIX_SPEAKERS)
+        .clamp(1, MAX_MATRIX_SPEAKERS);
+    match stats::speaker_matrix(&db.pool, query.series, query.season, speakers).await {
+        Ok(matrix) => HttpResponse::Ok().json(matrix),
+        Err(err) => {
+            eprintln!("Error building speaker matrix: {}", err);
+            HttpResponse::InternalServerError().body("Error building speaker matrix")
+        }
+    }
+}
+
+#[get("/episodes/{episode_id}/stats")]
+async fn get_episode_stats(
+    path: web::Path<i64>,
+    query: web::Query<EpisodeStatsQuery>,
+    db: AuthedDb,
+) -> impl Responder {
+    let episode_id = path.into_inner();
+
+    let top = query
+        .top
+        .unwrap_or(DEFAULT_TOP_SPEAKERS)
+        .clamp(1, MAX_TOP_SPEAKERS);
+    match stats::episode_stats(&db.pool, episode_id, top).await {
+        Ok(Some(episode_stats)) => HttpResponse::Ok().json(episode_stats),
+        Ok(None) => HttpResponse::NotFound().body(format!("Episode {} not found", episode_id)),
+        Err(err) => {
+            eprintln!("Error computing stats for episode {}: {}", episode_id, err);
+            HttpResponse::InternalServerError().body("Error computing episode stats")
+        }
+    }
+}
+
+#[get("/seasons/{season_id}/episodes")]
+async fn get_episodes(
+    season_id: web::Path<i64>,
+    series_query: web::Query<SeriesQuery>,
+    db: AuthedDb,
+) -> impl Responder {
+    let db_pool = db.pool;
 
     let episodes = match sqlx::query_as::<_, Episode>(
-        "SELECT * FROM episodes WHERE season_id = ? ORDER BY number ASC",
+        r#"
+        SELECT e.* FROM episodes e
+        JOIN seasons sn ON e.season_id = sn.id
+        WHERE e.season_id = ? AND (? IS NULL OR sn.series_id = ?)
+        ORDER BY e.sort_key ASC
+        "#,
     )
     .bind(season_id.into_inner())
+    .bind(series_query.series)
+    .bind(series_query.series)
     .fetch_all(&db_pool)
     .await
     {
@@ -426,29 +1096,12 @@ async fn get_episodes(
 #[post("/upload")]
 async fn upload_zip(
     mut payload: Multipart,
-    db_registry: web::Data<DatabaseRegistry>,
-    query: web::Query<UserQuery>,
+    models: web::Data<ModelCache>,
+    WritableDb(db): WritableDb,
 ) -> Result<HttpResponse, actix_web::Error> {
-    let user_id = &query.user_id;
+    let db_pool = db.pool;
     let temp_dir = "./temp_uploads";
 
-    if user_id.is_empty() {
-        return Ok(
-            HttpResponse::BadRequest().json(serde_json::json!({ "error": "User ID is required" }))
-        );
-    }
-
-    let db_pool = {
-        let registry = db_registry.lock().await;
-        match registry.get(user_id) {
-            Some(pool) => pool.clone(),
-            None => {
-                return Ok(HttpResponse::NotFound()
-                    .json(serde_json::json!({ "error": "Database not found for user" })))
-            }
-        }
-    };
-
     if !Path::new(temp_dir).exists() {
         fs::create_dir_all(temp_dir).await.map_err(|err| {
             eprintln!("Failed to create temp directory: {}", err);
@@ -483,7 +1136,11 @@ async fn upload_zip(
             }
         }
 
-        let extract_path = format!("{}/extracted", temp_dir);
+        let series_name = Path::new(&filename)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "upload".to_string());
+        let extract_path = format!("{}/extracted-{}", temp_dir, Uuid::new_v4());
         fs::create_dir_all(&extract_path).await.map_err(|err| {
             eprintln!("Failed to create extract directory: {}", err);
             actix_web::error::ErrorInternalServerError("Failed to create extract directory")
@@ -504,36 +1161,1323 @@ async fn upload_zip(
             actix_web::error::ErrorInternalServerError("Failed to extract ZIP file")
         })?;
 
-        file_parser::process_seasons(&db_pool, &extract_path)
-            .await
-            .map_err(|err| {
-                eprintln!("Failed to process seasons: {}", err);
-                actix_web::error::ErrorInternalServerError("Failed to process ZIP content")
-            })?;
+        let processed = file_parser::process_seasons(&db_pool, &extract_path, &series_name).await;
+
+        if let Err(err) = fs::remove_dir_all(&extract_path).await {
+            eprintln!(
+                "Failed to remove extract directory {}: {}",
+                extract_path, err
+            );
+        }
+
+        // Even a failed upload may have stored some lines.
+        models.invalidate(&db.id);
+        processed.map_err(|err| {
+            eprintln!("Failed to process seasons: {}", err);
+            actix_web::error::ErrorInternalServerError("Failed to process ZIP content")
+        })?;
+
+        // Tagging and clustering are best effort; the transcripts are
+        // already stored.
+        if let Err(err) = sentiment::tag_lines(&db_pool, false).await {
+            eprintln!("Failed to tag uploaded lines: {}", err);
+        }
+        if let Err(err) = recurring::rebuild_clusters(&db_pool).await {
+            eprintln!("Failed to cluster recurring lines: {}", err);
+        }
     }
 
     Ok(HttpResponse::Ok().json(serde_json::json!({ "message": "Upload successful" })))
 }
 
-async fn get_db_pool(
+#[get("/export")]
+async fn export_database(WritableDb(db): WritableDb) -> impl Responder {
+    let temp_dir = "./temp_uploads";
+    if let Err(err) = fs::create_dir_all(temp_dir).await {
+        eprintln!("Failed to create temp directory: {}", err);
+        return HttpResponse::InternalServerError().body("Failed to export database");
+    }
+
+    let export_path = Path::new(temp_dir).join(format!("export-{}.sqlite", Uuid::new_v4()));
+    let backed_up = backup_database(&db.pool, &export_path).await;
+    let contents = match backed_up {
+        Ok(()) => fs::read(&export_path).await.map_err(|err| err.into()),
+        Err(err) => Err(err),
+    };
+    if let Err(err) = remove_database_files(&export_path).await {
+        eprintln!(
+            "Failed to remove export file {}: {}",
+            export_path.display(),
+            err
+        );
+    }
+
+    let contents = match contents {
+        Ok(contents) => contents,
+        Err(err) if err.is::<BackupBusy>() => {
+            eprintln!("Gave up exporting busy database {}: {}", db.id, err);
+            return HttpResponse::ServiceUnavailable()
+                .body("Database is too busy to export, try again later");
+        }
+        Err(err) => {
+            eprintln!("Failed to export database {}: {}", db.id, err);
+            return HttpResponse::InternalServerError().body("Failed to export database");
+        }
+    };
+
+    HttpResponse::Ok()
+        .content_type("application/vnd.sqlite3")
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(format!("{}.sqlite", db.id))],
+        })
+        .body(contents)
+}
+
+#[get("/lines/{line_id}/metadata")]
+async fn get_line_metadata(path: web::Path<i64>, db: AuthedDb) -> impl Responder {
+    let line_id = path.into_inner();
+
+    match metadata::fetch_metadata(&db.pool, line_id).await {
+        Ok(found) if found.is_empty() => {
+            HttpResponse::NotFound().body(format!("No metadata for line {}", line_id))
+        }
+        Ok(found) => HttpResponse::Ok().json(found),
+        Err(err) => {
+            eprintln!("Error fetching metadata for line {}: {}", line_id, err);
+            HttpResponse::InternalServerError().body("Error fetching metadata")
+        }
+    }
+}
+
+#[put("/lines/{line_id}/metadata")]
+async fn put_line_metadata(
+    path: web::Path<i64>,
+    WritableDb(db): WritableDb,
+    body: web::Json<MetadataRequest>,
+) -> impl Responder {
+    let line_id = path.into_inner();
+
+    match metadata::line_exists(&db.pool, line_id).await {
+        Ok(true) => {}
+        Ok(false) => return HttpResponse::NotFound().body(format!("Line {} not found", line_id)),
+        Err(err) => {
+            eprintln!("Error looking up line {}: {}", line_id, err);
+            return HttpResponse::InternalServerError().body("Error saving metadata");
+        }
+    }
+
+    match metadata::upsert_metadata(&db.pool, line_id, &body).await {
+        Ok(saved) => HttpResponse::Ok().json(saved),
+        Err(err) => {
+            eprintln!("Error saving metadata for line {}: {}", line_id, err);
+            HttpResponse::InternalServerError().body("Error saving metadata")
+        }
+    }
+}
+
+#[delete("/lines/{line_id}/metadata")]
+async fn delete_line_metadata(path: web::Path<i64>, WritableDb(db): WritableDb) -> impl Responder {
+    let line_id = path.into_inner();
+
+    match metadata::delete_metadata(&db.pool, line_id).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().body(format!("No metadata for line {}", line_id)),
+        Err(err) => {
+            eprintln!("Error deleting metadata for line {}: {}", line_id, err);
+            HttpResponse::InternalServerError().body("Error deleting metadata")
+        }
+    }
+}
+
+/// Attaches metadata to many lines at once. The body is either a JSON array of
+/// records or, with `Content-Type: text/csv`, a CSV file with a
+/// `line_id,sentiment,tone,primary_emotion` header.
+#[post("/metadata/bulk")]
+async fn bulk_metadata(
+    req: HttpRequest,
+    mut payload: web::Payload,
+    WritableDb(db): WritableDb,
+) -> Result<HttpResponse, actix_web::Error> {
+    let mut body = web::BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        if body.len() + chunk.len() > MAX_BULK_METADATA_BYTES {
+            return Ok(HttpResponse::PayloadTooLarge().json(serde_json::json!({
+                "error": format!("Bulk uploads are limited to {} bytes", MAX_BULK_METADATA_BYTES),
+            })));
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    let is_csv = req.content_type().eq_ignore_ascii_case("text/csv");
+    let records: Result<Vec<MetadataRecord>, String> = if is_csv {
+        metadata::parse_csv_records(&body).map_err(|err| err.to_string())
+    } else {
+        serde_json::from_slice(&body).map_err(|err| err.to_string())
+    };
+    let records = match records {
+        Ok(records) => records,
+        Err(err) => {
+            return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+                "error": format!("Invalid metadata records: {}", err),
+            })))
+        }
+    };
+
+    let missing = metadata::missing_lines(&db.pool, records.iter().map(|record| record.line_id))
+        .await
+        .map_err(|err| {
+            eprintln!("Error checking metadata lines: {}", err);
+            actix_web::error::ErrorInternalServerError("Error saving metadata")
+        })?;
+    if !missing.is_empty() {
+        return Ok(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "Some lines do not exist",
+            "missing_lines": missing,
+        })));
+    }
+
+    let updated = metadata::import_metadata(&db.pool, &records)
+        .await
+        .map_err(|err| {
+            eprintln!("Error saving bulk metadata: {}", err);
+            actix_web::error::ErrorInternalServerError("Error saving metadata")
+        })?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "updated": updated })))
+}
+
+/// Runs the built-in lexicon tagger over lines it has not tagged yet, or over
+/// every line with `?retag=true`.
+#[post("/metadata/analyze")]
+async fn analyze_metadata(
+    query: web::Query<AnalyzeQuery>,
+    WritableDb(db): WritableDb,
+) -> impl Responder {
+    match sentiment::tag_lines(&db.pool, query.retag.unwrap_or(false)).await {
+        Ok(tagged) => HttpResponse::Ok().json(serde_json::json!({
+            "tagged": tagged,
+            "source": sentiment::LEXICON_SOURCE,
+            "model_version": sentiment::LEXICON_MODEL_VERSION,
+        })),
+        Err(err) => {
+            eprintln!("Error tagging lines: {}", err);
+            HttpResponse::InternalServerError().body("Error tagging lines")
+        }
+    }
+}
+
+fn bad_request(message: String) -> HttpResponse {
+    HttpResponse::BadRequest().json(serde_json::json!({ "error": message }))
+}
+
+fn is_unique_violation(err: &sqlx::Error) -> bool {
+    err.as_database_error()
+        .is_some_and(|err| err.is_unique_violation())
+}
+
+/// Answers 404 unless `line_id` names a line, so annotations never dangle.
+async fn require_line(pool: &sqlx::SqlitePool, line_id: i64) -> Result<(), HttpResponse> {
+    match metadata::line_exists(pool, line_id).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(HttpResponse::NotFound().body(format!("Line {} not found", line_id))),
+        Err(err) => {
+            eprintln!("Error looking up line {}: {}", line_id, err);
+            Err(HttpResponse::InternalServerError().body("Error looking up line"))
+        }
+    }
+}
+
+const DEFAULT_SIMILAR_LIMIT: usize = 10;
+const MAX_SIMILAR_LIMIT: usize = 100;
+
+/// Lines that read most like the given line, ranked by BM25 over the token
+/// index.
+#[get("/lines/{line_id}/similar")]
+async fn get_similar_lines(
+    path: web::Path<i64>,
+    query: web::Query<SimilarLinesQuery>,
+    db: AuthedDb,
+) -> impl Responder {
+    let line_id = path.into_inner();
+    if let Err(response) = require_line(&db.pool, line_id).await {
+        return response;
+    }
+
+    let filter = SimilarityFilter {
+        same_speaker: query.same_speaker.unwrap_or(false),
+        exclude_episode: query.exclude_episode.unwrap_or(false),
+    };
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_SIMILAR_LIMIT)
+        .clamp(1, MAX_SIMILAR_LIMIT);
+    let ranked = match similarity::similar_lines(&db.pool, line_id, filter, limit).await {
+        Ok(ranked) => ranked,
+        Err(err) => {
+            eprintln!("Error finding lines similar to {}: {}", line_id, err);
+            return HttpResponse::InternalServerError().body("Error finding similar lines");
+        }
+    };
+
+    let ids: Vec<i64> = ranked.iter().map(|(id, _)| *id).collect();
+    let query = format!(
+        "{} WHERE l.id IN (SELECT value FROM json_each(?))",
+        LINE_SELECT
+    );
+    let lines = match sqlx::query_as::<_, Line>(&query)
+        .bind(serde_json::to_string(&ids).unwrap_or_default())
+        .fetch_all(&db.pool)
+        .await
+    {
+        Ok(lines) => lines,
+        Err(err) => {
+            eprintln!("Error fetching lines similar to {}: {}", line_id, err);
+            return HttpResponse::InternalServerError().body("Error finding similar lines");
+        }
+    };
+
+    let mut lines: HashMap<i64, Line> = lines.into_iter().map(|line| (line.id, line)).collect();
+    let similar: Vec<SimilarLine> = ranked
+        .into_iter()
+        .filter_map(|(id, score)| {
+            Some(SimilarLine {
+                line: lines.remove(&id)?,
+                score,
+            })
+        })
+        .collect();
+
+    HttpResponse::Ok().json(similar)
+}
+
+/// Loads the text and background for a line's quote card.
+async fn card_parts(
+    pool: &sqlx::SqlitePool,
+    line_id: i64,
+    query: &CardQuery,
+) -> Result<(cards::CardText, &'static [u8]), HttpResponse> {
+    let Some((_, background)) = cards::background(query.background.as_deref(), line_id) else {
+        let names: Vec<&str> = cards::BACKGROUNDS.iter().map(|(name, _)| *name).collect();
+        return Err(bad_request(format!(
+            "Unknown background; choose one of: {}",
+            names.join(", ")
+        )));
+    };
+
+    match cards::fetch_card_text(pool, line_id).await {
+        Ok(Some(card)) => Ok((card, background)),
+        Ok(None) => Err(HttpResponse::NotFound().body(format!("Line {} not found", line_id))),
+        Err(err) => {
+            eprintln!("Error fetching line {} for card: {}", line_id, err);
+            Err(HttpResponse::InternalServerError().body("Error rendering card"))
+        }
+    }
+}
+
+#[get("/lines/{line_id}/card.png")]
+async fn get_line_card_png(
+    path: web::Path<i64>,
+    query: web::Query<CardQuery>,
+    db: AuthedDb,
+) -> impl Responder {
+    let line_id = path.into_inner();
+    let (card, background) = match card_parts(&db.pool, line_id, &query).await {
+        Ok(parts) => parts,
+        Err(response) => return response,
+    };
+
+    // Layout and rasterizing take long enough to stall the async workers.
+    match web::block(move || cards::render_png(&card, background)).await {
+        Ok(Some(png)) => HttpResponse::Ok().content_type("image/png").body(png),
+        Ok(None) | Err(_) => {
+            eprintln!("Error rendering card for line {}", line_id);
+            HttpResponse::InternalServerError().body("Error rendering card")
+        }
+    }
+}
+
+#[get("/lines/{line_id}/card.svg")]
+async fn get_line_card_svg(
+    path: web::Path<i64>,
+    query: web::Query<CardQuery>,
+    db: AuthedDb,
+) -> impl Responder {
+    let line_id = path.into_inner();
+    let (card, background) = match card_parts(&db.pool, line_id, &query).await {
+        Ok(parts) => parts,
+        Err(response) => return response,
+    };
+
+    match web::block(move || cards::render_svg(&card, background)).await {
+        Ok(svg) => HttpResponse::Ok().content_type("image/svg+xml").body(svg),
+        Err(err) => {
+            eprintln!("Error rendering card for line {}: {}", line_id, err);
+            HttpResponse::InternalServerError().body("Error rendering card")
+        }
+    }
+}
+
+#[get("/tags")]
+async fn get_tags(db: AuthedDb) -> impl Responder {
+    match annotations::list_tags(&db.pool).await {
+        Ok(tags) => HttpResponse::Ok().json(tags),
+        Err(err) => {
+            eprintln!("Error fetching tags: {}", err);
+            HttpResponse::InternalServerError().body("Error fetching tags")
+        }
+    }
+}
+
+#[get("/lines/{line_id}/tags")]
+async fn get_line_tags(path: web::Path<i64>, db: AuthedDb) -> impl Responder {
+    match annotations::line_tags(&db.pool, path.into_inner()).await {
+        Ok(tags) => HttpResponse::Ok().json(tags),
+        Err(err) => {
+            eprintln!("Error fetching line tags: {}", err);
+            HttpResponse::InternalServerError().body("Error fetching tags")
+        }
+    }
+}
+
+#[post("/lines/{line_id}/tags")]
+async fn add_line_tag(
+    path: web::Path<i64>,
+    WritableDb(db): WritableDb,
+    body: web::Json<TagRequest>,
+) -> impl Responder {
+    let line_id = path.into_inner();
+    let name = match annotations::validate_tag_name(&body.name) {
+        Ok(name) => name,
+        Err(err) => return bad_request(err),
+    };
+    if let Err(response) = require_line(&db.pool, line_id).await {
+        return response;
+    }
+
+    match annotations::add_line_tag(&db.pool, line_id, &name).await {
+        Ok(tag) => HttpResponse::Ok().json(tag),
+        Err(err) => {
+            eprintln!("Error tagging line {}: {}", line_id, err);
+            HttpResponse::InternalServerError().body("Error tagging line")
+        }
+    }
+}
+
+#[delete("/lines/{line_id}/tags/{name}")]
+async fn remove_line_tag(
+    path: web::Path<(i64, String)>,
+    WritableDb(db): WritableDb,
+) -> impl Responder {
+    let (line_id, name) = path.into_inner();
+    let name = match annotations::validate_tag_name(&name) {
+        Ok(name) => name,
+        Err(err) => return bad_request(err),
+    };
+
+    match annotations::remove_line_tag(&db.pool, line_id, &name).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => {
+            HttpResponse::NotFound().body(format!("Line {} is not tagged {}", line_id, name))
+        }
+        Err(err) => {
+            eprintln!("Error untagging line {}: {}", line_id, err);
+            HttpResponse::InternalServerError().body("Error untagging line")
+        }
+    }
+}
+
+#[get("/lines/{line_id}/notes")]
+async fn get_line_notes(path: web::Path<i64>, db: AuthedDb) -> impl Responder {
+    match annotations::list_notes(&db.pool, path.into_inner()).await {
+        Ok(notes) => HttpResponse::Ok().json(notes),
+        Err(err) => {
+            eprintln!("Error fetching notes: {}", err);
+            HttpResponse::InternalServerError().body("Error fetching notes")
+        }
+    }
+}
+
+#[post("/lines/{line_id}/notes")]
+async fn add_line_note(
+    path: web::Path<i64>,
+    WritableDb(db): WritableDb,
+    body: web::Json<NoteRequest>,
+) -> impl Responder {
+    let line_id = path.into_inner();
+    let note_body = match annotations::validate_note_body(&body.body) {
+        Ok(note_body) => note_body,
+        Err(err) => return bad_request(err),
+    };
+    if let Err(response) = require_line(&db.pool, line_id).await {
+        return response;
+    }
+
+    match annotations::add_note(&db.pool, line_id, &note_body).await {
+        Ok(note) => HttpResponse::Created().json(note),
+        Err(err) => {
+            eprintln!("Error adding note to line {}: {}", line_id, err);
+            HttpResponse::InternalServerError().body("Error adding note")
+        }
+    }
+}
+
+#[patch("/notes/{note_id}")]
+async fn update_note(
+    path: web::Path<i64>,
+    WritableDb(db): WritableDb,
+    body: web::Json<NoteRequest>,
+) -> impl Responder {
+    let note_id = path.into_inner();
+    let note_body = match annotations::validate_note_body(&body.body) {
+        Ok(note_body) => note_body,
+        Err(err) => return bad_request(err),
+    };
+
+    match annotations::update_note(&db.pool, note_id, &note_body).await {
+        Ok(Some(note)) => HttpResponse::Ok().json(note),
+        Ok(None) => HttpResponse::NotFound().body(format!("Note {} not found", note_id)),
+        Err(err) => {
+            eprintln!("Error updating note {}: {}", note_id, err);
+            HttpResponse::InternalServerError().body("Error updating note")
+        }
+    }
+}
+
+#[delete("/notes/{note_id}")]
+async fn delete_note(path: web::Path<i64>, WritableDb(db): WritableDb) -> impl Responder {
+    let note_id = path.into_inner();
+
+    match annotations::delete_note(&db.pool, note_id).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().body(format!("Note {} not found", note_id)),
+        Err(err) => {
+            eprintln!("Error deleting note {}: {}", note_id, err);
+            HttpResponse::InternalServerError().body("Error deleting note")
+        }
+    }
+}
+
+#[get("/collections")]
+async fn get_collections(db: AuthedDb) -> impl Responder {
+    match annotations::list_collections(&db.pool).await {
+        Ok(collections) => HttpResponse::Ok().json(collections),
+        Err(err) => {
+            eprintln!("Error fetching collections: {}", err);
+            HttpResponse::InternalServerError().body("Error fetching collections")
+        }
+    }
+}
+
+#[post("/collections")]
+async fn create_collection(
+    WritableDb(db): WritableDb,
+    body: web::Json<CollectionRequest>,
+) -> impl Responder {
+    let name = match annotations::validate_collection_name(&body.name) {
+        Ok(name) => name,
+        Err(err) => return bad_request(err),
+    };
+
+    match annotations::create_collection(&db.pool, &name, body.description.as_deref()).await {
+        Ok(collection) => HttpResponse::Created().json(collection),
+        Err(err) if is_unique_violation(&err) => HttpResponse::Conflict()
+            .json(serde_json::json!({ "error": "A collection with that name already exists" })),
+        Err(err) => {
+            eprintln!("Error creating collection: {}", err);
+            HttpResponse::InternalServerError().body("Error creating collection")
+        }
+    }
+}
+
+/// Returns a collection along with its lines, in collection order.
+#[get("/collections/{collection_id}")]
+async fn get_collection(path: web::Path<i64>, db: AuthedDb) -> impl Responder {
+    let collection_id = path.into_inner();
+
+    let collection = match annotations::fetch_collection(&db.pool, collection_id).await {
+        Ok(Some(collection)) => collection,
+        Ok(None) => {
+            return HttpResponse::NotFound().body(format!("Collection {} not found", collection_id))
+        }
+        Err(err) => {
+            eprintln!("Error fetching collection {}: {}", collection_id, err);
+            return HttpResponse::InternalServerError().body("Error fetching collection");
+        }
+    };
+
+    let query = format!(
+        "{} JOIN collection_lines cl ON cl.line_id = l.id WHERE cl.collection_id = ? ORDER BY cl.position",
+        LINE_SELECT
+    );
+    match sqlx::query_as::<_, Line>(&query)
+        .bind(collection_id)
+        .fetch_all(&db.pool)
+        .await
+    {
+        Ok(lines) => HttpResponse::Ok().json(serde_json::json!({
+            "collection": collection,
+            "lines": lines,
+        })),
+        Err(err) => {
+            eprintln!("Error fetching collection {} lines: {}", collection_id, err);
+            HttpResponse::InternalServerError().body("Error fetching collection")
+        }
+    }
+}
+
+#[patch("/collections/{collection_id}")]
+async fn update_collection(
+    path: web::Path<i64>,
+    WritableDb(db): WritableDb,
+    body: web::Json<CollectionUpdate>,
+) -> impl Responder {
+    let collection_id = path.into_inner();
+    let name = match body
+        .name
+        .as_deref()
+        .map(annotations::validate_collection_name)
+    {
+        Some(Ok(name)) => Some(name),
+        Some(Err(err)) => return bad_request(err),
+        None => None,
+    };
+
+    match annotations::update_collection(
+        &db.pool,
+        collection_id,
+        name.as_deref(),
+        body.description.as_deref(),
+    )
+    .await
+    {
+        Ok(Some(collection)) => HttpResponse::Ok().json(collection),
+        Ok(None) => {
+            HttpResponse::NotFound().body(format!("Collection {} not found", collection_id))
+        }
+        Err(err) if is_unique_violation(&err) => HttpResponse::Conflict()
+            .json(serde_json::json!({ "error": "A collection with that name already exists" })),
+        Err(err) => {
+            eprintln!("Error updating collection {}: {}", collection_id, err);
+            HttpResponse::InternalServerError().body("Error updating collection")
+        }
+    }
+}
+
+#[delete("/collections/{collection_id}")]
+async fn delete_collection(path: web::Path<i64>, WritableDb(db): WritableDb) -> impl Responder {
+    let collection_id = path.into_inner();
+
+    match annotations::delete_collection(&db.pool, collection_id).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => {
+            HttpResponse::NotFound().body(format!("Collection {} not found", collection_id))
+        }
+        Err(err) => {
+            eprintln!("Error deleting collection {}: {}", collection_id, err);
+            HttpResponse::InternalServerError().body("Error deleting collection")
+        }
+    }
+}
+
+/// Answers 404 unless the collection exists.
+async fn require_collection(
+    pool: &sqlx::SqlitePool,
+    collection_id: i64,
+) -> Result<(), HttpResponse> {
+    match annotations::fetch_collection(pool, collection_id).await {
+        Ok(Some(_)) => Ok(()),
+        Ok(None) => {
+            Err(HttpResponse::NotFound().body(format!("Collection {} not found", collection_id)))
+        }
+        Err(err) => {
+            eprintln!("Error fetching collection {}: {}", collection_id, err);
+            Err(HttpResponse::InternalServerError().body("Error fetching collection"))
+        }
+    }
+}
+
+/// Replaces the lines of a collection with the given ordered list.
+#[put("/collections/{collection_id}/lines")]
+async fn set_collection_lines(
+    path: web::Path<i64>,
+    WritableDb(db): WritableDb,
+    body: web::Json<CollectionLinesRequest>,
+) -> impl Responder {
+    let collection_id = path.into_inner();
+    if let Err(response) = require_collection(&db.pool, collection_id).await {
+        return response;
+    }
+
+    match metadata::missing_lines(&db.pool, body.line_ids.iter().copied()).await {
+        Ok(missing) if !missing.is_empty() => {
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "error": "Some lines do not exist",
+                "missing_lines": missing,
+            }))
+        }
+        Ok(_) => {}
+        Err(err) => {
+            eprintln!("Error checking collection lines: {}", err);
+            return HttpResponse::InternalServerError().body("Error updating collection");
+        }
+    }
+
+    match annotations::set_collection_lines(&db.pool, collection_id, &body.line_ids).await {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(err) => {
+            eprintln!("Error updating collection {}: {}", collection_id, err);
+            HttpResponse::InternalServerError().body("Error updating collection")
+        }
+    }
+}
+
+#[post("/collections/{collection_id}/lines")]
+async fn add_collection_line(
+    path: web::Path<i64>,
+    WritableDb(db): WritableDb,
+    body: web::Json<CollectionLineRequest>,
+) -> impl Responder {
+    let collection_id = path.into_inner();
+    if let Err(response) = require_collection(&db.pool, collection_id).await {
+        return response;
+    }
+    if let Err(response) = require_line(&db.pool, body.line_id).await {
+        return response;
+    }
+
+    match annotations::add_collection_line(&db.pool, collection_id, body.line_id, body.position)
+        .await
+    {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(err) => {
+            eprintln!("Error updating collection {}: {}", collection_id, err);
+            HttpResponse::InternalServerError().body("Error updating collection")
+        }
+    }
+}
+
+#[delete("/collections/{collection_id}/lines/{line_id}")]
+async fn remove_collection_line(
+    path: web::Path<(i64, i64)>,
+    WritableDb(db): WritableDb,
+) -> impl Responder {
+    let (collection_id, line_id) = path.into_inner();
+
+    match annotations::remove_collection_line(&db.pool, collection_id, line_id).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().body(format!(
+            "Line {} is not in collection {}",
+            line_id, collection_id
+        )),
+        Err(err) => {
+            eprintln!("Error updating collection {}: {}", collection_id, err);
+            HttpResponse::InternalServerError().body("Error updating collection")
+        }
+    }
+}
+
+/// Downloads a collection as JSON (the default) or with `?format=csv` as CSV.
+#[get("/collections/{collection_id}/export")]
+async fn export_collection(
+    path: web::Path<i64>,
+    query: web::Query<ExportQuery>,
+    db: AuthedDb,
+) -> impl Responder {
+    let collection_id = path.into_inner();
+
+    let collection = match annotations::fetch_collection(&db.pool, collection_id).await {
+        Ok(Some(collection)) => collection,
+        Ok(None) => {
+            return HttpResponse::NotFound().body(format!("Collection {} not found", collection_id))
+        }
+        Err(err) => {
+            eprintln!("Error fetching collection {}: {}", collection_id, err);
+            return HttpResponse::InternalServerError().body("Error exporting collection");
+        }
+    };
+
+    let rows = match annotations::collection_export_rows(&db.pool, collection_id).await {
+        Ok(rows) => rows,
+        Err(err) => {
+            eprintln!("Error exporting collection {}: {}", collection_id, err);
+            return HttpResponse::InternalServerError().body("Error exporting collection");
+        }
+    };
+
+    let stem = match sanitize(&collection.name) {
+        stem if stem.is_empty() => format!("collection-{}", collection.id),
+        stem => stem,
+    };
+
+    let (content_type, extension, body) = match query.format.as_deref().unwrap_or("json") {
+        "json" => (
+            "application/json",
+            "json",
+            serde_json::to_vec(&serde_json::json!({
+                "collection": collection,
+                "lines": rows,
+            }))
+            .unwrap_or_default(),
+        ),
+        "csv" => match annotations::export_rows_to_csv(&rows) {
+            Ok(body) => ("text/csv; charset=utf-8", "csv", body),
+            Err(err) => {
+                eprintln!("Error writing collection {} as CSV: {}", collection_id, err);
+                return HttpResponse::InternalServerError().body("Error exporting collection");
+            }
+        },
+        other => return bad_request(format!("Unsupported export format: {}", other)),
+    };
+
+    HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(format!(
+                "{}.{}",
+                stem, extension
+            ))],
+        })
+        .body(body)
+}
+
+const DEFAULT_NGRAM_LIMIT: usize = 50;
+const MAX_NGRAM_LIMIT: usize = 1000;
+
+/// Returns the most frequent n-grams for the selected lines, or with
+/// `mode=distinctive` the n-grams most overrepresented in them relative to the
+/// rest of the corpus. Stopwords are removed unless `remove_stopwords=false`.
+#[get("/analysis/ngrams")]
+async fn get_ngrams(query: web::Query<NgramQuery>, db: AuthedDb) -> impl Responder {
+    let n = query.n.unwrap_or(1);
+    if !(1..=analysis::MAX_NGRAM).contains(&n) {
+        return bad_request(format!("n must be between 1 and {}", analysis::MAX_NGRAM));
+    }
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_NGRAM_LIMIT)
+        .clamp(1, MAX_NGRAM_LIMIT);
+    let min_count = query.min_count.unwrap_or(1).max(1);
+    let exclude_stopwords = query.remove_stopwords.unwrap_or(true);
+    let scope = LineScope {
+        series: query.series,
+        season: query.season,
+        episode: query.episode,
+        speaker: query.speaker,
+    };
+
+    match query.mode.unwrap_or_default() {
+        NgramMode::Frequency => {
+            match analysis::count_ngrams(
+                &db.pool,
+                n,
+                scope,
+                exclude_stopwords,
+                min_count,
+                limit as i64,
+            )
+            .await
+            {
+                Ok(ngrams) => HttpResponse::Ok().json(ngrams),
+                Err(err) => {
+                    eprintln!("Error counting n-grams: {}", err);
+                    HttpResponse::InternalServerError().body("Error counting n-grams")
+                }
+            }
+        }
+        NgramMode::Distinctive => {
+            if !scope.is_subset() {
+                return bad_request(
+                    "Distinctive terms need a speaker, season or episode to compare".to_string(),
+                );
+            }
+            match analysis::distinctive_ngrams(
+                &db.pool,
+                n,
+                scope,
+                exclude_stopwords,
+                min_count,
+                limit,
+            )
+            .await
+            {
+                Ok(terms) => HttpResponse::Ok().json(terms),
+                Err(err) => {
+                    eprintln!("Error ranking distinctive terms: {}", err);
+                    HttpResponse::InternalServerError().body("Error ranking distinctive terms")
+                }
+            }
+        }
+    }
+}
+
+const DEFAULT_KWIC_WINDOW: usize = 5;
+const MAX_KWIC_WINDOW: usize = 25;
+const DEFAULT_KWIC_LIMIT: usize = 200;
+const MAX_KWIC_LIMIT: usize = 5000;
+
+/// Keyword-in-context concordance for a word or phrase. `sort` is `position`,
+/// `left` or `right`; `format=tsv` downloads the results as TSV.
+#[get("/analysis/kwic")]
+async fn get_kwic(query: web::Query<KwicQuery>, db: AuthedDb) -> impl Responder {
+    if text::tokenize(&query.q).is_empty() {
+        return bad_request("q must contain at least one word".to_string());
+    }
+    let window = query
+        .window
+        .unwrap_or(DEFAULT_KWIC_WINDOW)
+        .min(MAX_KWIC_WINDOW);
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_KWIC_LIMIT)
+        .clamp(1, MAX_KWIC_LIMIT);
+    let scope = LineScope {
+        series: query.series,
+        season: query.season,
+        episode: query.episode,
+        speaker: query.speaker,
+    };
+    let as_tsv = match query.format.as_deref().unwrap_or("json") {
+        "json" => false,
+        "tsv" => true,
+        other => return bad_request(format!("Unsupported export format: {}", other)),
+    };
+
+    let lines = match analysis::keyword_in_context(
+        &db.pool,
+        &query.q,
+        window,
+        scope,
+        query.sort.unwrap_or_default(),
+        limit,
+    )
+    .await
+    {
+        Ok(KwicOutcome::Lines(lines)) => lines,
+        Ok(KwicOutcome::TooManyMatches) => {
+            return bad_request(format!(
+                "More than {} matches to sort; narrow the scope or sort by position",
+                analysis::MAX_SORTED_KWIC_MATCHES
+            ))
+        }
+        Err(err) => {
+            eprintln!("Error building concordance: {}", err);
+            return HttpResponse::InternalServerError().body("Error building concordance");
+        }
+    };
+
+    if !as_tsv {
+        return HttpResponse::Ok().json(lines);
+    }
+
+    HttpResponse::Ok()
+        .content_type("text/tab-separated-values; charset=utf-8")
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename("kwic.tsv".to_string())],
+        })
+        .body(analysis::kwic_to_tsv(&lines))
+}
+
+/// Speaker interaction network as JSON, GraphML (`format=graphml`) or DOT
+/// (`format=dot`).
+#[get("/analysis/interactions")]
+async fn get_interactions(query: web::Query<InteractionQuery>, db: AuthedDb) -> impl Responder {
+    let (content_type, extension) = match query.format.as_deref().unwrap_or("json") {
+        "json" => ("application/json", None),
+        "graphml" => ("application/graphml+xml", Some("graphml")),
+        "dot" => ("text/vnd.graphviz; charset=utf-8", Some("dot")),
+        other => return bad_request(format!("Unsupported export format: {}", other)),
+    };
+
+    let graph = match interactions::interaction_graph(
+        &db.pool,
+        query.series,
+        query.season,
+        query.episode,
+        query.min_weight.unwrap_or(1).max(1),
+    )
+    .await
+    {
+        Ok(graph) => graph,
+        Err(err) => {
+            eprintln!("Error building interaction graph: {}", err);
+            return HttpResponse::InternalServerError().body("Error building interaction graph");
+        }
+    };
+
+    let Some(extension) = extension else {
+        return HttpResponse::Ok().json(graph);
+    };
+    let body = if extension == "graphml" {
+        interactions::to_graphml(&graph)
+    } else {
+        interactions::to_dot(&graph)
+    };
+
+    HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(format!(
+                "interactions.{}",
+                extension
+            ))],
+        })
+        .body(body)
+}
+
+const DEFAULT_COLLOCATION_LIMIT: usize = 50;
+const MAX_COLLOCATION_LIMIT: usize = 1000;
+const DEFAULT_COLLOCATION_MIN_COUNT: i64 = 2;
+
+/// Word pairs that occur together more than chance predicts, scored by PMI,
+/// t-score and Dice and ranked by `measure`.
+#[get("/analysis/collocations")]
+async fn get_collocations(query: web::Query<CollocationQuery>, db: AuthedDb) -> impl Responder {
+    let scope = LineScope {
+        series: query.series,
+        season: query.season,
+        episode: query.episode,
+        speaker: query.speaker,
+    };
+
+    match collocations::collocations(
+        &db.pool,
+        scope,
+        AssociationOptions {
+            window: query.window.unwrap_or(1),
+            measure: query.measure.unwrap_or_default(),
+            exclude_stopwords: query.remove_stopwords.unwrap_or(true),
+            min_count: query
+                .min_count
+                .unwrap_or(DEFAULT_COLLOCATION_MIN_COUNT)
+                .max(1),
+            limit: query
+                .limit
+                .unwrap_or(DEFAULT_COLLOCATION_LIMIT)
+                .clamp(1, MAX_COLLOCATION_LIMIT),
+        },
+    )
+    .await
+    {
+        Ok(pairs) => HttpResponse::Ok().json(pairs),
+        Err(err) => {
+            eprintln!("Error scoring collocations: {}", err);
+            HttpResponse::InternalServerError().body("Error scoring collocations")
+        }
+    }
+}
+
+/// Terms that appear near `term`, scored like collocations.
+#[get("/analysis/cooccurrences")]
+async fn get_cooccurrences(query: web::Query<CooccurrenceQuery>, db: AuthedDb) -> impl Responder {
+    let tokens = text::tokenize(&query.term);
+    let [token] = tokens.as_slice() else {
+        return bad_request("term must be a single word".to_string());
+    };
+    let term = text::normalize(token.text);
+    let scope = LineScope {
+        series: query.series,
+        season: query.season,
+        episode: query.episode,
+        speaker: query.speaker,
+    };
+
+    match collocations::cooccurrences(
+        &db.pool,
+        &term,
+        scope,
+        AssociationOptions {
+            window: query.window.unwrap_or(5),
+            measure: query.measure.unwrap_or_default(),
+            exclude_stopwords: query.remove_stopwords.unwrap_or(true),
+            min_count: query
+                .min_count
+                .unwrap_or(DEFAULT_COLLOCATION_MIN_COUNT)
+                .max(1),
+            limit: query
+                .limit
+                .unwrap_or(DEFAULT_COLLOCATION_LIMIT)
+                .clamp(1, MAX_COLLOCATION_LIMIT),
+        },
+    )
+    .await
+    {
+        Ok(terms) => HttpResponse::Ok().json(terms),
+        Err(err) => {
+            eprintln!("Error scoring co-occurrences: {}", err);
+            HttpResponse::InternalServerError().body("Error scoring co-occurrences")
+        }
+    }
+}
+
+const DEFAULT_RECURRING_LIMIT: i64 = 50;
+const MAX_RECURRING_LIMIT: i64 = 500;
+
+/// Recurring lines and catchphrases with every occurrence, as found by the
+/// last run of the clustering job.
+#[get("/analysis/recurring")]
+async fn get_recurring_lines(query: web::Query<RecurringQuery>, db: AuthedDb) -> impl Responder {
+    let mut clusters = match recurring::list_clusters(
+        &db.pool,
+        query.min_occurrences.unwrap_or(2),
+        query.min_seasons.unwrap_or(1),
+        query.sort.unwrap_or_default(),
+        query
+            .limit
+            .unwrap_or(DEFAULT_RECURRING_LIMIT)
+            .clamp(1, MAX_RECURRING_LIMIT),
+    )
+    .await
+    {
+        Ok(clusters) => clusters,
+        Err(err) => {
+            eprintln!("Error fetching recurring lines: {}", err);
+            return HttpResponse::InternalServerError().body("Error fetching recurring lines");
+        }
+    };
+
+    let cluster_ids: Vec<i64> = clusters.iter().map(|cluster| cluster.id).collect();
+    let members = match recurring::cluster_members(&db.pool, &cluster_ids).await {
+        Ok(members) => members,
+        Err(err) => {
+            eprintln!("Error fetching recurring lines: {}", err);
+            return HttpResponse::InternalServerError().body("Error fetching recurring lines");
+        }
+    };
+
+    let line_ids: Vec<i64> = members.keys().copied().collect();
+    let lines_query = format!(
+        "{} JOIN episodes e ON e.id = l.episode_id WHERE l.id IN (SELECT value FROM json_each(?)) ORDER BY sn.series_id, sn.number, e.sort_key, l.line_number",
+        LINE_SELECT
+    );
+    let lines = match sqlx::query_as::<_, Line>(&lines_query)
+        .bind(serde_json::to_string(&line_ids).unwrap_or_default())
+        .fetch_all(&db.pool)
+        .await
+    {
+        Ok(lines) => lines,
+        Err(err) => {
+            eprintln!("Error fetching recurring lines: {}", err);
+            return HttpResponse::InternalServerError().body("Error fetching recurring lines");
+        }
+    };
+
+    let positions: HashMap<i64, usize> = cluster_ids
+        .iter()
+        .enumerate()
+        .map(|(index, id)| (*id, index))
+        .collect();
+    for line in lines {
+        if let Some(&index) = members.get(&line.id).and_then(|id| positions.get(id)) {
+            clusters[index].occurrences.push(line);
+        }
+    }
+
+    HttpResponse::Ok().json(clusters)
+}
+
+/// Reruns the recurring-line clustering job over the whole workspace.
+#[post("/analysis/recurring")]
+async fn rebuild_recurring_lines(WritableDb(db): WritableDb) -> impl Responder {
+    match recurring::rebuild_clusters(&db.pool).await {
+        Ok(clusters) => HttpResponse::Ok().json(serde_json::json!({ "clusters": clusters })),
+        Err(err) => {
+            eprintln!("Error clustering recurring lines: {}", err);
+            HttpResponse::InternalServerError().body("Error clustering recurring lines")
+        }
+    }
+}
+
+#[post("/quiz/sessions")]
+async fn create_quiz_session(
+    WritableDb(db): WritableDb,
+    body: Option<web::Json<QuizSessionRequest>>,
+) -> impl Responder {
+    let difficulty = body.and_then(|body| body.difficulty).unwrap_or_default();
+
+    match quiz::create_session(&db.pool, difficulty).await {
+        Ok(session) => HttpResponse::Created().json(session),
+        Err(err) => {
+            eprintln!("Error creating quiz session: {}", err);
+            HttpResponse::InternalServerError().body("Error creating quiz session")
+        }
+    }
+}
+
+#[get("/quiz/sessions/{session_id}")]
+async fn get_quiz_session(path: web::Path<String>, db: AuthedDb) -> impl Responder {
+    let session_id = path.into_inner();
+
+    match quiz::fetch_session(&db.pool, &session_id).await {
+        Ok(Some(session)) => HttpResponse::Ok().json(session),
+        Ok(None) => HttpResponse::NotFound().body(format!("Quiz session {} not found", session_id)),
+        Err(err) => {
+            eprintln!("Error fetching quiz session {}: {}", session_id, err);
+            HttpResponse::InternalServerError().body("Error fetching quiz session")
+        }
+    }
+}
+
+/// Asks the next "who said it?" question in a session.
+#[post("/quiz/sessions/{session_id}/questions")]
+async fn create_quiz_question(
+    path: web::Path<String>,
+    WritableDb(db): WritableDb,
+) -> impl Responder {
+    let session_id = path.into_inner();
+
+    match quiz::next_question(&db.pool, &session_id).await {
+        Ok(QuestionOutcome::Created(question)) => HttpResponse::Created().json(question),
+        Ok(QuestionOutcome::SessionNotFound) => {
+            HttpResponse::NotFound().body(format!("Quiz session {} not found", session_id))
+        }
+        Ok(QuestionOutcome::NoQuestion) => HttpResponse::Conflict().json(serde_json::json!({
+            "error": "Not enough lines or speakers for a question",
+        })),
+        Err(err) => {
+            eprintln!("Error creating quiz question: {}", err);
+            HttpResponse::InternalServerError().body("Error creating quiz question")
+        }
+    }
+}
+
+#[post("/quiz/questions/{question_id}/answer")]
+async fn answer_quiz_question(
+    path: web::Path<i64>,
+    WritableDb(db): WritableDb,
+    body: web::Json<QuizAnswerRequest>,
+) -> impl Responder {
+    let question_id = path.into_inner();
+
+    match quiz::answer_question(&db.pool, question_id, body.speaker_id).await {
+        Ok(AnswerOutcome::Answered(answer)) => HttpResponse::Ok().json(answer),
+        Ok(AnswerOutcome::NotFound) => {
+            HttpResponse::NotFound().body(format!("Question {} not found", question_id))
+        }
+        Ok(AnswerOutcome::AlreadyAnswered) => HttpResponse::Conflict().json(serde_json::json!({
+            "error": format!("Question {} has already been answered", question_id),
+        })),
+        Ok(AnswerOutcome::InvalidChoice) => bad_request(format!(
+            "Speaker {} is not one of the choices",
+            body.speaker_id
+        )),
+        Err(err) => {
+            eprintln!("Error answering question {}: {}", question_id, err);
+            HttpResponse::InternalServerError().body("Error answering question")
+        }
+    }
+}
+
+#[get("/health")]
+async fn health(db_registry: web::Data<DatabaseRegistry>) -> impl Responder {
+    HttpResponse::Ok().json(serde_json::json!({
+        "status": "ok",
+        "schema_version": latest_schema_version(),
+        "open_databases": db_registry.snapshot().len(),
+    }))
+}
+
+#[get("/sessions")]
+async fn list_sessions(
+    req: HttpRequest,
     db_registry: web::Data<DatabaseRegistry>,
-    user_id: &str,
-) -> Option<SqlitePool> {
-    if user_id.is_empty() {
-        return None;
+) -> impl Responder {
+    if !is_admin(&req) {
+        return forbidden_admin();
     }
-    let registry = db_registry.lock().await;
-    registry.get(user_id).cloned()
+
+    let entries = db_registry.snapshot();
+
+    let mut sessions = Vec::with_capacity(entries.len());
+    for (id, entry) in entries {
+        sessions.push(SessionInfo {
+            id,
+            scratch: entry.scratch,
+            size_bytes: database_size(&entry.path).await,
+            idle_secs: entry.idle_for().as_secs(),
+        });
+    }
+    sessions.sort_by_key(|session| session.idle_secs);
+
+    HttpResponse::Ok().json(sessions)
 }
 
 pub fn init_routes(cfg: &mut web::ServiceConfig) {
-    cfg.service(init_db)
-        .service(cleanup_db)
-        .service(upload_zip)
-        .service(get_transcript)
-        .service(get_random_line)
-        .service(get_speakers)
-        .service(get_seasons)
-        .service(get_episodes)
-        .service(search_phrases);
+    cfg.service(health)
+        .service(init_db)
+        .service(create_workspace)
+        .service(import_workspace)
+        .service(list_workspaces)
+        .service(admin_issue_token)
+        .service(list_sessions)
+        .service(open_share)
+        .service(
+            web::scope("")
+                .wrap(from_fn(require_db))
+                .service(cleanup_db)
+                .service(rename_workspace)
+                .service(delete_workspace)
+                .service(create_share)
+                .service(list_shares)
+                .service(revoke_share)
+                .service(issue_token)
+                .service(upload_zip)
+                .service(export_database)
+                .service(get_transcript)
+                .service(get_random_line)
+                .service(generate_lines)
+                .service(get_speakers)
+                .service(get_all_speaker_stats)
+                .service(get_speaker_stats)
+                .service(get_all_speaker_metrics)
+                .service(get_speaker_metrics)
+                .service(get_season_metrics)
+                .service(get_corpus_stats)
+                .service(get_speaker_matrix)
+                .service(get_episode_stats)
+                .service(get_series)
+                .service(get_seasons)
+                .service(get_episodes)
+                .service(search_phrases)
+                .service(get_line_metadata)
+                .service(put_line_metadata)
+                .service(delete_line_metadata)
+                .service(bulk_metadata)
+                .service(analyze_metadata)
+                .service(get_similar_lines)
+                .service(get_line_card_png)
+                .service(get_line_card_svg)
+                .service(get_tags)
+                .service(get_line_tags)
+                .service(add_line_tag)
+                .service(remove_line_tag)
+                .service(get_line_notes)
+                .service(add_line_note)
+                .service(update_note)
+                .service(delete_note)
+                .service(get_collections)
+                .service(create_collection)
+                .service(get_collection)
+                .service(update_collection)
+                .service(delete_collection)
+                .service(set_collection_lines)
+                .service(add_collection_line)
+                .service(remove_collection_line)
+                .service(export_collection)
+                .service(get_ngrams)
+                .service(get_kwic)
+                .service(get_interactions)
+                .service(get_collocations)
+                .service(get_cooccurrences)
+                .service(get_recurring_lines)
+                .service(rebuild_recurring_lines)
+                .service(create_quiz_session)
+                .service(get_quiz_session)
+                .service(create_quiz_question)
+                .service(answer_quiz_question),
+        );
 }
@@ -0,0 +1,359 @@
+use crate::registry::DatabaseRegistry;
+use actix_web::body::MessageBody;
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::error::InternalError;
+use actix_web::http::header::{HeaderMap, AUTHORIZATION};
+use actix_web::middleware::Next;
+use actix_web::{
+    web, Error, FromRequest, HttpMessage, HttpRequest, HttpResponse, HttpResponseBuilder,
+};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use futures_util::future::{ready, Ready};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const SECRET_PATH: &str = "./data/auth_secret";
+const SECRET_LEN: usize = 32;
+
+/// Write tokens never outlive this, so a leaked one stops working on its own.
+/// Holders renew through `POST /tokens` before it runs out. Once every write
+/// token for a workspace has expired, an admin mints a new one through
+/// `POST /workspaces/{id}/tokens`.
+pub const WRITE_TOKEN_TTL_SECS: u64 = 7 * 24 * 60 * 60;
+
+/// The longest TTL `POST /tokens` accepts for any scope.
+pub const MAX_TOKEN_TTL_SECS: u64 = 365 * 24 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Write,
+    Read,
+}
+
+/// The signed payload of a bearer token.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: String,
+    pub scope: Scope,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exp: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub share: Option<String>,
+}
+
+impl Claims {
+    /// Read tokens without a TTL never expire. Write tokens are capped at
+    /// [`WRITE_TOKEN_TTL_SECS`].
+    pub fn new(sub: &str, scope: Scope, ttl_secs: Option<u64>) -> Self {
+        let ttl_secs = match scope {
+            Scope::Write => {
+                Some(ttl_secs.map_or(WRITE_TOKEN_TTL_SECS, |ttl| ttl.min(WRITE_TOKEN_TTL_SECS)))
+            }
+            Scope::Read => ttl_secs,
+        };
+        Self {
+            sub: sub.to_string(),
+            scope,
+            exp: ttl_secs.map(|ttl| now_secs().saturating_add(ttl)),
+            share: None,
+        }
+    }
+
+    /// A read-only token that stays valid only while `share_id` is published.
+    pub fn for_share(sub: &str, share_id: &str) -> Self {
+        Self {
+            share: Some(share_id.to_string()),
+            ..Self::new(sub, Scope::Read, None)
+        }
+    }
+}
+
+/// Checks a TTL requested through `POST /tokens`. No TTL means the scope's
+/// default.
+pub fn validate_ttl(ttl_secs: Option<u64>) -> Result<Option<u64>, String> {
+    match ttl_secs {
+        Some(0) => Err("ttl_secs must be at least 1".to_string()),
+        Some(ttl) if ttl > MAX_TOKEN_TTL_SECS => {
+            Err(format!("ttl_secs must be at most {}", MAX_TOKEN_TTL_SECS))
+        }
+        ttl => Ok(ttl),
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0)
+}
+
+/// Issues and verifies HMAC-SHA256 bearer tokens of the form
+/// `base64url(claims).base64url(signature)`.
+#[derive(Clone)]
+pub struct TokenSigner {
+    key: Vec<u8>,
+}
+
+impl TokenSigner {
+    pub fn new(key: Vec<u8>) -> Self {
+        Self { key }
+    }
+
+    /// Uses `AUTH_SECRET` when set, otherwise a random secret persisted under
+    /// `./data` so tokens stay valid across restarts.
+    pub async fn load() -> std::io::Result<Self> {
+        if let Ok(secret) = std::env::var("AUTH_SECRET") {
+            if !secret.is_empty() {
+                return Ok(Self::new(secret.into_bytes()));
+            }
+        }
+
+        match fs::read(SECRET_PATH).await {
+            Ok(key) if !key.is_empty() => return Ok(Self::new(key)),
+            Ok(_) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+
+        let mut key = vec![0u8; SECRET_LEN];
+        rand::thread_rng().fill_bytes(&mut key);
+        if let Some(dir) = Path::new(SECRET_PATH).parent() {
+            fs::create_dir_all(dir).await?;
+        }
+        fs::write(SECRET_PATH, &key).await?;
+        println!("Generated a new auth secret at {}", SECRET_PATH);
+
+        Ok(Self::new(key))
+    }
+
+    fn mac(&self) -> HmacSha256 {
+        HmacSha256::new_from_slice(&self.key).expect("HMAC accepts keys of any length")
+    }
+
+    pub fn issue(&self, claims: &Claims) -> String {
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(claims).unwrap_or_default());
+        let mut mac = self.mac();
+        mac.update(payload.as_bytes());
+        let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+        format!("{}.{}", payload, signature)
+    }
+
+    pub fn verify(&self, token: &str) -> Result<Claims, &'static str> {
+        let (payload, signature) = token.split_once('.').ok_or("Malformed token")?;
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| "Malformed token")?;
+
+        let mut mac = self.mac();
+        mac.update(payload.as_bytes());
+        mac.verify_slice(&signature)
+            .map_err(|_| "Invalid token signature")?;
+
+        let claims: Claims = URL_SAFE_NO_PAD
+            .decode(payload)
+            .ok()
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .ok_or("Malformed token")?;
+
+        if claims.exp.is_some_and(|exp| exp <= now_secs()) {
+            return Err("Token has expired");
+        }
+
+        Ok(claims)
+    }
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+fn json_error(mut builder: HttpResponseBuilder, message: &'static str) -> Error {
+    let response = builder.json(serde_json::json!({ "error": message }));
+    InternalError::from_response(message, response).into()
+}
+
+/// The database resolved from the request's bearer token.
+#[derive(Clone)]
+pub struct AuthedDb {
+    pub id: String,
+    pub pool: SqlitePool,
+    pub scope: Scope,
+}
+
+impl FromRequest for AuthedDb {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<AuthedDb>()
+                .cloned()
+                .ok_or_else(|| json_error(HttpResponse::Unauthorized(), "Missing bearer token")),
+        )
+    }
+}
+
+/// An [`AuthedDb`] whose token allows writes. Read-only share tokens are rejected.
+pub struct WritableDb(pub AuthedDb);
+
+impl FromRequest for WritableDb {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let db = match AuthedDb::from_request(req, payload).into_inner() {
+            Ok(db) => db,
+            Err(err) => return ready(Err(err)),
+        };
+
+        if db.scope != Scope::Write {
+            return ready(Err(json_error(
+                HttpResponse::Forbidden(),
+                "This token is read-only",
+            )));
+        }
+
+        ready(Ok(WritableDb(db)))
+    }
+}
+
+/// Verifies the bearer token and attaches the matching [`AuthedDb`] to the request.
+pub async fn require_db(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    // The authenticated routes share a catch-all scope, so paths that match
+    // no route at all end up here too. Those are 404s, not auth failures.
+    if req.match_pattern().is_none() {
+        return Err(json_error(HttpResponse::NotFound(), "Not found"));
+    }
+
+    let signer = req
+        .app_data::<web::Data<TokenSigner>>()
+        .cloned()
+        .ok_or_else(|| json_error(HttpResponse::InternalServerError(), "Auth not configured"))?;
+    let registry = req
+        .app_data::<web::Data<DatabaseRegistry>>()
+        .cloned()
+        .ok_or_else(|| json_error(HttpResponse::InternalServerError(), "Auth not configured"))?;
+
+    let token = bearer_token(req.headers())
+        .ok_or_else(|| json_error(HttpResponse::Unauthorized(), "Missing bearer token"))?;
+    let claims = signer
+        .verify(token)
+        .map_err(|message| json_error(HttpResponse::Unauthorized(), message))?;
+
+    if let Some(share_id) = &claims.share {
+        if registry.share_target(share_id).as_deref() != Some(claims.sub.as_str()) {
+            return Err(json_error(
+                HttpResponse::Unauthorized(),
+                "This share link has been revoked",
+            ));
+        }
+    }
+
+    let lease = registry
+        .lease(&claims.sub)
+        .await
+        .ok_or_else(|| json_error(HttpResponse::NotFound(), "Database not found for user"))?;
+
+    // Share tokens are always read-only, whatever scope they claim.
+    let scope = if claims.share.is_some() {
+        Scope::Read
+    } else {
+        claims.scope
+    };
+
+    req.extensions_mut().insert(AuthedDb {
+        id: claims.sub,
+        pool: lease.pool().clone(),
+        scope,
+    });
+
+    // Hold the lease until the handler finishes so a request that outlasts
+    // the idle TTL cannot have its pool closed underneath it.
+    let response = next.call(req).await;
+    drop(lease);
+    response
+}
+
+/// Checks the bearer token against `ADMIN_TOKEN`. Admin endpoints are disabled
+/// when it is unset.
+pub fn is_admin(req: &HttpRequest) -> bool {
+    let expected = match std::env::var("ADMIN_TOKEN") {
+        Ok(token) if !token.is_empty() => token,
+        _ => return false,
+    };
+
+    bearer_token(req.headers()).is_some_and(|token| {
+        Sha256::digest(token.as_bytes()) == Sha256::digest(expected.as_bytes())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_tokens_always_expire() {
+        let claims = Claims::new("workspace", Scope::Write, None);
+        assert!(claims
+            .exp
+            .is_some_and(|exp| exp <= now_secs() + WRITE_TOKEN_TTL_SECS));
+
+        let claims = Claims::new("workspace", Scope::Write, Some(u64::MAX / 2));
+        assert!(claims
+            .exp
+            .is_some_and(|exp| exp <= now_secs() + WRITE_TOKEN_TTL_SECS));
+    }
+
+    #[test]
+    fn read_token_ttls_are_bounded() {
+        assert!(validate_ttl(Some(0)).is_err());
+        assert!(validate_ttl(Some(MAX_TOKEN_TTL_SECS + 1)).is_err());
+        assert!(validate_ttl(Some(u64::MAX)).is_err());
+        assert_eq!(
+            validate_ttl(Some(MAX_TOKEN_TTL_SECS)),
+            Ok(Some(MAX_TOKEN_TTL_SECS))
+        );
+        assert_eq!(validate_ttl(None), Ok(None));
+
+        let claims = Claims::new("workspace", Scope::Read, Some(u64::MAX));
+        assert_eq!(claims.exp, Some(u64::MAX));
+    }
+
+    #[test]
+    fn read_tokens_keep_their_requested_ttl() {
+        assert_eq!(Claims::new("workspace", Scope::Read, None).exp, None);
+        assert!(Claims::for_share("workspace", "share").exp.is_none());
+    }
+
+    #[test]
+    fn signer_rejects_expired_tokens() {
+        let signer = TokenSigner::new(b"secret".to_vec());
+        let mut claims = Claims::new("workspace", Scope::Read, None);
+        assert!(signer.verify(&signer.issue(&claims)).is_ok());
+
+        claims.exp = Some(now_secs().saturating_sub(1));
+        assert_eq!(
+            signer.verify(&signer.issue(&claims)).err(),
+            Some("Token has expired")
+        );
+    }
+}
@@ -0,0 +1,448 @@
+use ab_glyph::{Font, FontRef, OutlineCurve, Point, PxScale, ScaleFont};
+use base64::Engine as _;
+use sqlx::{FromRow, SqlitePool};
+use std::fmt::Write as _;
+use std::sync::OnceLock;
+use tiny_skia::{FillRule, Paint, PathBuilder, PathSegment, Pixmap, Transform};
+
+pub const CARD_WIDTH: u32 = 1200;
+pub const CARD_HEIGHT: u32 = 630;
+
+const PADDING: f32 = 90.0;
+const LINE_HEIGHT: f32 = 1.3;
+const MAX_QUOTE_SIZE: f32 = 64.0;
+const MIN_QUOTE_SIZE: f32 = 24.0;
+const SPEAKER_SIZE: f32 = 40.0;
+const CITATION_SIZE: f32 = 26.0;
+/// Space kept below the quote for the speaker and citation.
+const ATTRIBUTION_HEIGHT: f32 = 120.0;
+const SHADOW_OFFSET: (f32, f32) = (2.0, 3.0);
+const SHADOW_ALPHA: f32 = 0.35;
+const CITATION_ALPHA: f32 = 0.75;
+
+const QUOTE_FONT: &[u8] = include_bytes!("../assets/fonts/DejaVuSerif-Italic.ttf");
+const LABEL_FONT: &[u8] = include_bytes!("../assets/fonts/DejaVuSans-Bold.ttf");
+
+/// Bundled backgrounds, all dark enough for white text.
+pub const BACKGROUNDS: &[(&str, &[u8])] = &[
+    ("dusk", include_bytes!("../assets/cards/dusk.png")),
+    ("forest", include_bytes!("../assets/cards/forest.png")),
+    ("ocean", include_bytes!("../assets/cards/ocean.png")),
+];
+
+struct Fonts {
+    quote: FontRef<'static>,
+    label: FontRef<'static>,
+}
+
+fn fonts() -> &'static Fonts {
+    static FONTS: OnceLock<Fonts> = OnceLock::new();
+    FONTS.get_or_init(|| Fonts {
+        quote: FontRef::try_from_slice(QUOTE_FONT).expect("bundled quote font is valid"),
+        label: FontRef::try_from_slice(LABEL_FONT).expect("bundled label font is valid"),
+    })
+}
+
+/// The text printed on a card.
+#[derive(Debug, Clone, FromRow)]
+pub struct CardText {
+    pub content: String,
+    pub speaker_name: Option<String>,
+    pub series: String,
+    pub season: i32,
+    pub episode_code: String,
+    pub episode_title: String,
+}
+
+pub async fn fetch_card_text(
+    pool: &SqlitePool,
+    line_id: i64,
+) -> Result<Option<CardText>, sqlx::Error> {
+    sqlx::query_as(
+        r#"
+        SELECT
+            l.content,
+            sp.name AS speaker_name,
+            sr.name AS series,
+            sn.number AS season,
+            e.code AS episode_code,
+            e.title AS episode_title
+        FROM lines l
+        JOIN seasons sn ON l.season_id = sn.id
+        JOIN series sr ON sn.series_id = sr.id
+        JOIN episodes e ON l.episode_id = e.id
+        LEFT JOIN speakers sp ON l.speaker_id = sp.id
+        WHERE l.id = ?
+        "#,
+    )
+    .bind(line_id)
+    .fetch_optional(pool)
+    .await
+}
+
+/// Picks a background by name, or by line id when no name is given so a
+/// line always gets the same card.
+pub fn background(
+    name: Option<&str>,
+    line_id: i64,
+) -> Option<&'static (&'static str, &'static [u8])> {
+    match name {
+        Some(name) => BACKGROUNDS
+            .iter()
+            .find(|(candidate, _)| candidate.eq_ignore_ascii_case(name)),
+        None => BACKGROUNDS.get(line_id.rem_euclid(BACKGROUNDS.len() as i64) as usize),
+    }
+}
+
+/// Formats an episode as `S01E02a`, or `S00 SP1` for specials and shorts.
+fn episode_label(season: i32, code: &str) -> String {
+    let digits = code.chars().take_while(char::is_ascii_digit).count();
+    if digits == 0 {
+        return format!("S{:02} {}", season, code);
+    }
+    format!("S{:02}E{:0>2}{}", season, &code[..digits], &code[digits..])
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FontKind {
+    Quote,
+    Label,
+}
+
+impl FontKind {
+    fn font(self) -> &'static FontRef<'static> {
+        match self {
+            FontKind::Quote => &fonts().quote,
+            FontKind::Label => &fonts().label,
+        }
+    }
+}
+
+/// One line of text placed on the card, with `y` at its baseline.
+#[derive(Debug, Clone)]
+struct TextRun {
+    text: String,
+    font: FontKind,
+    size: f32,
+    x: f32,
+    y: f32,
+    alpha: f32,
+}
+
+fn text_width(font: &FontRef<'_>, size: f32, text: &str) -> f32 {
+    let scaled = font.as_scaled(PxScale::from(size));
+    let mut width = 0.0;
+    let mut previous = None;
+    for c in text.chars() {
+        let id = scaled.glyph_id(c);
+        if let Some(previous) = previous {
+            width += scaled.kern(previous, id);
+        }
+        width += scaled.h_advance(id);
+        previous = Some(id);
+    }
+    width
+}
+
+/// Breaks text into lines no wider than `max_width`, splitting words that
+/// are too long to fit on a line of their own.
+fn wrap(font: &FontRef<'_>, size: f32, text: &str, max_width: f32) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut current = String::new();
+
+    for word in text.split_whitespace() {
+        let candidate = if current.is_empty() {
+            word.to_string()
+        } else {
+            format!("{} {}", current, word)
+        };
+        if text_width(font, size, &candidate) <= max_width {
+            current = candidate;
+            continue;
+        }
+
+        if !current.is_empty() {
+            lines.push(std::mem::take(&mut current));
+        }
+        for c in word.chars() {
+            current.push(c);
+            if text_width(font, size, &current) > max_width && current.chars().count() > 1 {
+                current.pop();
+                lines.push(std::mem::take(&mut current));
+                current.push(c);
+            }
+        }
+    }
+
+    if !current.is_empty() {
+        lines.push(current);
+    }
+    lines
+}
+
+/// Finds the largest font size at which the quote fits the box. At the
+/// smallest size, lines that still do not fit are dropped and the last one
+/// ends in an ellipsis.
+fn fit_quote(text: &str, width: f32, height: f32) -> (f32, Vec<String>) {
+    let font = &fonts().quote;
+    let mut size = MAX_QUOTE_SIZE;
+    loop {
+        let lines = wrap(font, size, text, width);
+        let max_lines = (height / (size * LINE_HEIGHT)).floor().max(1.0) as usize;
+        if lines.len() <= max_lines {
+            return (size, lines);
+        }
+        if size <= MIN_QUOTE_SIZE {
+            let mut lines = lines;
+            lines.truncate(max_lines);
+            if let Some(last) = lines.last_mut() {
+                while !last.is_empty() && text_width(font, size, &format!("{}…", last)) > width {
+                    last.pop();
+                }
+                *last = format!("{}…", last.trim_end());
+            }
+            return (size, lines);
+        }
+        size -= 2.0;
+    }
+}
+
+fn layout(card: &CardText) -> Vec<TextRun> {
+    let width = CARD_WIDTH as f32 - 2.0 * PADDING;
+    let quote_height = CARD_HEIGHT as f32 - 2.0 * PADDING - ATTRIBUTION_HEIGHT;
+
+    // Spoken lines are quoted; stage directions are shown as they are.
+    let content = card.content.trim();
+    let quote = if card.speaker_name.is_some() {
+        format!("“{}”", content)
+    } else {
+        content.to_string()
+    };
+    let (size, lines) = fit_quote(&quote, width, quote_height);
+
+    let line_height = size * LINE_HEIGHT;
+    let ascent = fonts().quote.as_scaled(PxScale::from(size)).ascent();
+    let top = PADDING + (quote_height - lines.len() as f32 * line_height) / 2.0;
+
+    let mut runs: Vec<TextRun> = lines
+        .into_iter()
+        .enumerate()
+        .map(|(i, text)| TextRun {
+            text,
+            font: FontKind::Quote,
+            size,
+            x: PADDING,
+            y: top + i as f32 * line_height + ascent,
+            alpha: 1.0,
+        })
+        .collect();
+
+    let citation_y = CARD_HEIGHT as f32 - PADDING;
+    if let Some(speaker) = &card.speaker_name {
+        runs.push(TextRun {
+            text: format!("— {}", speaker),
+            font: FontKind::Label,
+            size: SPEAKER_SIZE,
+            x: PADDING,
+            y: citation_y - CITATION_SIZE * 1.6,
+            alpha: 1.0,
+        });
+    }
+
+    let mut citation = format!(
+        "{} · {}",
+        card.series,
+        episode_label(card.season, &card.episode_code)
+    );
+    if !card.episode_title.is_empty() {
+        citation = format!("{} · {}", citation, card.episode_title);
+    }
+    let citation = wrap(&fonts().label, CITATION_SIZE, &citation, width)
+        .into_iter()
+        .next()
+        .unwrap_or_default();
+    runs.push(TextRun {
+        text: citation,
+        font: FontKind::Label,
+        size: CITATION_SIZE,
+        x: PADDING,
+        y: citation_y,
+        alpha: CITATION_ALPHA,
+    });
+
+    runs
+}
+
+/// Converts a run of text into one fillable path, following the glyph
+/// outlines.
+fn text_path(run: &TextRun, dx: f32, dy: f32) -> Option<tiny_skia::Path> {
+    let font = run.font.font();
+    let scaled = font.as_scaled(PxScale::from(run.size));
+    let factor = scaled.scale_factor();
+
+    let mut builder = PathBuilder::new();
+    let mut pen = run.x + dx;
+    let baseline = run.y + dy;
+    let mut previous = None;
+
+    for c in run.text.chars() {
+        let id = scaled.glyph_id(c);
+        if let Some(previous) = previous {
+            pen += scaled.kern(previous, id);
+        }
+        previous = Some(id);
+
+        if let Some(outline) = font.outline(id) {
+            // Outlines are in font units with y pointing up.
+            let place = |p: &Point| {
+                (
+                    pen + p.x * factor.horizontal,
+                    baseline - p.y * factor.vertical,
+                )
+            };
+            let mut last_end: Option<Point> = None;
+            for curve in &outline.curves {
+                let (start, end) = match curve {
+                    OutlineCurve::Line(p0, p1) => (*p0, *p1),
+                    OutlineCurve::Quad(p0, _, p2) => (*p0, *p2),
+                    OutlineCurve::Cubic(p0, _, _, p3) => (*p0, *p3),
+                };
+                if last_end != Some(start) {
+                    if last_end.is_some() {
+                        builder.close();
+                    }
+                    let (x, y) = place(&start);
+                    builder.move_to(x, y);
+                }
+                match curve {
+                    OutlineCurve::Line(_, p1) => {
+                        let (x, y) = place(p1);
+                        builder.line_to(x, y);
+                    }
+                    OutlineCurve::Quad(_, p1, p2) => {
+                        let (x1, y1) = place(p1);
+                        let (x, y) = place(p2);
+                        builder.quad_to(x1, y1, x, y);
+                    }
+                    OutlineCurve::Cubic(_, p1, p2, p3) => {
+                        let (x1, y1) = place(p1);
+                        let (x2, y2) = place(p2);
+                        let (x, y) = place(p3);
+                        builder.cubic_to(x1, y1, x2, y2, x, y);
+                    }
+                }
+                last_end = Some(end);
+            }
+            if last_end.is_some() {
+                builder.close();
+            }
+        }
+
+        pen += scaled.h_advance(id);
+    }
+
+    builder.finish()
+}
+
+/// Renders a card as PNG, or `None` if the background cannot be decoded or
+/// the image encoded.
+pub fn render_png(card: &CardText, background: &[u8]) -> Option<Vec<u8>> {
+    let mut pixmap = Pixmap::decode_png(background).ok()?;
+    let runs = layout(card);
+
+    for (offset, color) in [
+        (SHADOW_OFFSET, (0, 0, 0, SHADOW_ALPHA)),
+        ((0.0, 0.0), (255, 255, 255, 1.0)),
+    ] {
+        for run in &runs {
+            let Some(path) = text_path(run, offset.0, offset.1) else {
+                continue;
+            };
+            let mut paint = Paint {
+                anti_alias: true,
+                ..Default::default()
+            };
+            paint.set_color_rgba8(
+                color.0,
+                color.1,
+                color.2,
+                (color.3 * run.alpha * 255.0) as u8,
+            );
+            pixmap.fill_path(
+                &path,
+                &paint,
+                FillRule::Winding,
+                Transform::identity(),
+                None,
+            );
+        }
+    }
+
+    pixmap.encode_png().ok()
+}
+
+fn escape_xml(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Writes a path as SVG path data.
+fn svg_path_data(path: &tiny_skia::Path) -> String {
+    let mut data = String::new();
+    for segment in path.segments() {
+        let _ = match segment {
+            PathSegment::MoveTo(p) => write!(data, "M{:.2} {:.2}", p.x, p.y),
+            PathSegment::LineTo(p) => write!(data, "L{:.2} {:.2}", p.x, p.y),
+            PathSegment::QuadTo(p1, p) => {
+                write!(data, "Q{:.2} {:.2} {:.2} {:.2}", p1.x, p1.y, p.x, p.y)
+            }
+            PathSegment::CubicTo(p1, p2, p) => write!(
+                data,
+                "C{:.2} {:.2} {:.2} {:.2} {:.2} {:.2}",
+                p1.x, p1.y, p2.x, p2.y, p.x, p.y
+            ),
+            PathSegment::Close => write!(data, "Z"),
+        };
+    }
+    data
+}
+
+/// Renders a card as SVG with the background embedded. Text is drawn as the
+/// bundled fonts' glyph outlines, the same ones the PNG is filled from, so the
+/// card looks identical whatever fonts the viewer has installed.
+pub fn render_svg(card: &CardText, background: &[u8]) -> String {
+    let runs = layout(card);
+    let mut svg = format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}">
+  <image width="{w}" height="{h}" href="data:image/png;base64,{image}"/>
+"#,
+        w = CARD_WIDTH,
+        h = CARD_HEIGHT,
+        image = base64::engine::general_purpose::STANDARD.encode(background),
+    );
+
+    for (offset, fill, opacity) in [
+        (SHADOW_OFFSET, "#000000", SHADOW_ALPHA),
+        ((0.0, 0.0), "#ffffff", 1.0),
+    ] {
+        for run in &runs {
+            let Some(path) = text_path(run, offset.0, offset.1) else {
+                continue;
+            };
+            let _ = writeln!(
+                svg,
+                r#"  <path d="{}" fill="{}" fill-opacity="{:.2}" aria-label="{}"/>"#,
+                svg_path_data(&path),
+                fill,
+                opacity * run.alpha,
+                escape_xml(&run.text)
+            );
+        }
+    }
+
+    svg.push_str("</svg>\n");
+    svg
+}
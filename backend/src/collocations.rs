@@ -0,0 +1,235 @@
+use crate::analysis::{stopwords, LineScope};
+use crate::models::{AssociationMeasure, AssociationScores, Collocation, Cooccurrence};
+use sqlx::SqlitePool;
+use std::collections::HashMap;
+
+pub const MAX_WINDOW: usize = 10;
+
+/// Term frequencies within the scope and the total number of tokens.
+async fn term_frequencies(
+    pool: &SqlitePool,
+    scope: LineScope,
+) -> Result<(HashMap<String, i64>, i64), sqlx::Error> {
+    let sql = format!(
+        r#"
+        SELECT t.term, COUNT(*)
+        FROM tokens t
+        JOIN lines l ON l.id = t.line_id
+        JOIN seasons sn ON sn.id = l.season_id
+        WHERE {filter}
+        GROUP BY t.term
+        "#,
+        filter = LineScope::FILTER,
+    );
+    let frequencies: HashMap<String, i64> = scope
+        .bind(sqlx::query_as::<_, (String, i64)>(&sql))
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .collect();
+    let total = frequencies.values().sum();
+    Ok((frequencies, total))
+}
+
+/// The terms of each line in the scope, in order. With `containing`, only
+/// lines that use that term are read.
+async fn scope_lines(
+    pool: &SqlitePool,
+    scope: LineScope,
+    containing: Option<&str>,
+) -> Result<Vec<Vec<String>>, sqlx::Error> {
+    let sql = format!(
+        r#"
+        SELECT t.line_id, t.term
+        FROM tokens t
+        JOIN lines l ON l.id = t.line_id
+        JOIN seasons sn ON sn.id = l.season_id
+        WHERE {filter}
+            AND (? IS NULL OR EXISTS (
+                SELECT 1 FROM tokens c WHERE c.line_id = t.line_id AND c.term = ?
+            ))
+        ORDER BY t.line_id, t.position
+        "#,
+        filter = LineScope::FILTER,
+    );
+    let rows = scope
+        .bind(sqlx::query_as::<_, (i64, String)>(&sql))
+        .bind(containing)
+        .bind(containing)
+        .fetch_all(pool)
+        .await?;
+
+    let mut lines: Vec<Vec<String>> = Vec::new();
+    let mut current = None;
+    for (line_id, term) in rows {
+        if current != Some(line_id) {
+            lines.push(Vec::new());
+            current = Some(line_id);
+        }
+        if let Some(line) = lines.last_mut() {
+            line.push(term);
+        }
+    }
+    Ok(lines)
+}
+
+/// Scores how strongly two terms attract each other. `span` is the number of
+/// positions around each occurrence of the first term that were searched,
+/// which scales the count expected by chance.
+fn association(
+    observed: i64,
+    first: i64,
+    second: i64,
+    total: i64,
+    span: usize,
+) -> AssociationScores {
+    let observed = observed as f64;
+    let expected = first as f64 * second as f64 * span as f64 / total.max(1) as f64;
+
+    AssociationScores {
+        pmi: (observed / expected).log2(),
+        t_score: (observed - expected) / observed.sqrt(),
+        dice: 2.0 * observed / (first + second) as f64,
+    }
+}
+
+/// Settings shared by collocation and co-occurrence scoring.
+#[derive(Debug, Clone, Copy)]
+pub struct AssociationOptions {
+    pub window: usize,
+    pub measure: AssociationMeasure,
+    pub exclude_stopwords: bool,
+    pub min_count: i64,
+    pub limit: usize,
+}
+
+fn measure_value(scores: &AssociationScores, measure: AssociationMeasure) -> f64 {
+    match measure {
+        AssociationMeasure::Pmi => scores.pmi,
+        AssociationMeasure::TScore => scores.t_score,
+        AssociationMeasure::Dice => scores.dice,
+    }
+}
+
+/// Scores word pairs where the second word follows the first within the
+/// window, in the same line, ranked by the chosen measure.
+pub async fn collocations(
+    pool: &SqlitePool,
+    scope: LineScope,
+    options: AssociationOptions,
+) -> Result<Vec<Collocation>, sqlx::Error> {
+    let AssociationOptions {
+        window,
+        measure,
+        exclude_stopwords,
+        min_count,
+        limit,
+    } = options;
+    let window = window.clamp(1, MAX_WINDOW);
+    let (frequencies, total) = term_frequencies(pool, scope).await?;
+    let lines = scope_lines(pool, scope, None).await?;
+    let stopwords = stopwords();
+    let skip = |term: &str| exclude_stopwords && stopwords.contains(term);
+
+    let mut pairs: HashMap<(&str, &str), i64> = HashMap::new();
+    for line in &lines {
+        for (i, first) in line.iter().enumerate() {
+            if skip(first) {
+                continue;
+            }
+            for second in line.iter().skip(i + 1).take(window) {
+                if !skip(second) {
+                    *pairs.entry((first, second)).or_default() += 1;
+                }
+            }
+        }
+    }
+
+    let mut ranked: Vec<Collocation> = pairs
+        .into_iter()
+        .filter(|(_, count)| *count >= min_count)
+        .map(|((first, second), count)| Collocation {
+            scores: association(
+                count,
+                frequencies.get(first).copied().unwrap_or(count),
+                frequencies.get(second).copied().unwrap_or(count),
+                total,
+                window,
+            ),
+            first: first.to_string(),
+            second: second.to_string(),
+            count,
+        })
+        .collect();
+
+    ranked.sort_by(|a, b| {
+        measure_value(&b.scores, measure)
+            .total_cmp(&measure_value(&a.scores, measure))
+            .then(b.count.cmp(&a.count))
+            .then_with(|| (&a.first, &a.second).cmp(&(&b.first, &b.second)))
+    });
+    ranked.truncate(limit);
+
+    Ok(ranked)
+}
+
+/// Scores the terms found within the window either side of `term`, ranked
+/// by the chosen measure.
+pub async fn cooccurrences(
+    pool: &SqlitePool,
+    term: &str,
+    scope: LineScope,
+    options: AssociationOptions,
+) -> Result<Vec<Cooccurrence>, sqlx::Error> {
+    let AssociationOptions {
+        window,
+        measure,
+        exclude_stopwords,
+        min_count,
+        limit,
+    } = options;
+    let window = window.clamp(1, MAX_WINDOW);
+    let (frequencies, total) = term_frequencies(pool, scope).await?;
+    let Some(&node_frequency) = frequencies.get(term) else {
+        return Ok(Vec::new());
+    };
+    let lines = scope_lines(pool, scope, Some(term)).await?;
+    let stopwords = stopwords();
+
+    let mut counts: HashMap<&str, i64> = HashMap::new();
+    for line in &lines {
+        for (i, _) in line.iter().enumerate().filter(|(_, word)| *word == term) {
+            let start = i.saturating_sub(window);
+            let end = (i + window + 1).min(line.len());
+            for other in line[start..end].iter().filter(|word| *word != term) {
+                if !(exclude_stopwords && stopwords.contains(other.as_str())) {
+                    *counts.entry(other).or_default() += 1;
+                }
+            }
+        }
+    }
+
+    let mut ranked: Vec<Cooccurrence> = counts
+        .into_iter()
+        .filter(|(_, count)| *count >= min_count)
+        .map(|(other, count)| {
+            let frequency = frequencies.get(other).copied().unwrap_or(count);
+            Cooccurrence {
+                scores: association(count, node_frequency, frequency, total, 2 * window),
+                term: other.to_string(),
+                count,
+                frequency,
+            }
+        })
+        .collect();
+
+    ranked.sort_by(|a, b| {
+        measure_value(&b.scores, measure)
+            .total_cmp(&measure_value(&a.scores, measure))
+            .then(b.count.cmp(&a.count))
+            .then_with(|| a.term.cmp(&b.term))
+    });
+    ranked.truncate(limit);
+
+    Ok(ranked)
+}
@@ -1,49 +1,342 @@
-use lazy_static::lazy_static;
-use sqlx::migrate::MigrateDatabase;
-use sqlx::{Sqlite, SqlitePool};
-use std::collections::HashMap;
+use crate::text;
+use libsqlite3_sys as ffi;
+use sqlx::migrate::{MigrateDatabase, Migrator};
+use sqlx::{Connection, Sqlite, SqliteConnection, SqlitePool};
+use std::collections::BTreeSet;
+use std::ffi::CString;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use tokio::fs;
-use tokio::sync::Mutex;
 
-lazy_static! {
-    static ref DB_CACHE: Mutex<HashMap<String, SqlitePool>> = Mutex::new(HashMap::new());
+const BACKUP_PAGES_PER_STEP: i32 = 256;
+/// After this long the backup copies every remaining page in one step, which
+/// cannot be restarted by writes from other connections.
+const BACKUP_INCREMENTAL_FOR: Duration = Duration::from_secs(10);
+/// A backup still blocked by locks after this long gives up.
+const BACKUP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Returned by [`backup_database`] when the source stayed locked until
+/// [`BACKUP_TIMEOUT`].
+#[derive(Debug)]
+pub struct BackupBusy;
+
+impl std::fmt::Display for BackupBusy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Database stayed busy for {}s", BACKUP_TIMEOUT.as_secs())
+    }
+}
+
+impl std::error::Error for BackupBusy {}
+
+fn database_url(db_path: &Path) -> String {
+    format!("sqlite://{}", db_path.to_string_lossy())
+}
+
+/// Every database is brought up to the latest schema whenever a pool is opened.
+pub static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+/// The migration that added the `tokens` table.
+const TOKENS_MIGRATION_VERSION: i64 = 5;
+
+/// Applies pending migrations. Lines stored before the token table existed are
+/// tokenized once, in the same pass that creates it.
+async fn migrate(conn: &mut SqliteConnection) -> Result<(), sqlx::Error> {
+    let previous_version = schema_version(conn).await?;
+    MIGRATOR.run(&mut *conn).await?;
+
+    if previous_version < TOKENS_MIGRATION_VERSION {
+        let indexed = text::index_untokenized_lines(conn).await?;
+        if indexed > 0 {
+            println!("Tokenized {} existing lines", indexed);
+        }
+    }
+    Ok(())
 }
 
 pub async fn setup_database(
+    db_dir: &Path,
     user_id: &str,
-    schema_path: &str,
 ) -> Result<(SqlitePool, PathBuf), Box<dyn std::error::Error>> {
-    let db_path = Path::new("./temp_dbs").join(format!("{}.sqlite", user_id));
-    fs::create_dir_all("./temp_dbs").await?;
+    let db_path = db_dir.join(format!("{}.sqlite", user_id));
+    fs::create_dir_all(db_dir).await?;
 
-    let database_url = format!("sqlite://{}", db_path.to_string_lossy());
-    {
-        let cache = DB_CACHE.lock().await;
-        if let Some(pool) = cache.get(&database_url) {
-            return Ok((pool.clone(), db_path));
-        }
-    }
+    let database_url = database_url(&db_path);
 
-    let db_pool = if !Sqlite::database_exists(&database_url)
+    if !Sqlite::database_exists(&database_url)
         .await
         .unwrap_or(false)
     {
         Sqlite::create_database(&database_url).await?;
         println!("Database created for user: {}", user_id);
+    }
 
-        let pool = SqlitePool::connect(&database_url).await?;
-        let schema = tokio::fs::read_to_string(schema_path).await?;
-        sqlx::query(&schema).execute(&pool).await?;
+    let db_pool = open_database(&db_path).await?;
+    Ok((db_pool, db_path))
+}
 
-        pool
-    } else {
-        SqlitePool::connect(&database_url).await?
+/// Removes a database file along with any WAL and shared-memory sidecar files.
+pub async fn remove_database_files(db_path: &Path) -> std::io::Result<()> {
+    for suffix in ["-wal", "-shm"] {
+        let mut sidecar = db_path.as_os_str().to_owned();
+        sidecar.push(suffix);
+        if let Err(err) = fs::remove_file(&sidecar).await {
+            if err.kind() != std::io::ErrorKind::NotFound {
+                return Err(err);
+            }
+        }
+    }
+    fs::remove_file(db_path).await
+}
+
+/// Opens a pool on an existing database file without creating it, applying
+/// any migrations it has not seen yet.
+pub async fn open_database(db_path: &Path) -> Result<SqlitePool, sqlx::Error> {
+    let pool = SqlitePool::connect(&database_url(db_path)).await?;
+    let migrated = match pool.acquire().await {
+        Ok(mut conn) => migrate(&mut conn).await,
+        Err(err) => Err(err),
     };
-    DB_CACHE
-        .lock()
+    if let Err(err) = migrated {
+        pool.close().await;
+        return Err(err);
+    }
+    Ok(pool)
+}
+
+/// The newest migration version this build knows about.
+pub fn latest_schema_version() -> i64 {
+    MIGRATOR
+        .iter()
+        .map(|migration| migration.version)
+        .max()
+        .unwrap_or(0)
+}
+
+/// The newest migration applied to a database, or 0 if it has never been migrated.
+pub async fn schema_version(conn: &mut SqliteConnection) -> Result<i64, sqlx::Error> {
+    let tracked: bool = sqlx::query_scalar(
+        "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = '_sqlx_migrations')",
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+    if !tracked {
+        return Ok(0);
+    }
+
+    let version: Option<i64> =
+        sqlx::query_scalar("SELECT MAX(version) FROM _sqlx_migrations WHERE success")
+            .fetch_one(&mut *conn)
+            .await?;
+    Ok(version.unwrap_or(0))
+}
+
+/// Returns the on-disk size of a database including its WAL file.
+pub async fn database_size(db_path: &Path) -> u64 {
+    let mut wal = db_path.as_os_str().to_owned();
+    wal.push("-wal");
+
+    let mut size = 0;
+    for path in [db_path.as_os_str().to_owned(), wal] {
+        if let Ok(metadata) = fs::metadata(&path).await {
+            size += metadata.len();
+        }
+    }
+    size
+}
+
+/// Copies a live database to `dest` with SQLite's online backup API.
+///
+/// The copy is a consistent snapshot even while other connections keep
+/// writing, and the source is only locked for one batch of pages at a time.
+/// Writes restart an incremental backup, so a busy source is finished in a
+/// single step once [`BACKUP_INCREMENTAL_FOR`] has passed.
+pub async fn backup_database(
+    pool: &SqlitePool,
+    dest: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let dest = CString::new(dest.to_string_lossy().as_bytes())?;
+    let mut conn = pool.acquire().await?;
+    let mut handle = conn.lock_handle().await?;
+    let source = handle.as_raw_handle().as_ptr();
+
+    // SAFETY: `source` stays valid while `handle` holds the connection lock, and
+    // `dest_db` and `backup` are released on every path below.
+    unsafe {
+        let mut dest_db = std::ptr::null_mut();
+        let flags = ffi::SQLITE_OPEN_READWRITE | ffi::SQLITE_OPEN_CREATE;
+        let rc = ffi::sqlite3_open_v2(dest.as_ptr(), &mut dest_db, flags, std::ptr::null());
+        if rc != ffi::SQLITE_OK {
+            ffi::sqlite3_close(dest_db);
+            return Err(format!("Failed to open backup destination (code {})", rc).into());
+        }
+
+        let main = c"main";
+        let backup = ffi::sqlite3_backup_init(dest_db, main.as_ptr(), source, main.as_ptr());
+        if backup.is_null() {
+            let code = ffi::sqlite3_errcode(dest_db);
+            ffi::sqlite3_close(dest_db);
+            return Err(format!("Failed to start backup (code {})", code).into());
+        }
+
+        let started = Instant::now();
+        let mut rc;
+        loop {
+            let pages = if started.elapsed() < BACKUP_INCREMENTAL_FOR {
+                BACKUP_PAGES_PER_STEP
+            } else {
+                -1
+            };
+            rc = ffi::sqlite3_backup_step(backup, pages);
+            match rc {
+                ffi::SQLITE_OK | ffi::SQLITE_BUSY | ffi::SQLITE_LOCKED
+                    if started.elapsed() < BACKUP_TIMEOUT =>
+                {
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                }
+                _ => break,
+            }
+        }
+
+        ffi::sqlite3_backup_finish(backup);
+        ffi::sqlite3_close(dest_db);
+
+        if matches!(rc, ffi::SQLITE_BUSY | ffi::SQLITE_LOCKED) {
+            return Err(BackupBusy.into());
+        }
+        if rc != ffi::SQLITE_DONE {
+            return Err(format!("Backup failed (code {})", rc).into());
+        }
+    }
+
+    Ok(())
+}
+
+async fn schema_columns(conn: &mut SqliteConnection) -> Result<BTreeSet<String>, sqlx::Error> {
+    let rows: Vec<(String, String)> = sqlx::query_as(
+        r#"
+        SELECT m.name, p.name
+        FROM sqlite_master m
+        JOIN pragma_table_info(m.name) p
+        WHERE m.type = 'table' AND m.name NOT LIKE 'sqlite_%' AND m.name NOT LIKE '_sqlx_%'
+        "#,
+    )
+    .fetch_all(conn)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|(table, column)| format!("{}.{}", table, column))
+        .collect())
+}
+
+/// Checks that the database at `db_path` is an intact workspace database and
+/// brings it up to the latest schema.
+///
+/// Databases exported before migrations were tracked are accepted as long as
+/// they already hold the transcript tables. Databases from a newer build are
+/// rejected rather than half-migrated.
+pub async fn upgrade_imported_database(db_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let mut conn = SqliteConnection::connect(&database_url(db_path)).await?;
+    let checked = check_importable(&mut conn).await;
+    let migrated = match checked {
+        Ok(()) => migrate(&mut conn).await.map_err(|err| err.into()),
+        Err(err) => Err(err),
+    };
+    let compared = match migrated {
+        Ok(()) => compare_with_latest_schema(&mut conn).await,
+        Err(err) => Err(err),
+    };
+    conn.close().await?;
+    compared
+}
+
+async fn check_importable(conn: &mut SqliteConnection) -> Result<(), Box<dyn std::error::Error>> {
+    let integrity: String = sqlx::query_scalar("PRAGMA quick_check")
+        .fetch_one(&mut *conn)
+        .await?;
+    if integrity != "ok" {
+        return Err(format!("Database failed integrity check: {}", integrity).into());
+    }
+
+    let version = schema_version(conn).await?;
+    if version > latest_schema_version() {
+        return Err(format!(
+            "Database schema version {} is newer than this server's {}",
+            version,
+            latest_schema_version()
+        )
+        .into());
+    }
+
+    if version == 0 {
+        let has_lines: bool = sqlx::query_scalar(
+            "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'lines')",
+        )
+        .fetch_one(&mut *conn)
+        .await?;
+        if !has_lines {
+            return Err("Database does not contain any transcript tables".into());
+        }
+    }
+
+    Ok(())
+}
+
+async fn compare_with_latest_schema(
+    conn: &mut SqliteConnection,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut expected_conn = SqliteConnection::connect("sqlite::memory:").await?;
+    MIGRATOR.run(&mut expected_conn).await?;
+    let expected = schema_columns(&mut expected_conn).await?;
+    expected_conn.close().await?;
+
+    let actual = schema_columns(conn).await?;
+    let missing: Vec<&str> = expected.difference(&actual).map(String::as_str).collect();
+    if !missing.is_empty() {
+        return Err(format!("Incompatible schema, missing: {}", missing.join(", ")).into());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn opening_a_legacy_database_tokenizes_existing_lines() {
+        let db_path = std::env::temp_dir().join(format!("legacy-{}.sqlite", uuid::Uuid::new_v4()));
+        let url = database_url(&db_path);
+        Sqlite::create_database(&url).await.unwrap();
+
+        let mut conn = SqliteConnection::connect(&url).await.unwrap();
+        sqlx::raw_sql(include_str!("../migrations/0001_initial_schema.sql"))
+            .execute(&mut conn)
+            .await
+            .unwrap();
+        sqlx::raw_sql(
+            r#"
+            INSERT INTO series (id, name) VALUES (1, 'Show');
+            INSERT INTO seasons (id, series_id, number) VALUES (1, 1, 1);
+            INSERT INTO episodes (id, season_id, number, code, sort_key, title)
+                VALUES (1, 1, 1, '1', 100, 'Pilot');
+            INSERT INTO lines (season_id, episode_id, line_number, content)
+                VALUES (1, 1, 1, 'Hello there'), (1, 1, 2, '...');
+            "#,
+        )
+        .execute(&mut conn)
         .await
-        .insert(database_url.clone(), db_pool.clone());
+        .unwrap();
+        conn.close().await.unwrap();
 
-    Ok((db_pool, db_path))
+        let pool = open_database(&db_path).await.unwrap();
+        let terms: Vec<String> = sqlx::query_scalar("SELECT term FROM tokens ORDER BY position")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        pool.close().await;
+        remove_database_files(&db_path).await.unwrap();
+
+        assert_eq!(terms, vec!["hello", "there"]);
+    }
 }
@@ -1,36 +1,229 @@
-use sqlx::SqlitePool;
-use std::fs::read_dir;
-use std::path::Path;
+use crate::models::EpisodeKind;
+use crate::text::store_tokens;
+use sqlx::{Sqlite, SqlitePool, Transaction};
+use std::fs::{read_dir, DirEntry};
+use std::path::{Path, PathBuf};
 use tokio::{
     fs::File,
     io::{AsyncBufReadExt, BufReader},
 };
 
+const SPECIAL_SORT_BASE: i64 = 100_000;
+const SHORT_SORT_BASE: i64 = 200_000;
+
+/// Episode details derived from a transcript file name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EpisodeFile {
+    pub number: i32,
+    pub code: String,
+    pub kind: EpisodeKind,
+    pub sort_key: i64,
+    pub title: String,
+}
+
+/// Parses an episode code such as `12`, `E12a`, `SP1` or `sh3` into its kind,
+/// number and normalized display code (`12`, `12a`, `SP1`, `SH3`).
+///
+/// A single trailing letter marks one part of a split or multi-part episode.
+pub fn parse_episode_code(code: &str) -> Option<(EpisodeKind, i32, String)> {
+    let code = code.trim().to_ascii_uppercase();
+    let (kind, rest) = if let Some(rest) = code.strip_prefix("SP") {
+        (EpisodeKind::Special, rest)
+    } else if let Some(rest) = code.strip_prefix("SH") {
+        (EpisodeKind::Short, rest)
+    } else {
+        (
+            EpisodeKind::Regular,
+            code.strip_prefix('E').unwrap_or(&code),
+        )
+    };
+
+    let digits_end = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    let (digits, suffix) = rest.split_at(digits_end);
+    let number = digits.parse::<i32>().ok()?;
+
+    match (kind, suffix.len()) {
+        (EpisodeKind::Special, 0) => Some((kind, number, format!("SP{}", number))),
+        (EpisodeKind::Short, 0) => Some((kind, number, format!("SH{}", number))),
+        (EpisodeKind::Regular, 0) => Some((kind, number, number.to_string())),
+        (EpisodeKind::Regular, 1) if suffix.chars().all(|c| c.is_ascii_alphabetic()) => Some((
+            EpisodeKind::Part,
+            number,
+            format!("{}{}", number, suffix.to_ascii_lowercase()),
+        )),
+        _ => None,
+    }
+}
+
+fn episode_sort_key(kind: EpisodeKind, number: i32, code: &str) -> i64 {
+    let base = i64::from(number) * 100;
+    match kind {
+        EpisodeKind::Regular => base,
+        EpisodeKind::Part => {
+            let part = code.chars().last().map_or(0, |c| c as i64 - 'a' as i64 + 1);
+            base + part
+        }
+        EpisodeKind::Special => SPECIAL_SORT_BASE + base,
+        EpisodeKind::Short => SHORT_SORT_BASE + base,
+    }
+}
+
+/// Parses a transcript file name such as `E12a - Title.txt`.
+///
+/// Every episode in season 0 is treated as a special, matching the common
+/// `S00` convention for bonus material.
+pub fn parse_episode_file_name(file_name: &str, season_num: i32) -> Option<EpisodeFile> {
+    let stem = file_name.strip_suffix(".txt").unwrap_or(file_name);
+    let code = stem.split('-').next()?;
+    let (kind, number, code) = parse_episode_code(code)?;
+    let kind = if season_num == 0 && kind == EpisodeKind::Regular {
+        EpisodeKind::Special
+    } else {
+        kind
+    };
+
+    let title = stem
+        .split(" - ")
+        .nth(1)
+        .map_or_else(String::new, |title| title.to_string());
+
+    Some(EpisodeFile {
+        number,
+        sort_key: episode_sort_key(kind, number, &code),
+        code,
+        kind,
+        title,
+    })
+}
+
+fn is_episode_file_name(name: &str) -> bool {
+    let has_prefix = |prefix: &str| {
+        name.get(..prefix.len())
+            .is_some_and(|start| start.eq_ignore_ascii_case(prefix))
+    };
+    has_prefix("E") || has_prefix("SP") || has_prefix("SH")
+}
+
+fn is_season_dir_name(name: &str) -> bool {
+    name.strip_prefix('S')
+        .is_some_and(|num| !num.is_empty() && num.chars().all(|c| c.is_ascii_digit()))
+}
+
+fn visible_dirs(path: &Path) -> std::io::Result<Vec<DirEntry>> {
+    Ok(read_dir(path)?
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().map(|ft| ft.is_dir()).unwrap_or(false))
+        .filter(|entry| {
+            entry
+                .file_name()
+                .to_str()
+                .is_some_and(|name| !name.starts_with('.') && !name.starts_with("__"))
+        })
+        .collect())
+}
+
+fn has_season_dirs(dirs: &[DirEntry]) -> bool {
+    dirs.iter()
+        .any(|entry| entry.file_name().to_str().is_some_and(is_season_dir_name))
+}
+
+fn has_episode_files(path: &Path) -> std::io::Result<bool> {
+    Ok(read_dir(path)?.filter_map(Result::ok).any(|entry| {
+        entry.file_type().is_ok_and(|ft| ft.is_file())
+            && entry.file_name().to_str().is_some_and(is_episode_file_name)
+    }))
+}
+
+/// Lists the series directories under an extracted upload.
+///
+/// Each top-level directory is a series named after the directory. Archives
+/// that hold season directories at their root are a single series named
+/// `default_series`. A lone wrapper directory around several series
+/// directories, as some exports produce, is looked through.
+fn find_series_dirs(
+    base_path: &Path,
+    default_series: &str,
+) -> Result<Vec<(String, PathBuf)>, Box<dyn std::error::Error>> {
+    let dirs = visible_dirs(base_path)?;
+    let has_root_seasons = has_season_dirs(&dirs);
+
+    if let [wrapper] = dirs.as_slice() {
+        if !has_root_seasons
+            && !has_episode_files(base_path)?
+            && !has_season_dirs(&visible_dirs(&wrapper.path())?)
+        {
+            return find_series_dirs(&wrapper.path(), default_series);
+        }
+    }
+
+    if dirs.is_empty() || has_root_seasons {
+        return Ok(vec![(default_series.to_string(), base_path.to_path_buf())]);
+    }
+
+    let mut series = dirs
+        .into_iter()
+        .map(|entry| {
+            (
+                entry.file_name().to_string_lossy().into_owned(),
+                entry.path(),
+            )
+        })
+        .collect::<Vec<_>>();
+    series.sort();
+    Ok(series)
+}
+
 pub async fn process_seasons(
     db: &SqlitePool,
     base_path: &str,
+    default_series: &str,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let path = Path::new(base_path);
-    let first_dir = read_dir(path)?
-        .filter_map(Result::ok)
-        .find(|entry| entry.file_type().map(|ft| ft.is_dir()).unwrap_or(false));
+    let series_dirs = find_series_dirs(Path::new(base_path), default_series)?;
+
+    println!(
+        "Found series: {:?}",
+        series_dirs.iter().map(|(name, _)| name).collect::<Vec<_>>()
+    );
+
+    let mut transaction = db.begin().await?;
 
-    let season_base_path = first_dir
-        .map(|dir| dir.path())
-        .unwrap_or_else(|| path.to_path_buf());
+    for (series_name, series_path) in series_dirs {
+        let series_id: i64 = sqlx::query_scalar("INSERT INTO series (name) VALUES (?) ON CONFLICT(name) DO UPDATE SET name = excluded.name RETURNING id")
+            .bind(&series_name)
+            .fetch_one(&mut *transaction)
+            .await?;
 
-    let mut seasons = read_dir(&season_base_path)?
+        println!("Processing series: {}", series_name);
+
+        process_series(&mut transaction, series_id, &series_path).await?;
+    }
+
+    transaction.commit().await?;
+
+    println!("Finished processing all seasons and episodes.");
+    Ok(())
+}
+
+async fn process_series(
+    transaction: &mut Transaction<'_, Sqlite>,
+    series_id: i64,
+    series_path: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut seasons = read_dir(series_path)?
         .filter_map(Result::ok)
         .filter(|entry| {
             entry
                 .file_name()
                 .to_str()
-                .map_or(false, |name| name.starts_with('S'))
+                .is_some_and(|name| name.starts_with('S'))
         })
         .collect::<Vec<_>>();
 
     println!(
-        "Found seasons: {:?}",
+        "Found seasons for series {}: {:?}",
+        series_id,
         seasons.iter().map(|s| s.file_name()).collect::<Vec<_>>()
     );
 
@@ -43,8 +236,6 @@ pub async fn process_seasons(
             .unwrap_or(0)
     });
 
-    let mut transaction = db.begin().await?;
-
     for season in seasons {
         let season_num = season
             .file_name()
@@ -55,19 +246,15 @@ pub async fn process_seasons(
 
         println!("Processing season: {}", season_num);
 
-        let season_id: i64 = sqlx::query_scalar("INSERT INTO seasons (number) VALUES (?) ON CONFLICT(number) DO UPDATE SET number = excluded.number RETURNING id")
+        let season_id: i64 = sqlx::query_scalar("INSERT INTO seasons (series_id, number) VALUES (?, ?) ON CONFLICT(series_id, number) DO UPDATE SET number = excluded.number RETURNING id")
+            .bind(series_id)
             .bind(season_num)
-            .fetch_one(&mut *transaction)
+            .fetch_one(&mut **transaction)
             .await?;
 
         let episodes = read_dir(season.path())?
             .filter_map(Result::ok)
-            .filter(|entry| {
-                entry
-                    .file_name()
-                    .to_str()
-                    .map_or(false, |name| name.starts_with('E'))
-            })
+            .filter(|entry| entry.file_name().to_str().is_some_and(is_episode_file_name))
             .collect::<Vec<_>>();
 
         println!(
@@ -78,31 +265,26 @@ pub async fn process_seasons(
 
         for episode in episodes {
             let file_name = episode.file_name();
-            let episode_num = file_name
+            let episode_file = file_name
                 .to_str()
-                .and_then(|name| name.strip_prefix('E'))
-                .and_then(|num| num.split('-').next())
-                .and_then(|num| num.trim().parse::<i32>().ok())
+                .and_then(|name| parse_episode_file_name(name, season_num))
                 .ok_or_else(|| format!("Invalid episode file name: {:?}", file_name))?;
 
-            let title = file_name
-                .to_str()
-                .and_then(|name| name.strip_suffix(".txt"))
-                .and_then(|name| name.split(" - ").nth(1))
-                .map_or_else(|| "".to_string(), |title| title.to_string());
-
             println!(
                 "Processing episode {} - {} with title: {}",
-                episode_num,
+                episode_file.code,
                 file_name.to_string_lossy(),
-                title
+                episode_file.title
             );
 
-            let episode_id: i64 = sqlx::query_scalar("INSERT INTO episodes (season_id, number, title) VALUES (?, ?, ?) ON CONFLICT(season_id, number) DO UPDATE SET title = excluded.title RETURNING id")
+            let episode_id: i64 = sqlx::query_scalar("INSERT INTO episodes (season_id, number, code, kind, sort_key, title) VALUES (?, ?, ?, ?, ?, ?) ON CONFLICT(season_id, code) DO UPDATE SET title = excluded.title RETURNING id")
                 .bind(season_id)
-                .bind(episode_num)
-                .bind(title)
-                .fetch_one(&mut *transaction)
+                .bind(episode_file.number)
+                .bind(&episode_file.code)
+                .bind(episode_file.kind)
+                .bind(episode_file.sort_key)
+                .bind(&episode_file.title)
+                .fetch_one(&mut **transaction)
                 .await?;
 
             let file = File::open(episode.path()).await?;
@@ -119,7 +301,7 @@ pub async fn process_seasons(
                         "INSERT INTO speakers (name) VALUES (?) ON CONFLICT(name) DO UPDATE SET name = excluded.name RETURNING id"
                     )
                     .bind(speaker)
-                    .fetch_one(&mut *transaction)
+                    .fetch_one(&mut **transaction)
                     .await?;
 
                     (Some(speaker_id), content.trim().to_string())
@@ -127,21 +309,136 @@ pub async fn process_seasons(
                     (None, line.trim().to_string())
                 };
 
-                sqlx::query("INSERT INTO lines (season_id, episode_id, speaker_id, line_number, content) VALUES (?, ?, ?, ?, ?)")
+                let line_id: i64 = sqlx::query_scalar("INSERT INTO lines (season_id, episode_id, speaker_id, line_number, content) VALUES (?, ?, ?, ?, ?) RETURNING id")
                     .bind(season_id)
                     .bind(episode_id)
                     .bind(speaker_id)
                     .bind(line_num)
-                    .bind(content)
-                    .execute(&mut *transaction)
+                    .bind(&content)
+                    .fetch_one(&mut **transaction)
                     .await?;
 
+                store_tokens(transaction, line_id, &content).await?;
+
                 line_num += 1;
             }
         }
     }
-    transaction.commit().await?;
 
-    println!("Finished processing all seasons and episodes.");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_episode_code_normalizes_codes() {
+        assert_eq!(
+            parse_episode_code("12"),
+            Some((EpisodeKind::Regular, 12, "12".to_string()))
+        );
+        assert_eq!(
+            parse_episode_code("e07"),
+            Some((EpisodeKind::Regular, 7, "7".to_string()))
+        );
+        assert_eq!(
+            parse_episode_code("E12A"),
+            Some((EpisodeKind::Part, 12, "12a".to_string()))
+        );
+        assert_eq!(
+            parse_episode_code("sp1"),
+            Some((EpisodeKind::Special, 1, "SP1".to_string()))
+        );
+        assert_eq!(
+            parse_episode_code(" SH3 "),
+            Some((EpisodeKind::Short, 3, "SH3".to_string()))
+        );
+    }
+
+    #[test]
+    fn parse_episode_code_rejects_malformed_codes() {
+        assert_eq!(parse_episode_code(""), None);
+        assert_eq!(parse_episode_code("E"), None);
+        assert_eq!(parse_episode_code("E12ab"), None);
+        assert_eq!(parse_episode_code("SP1a"), None);
+        assert_eq!(parse_episode_code("E1é"), None);
+    }
+
+    #[test]
+    fn parse_episode_file_name_reads_code_and_title() {
+        let episode = parse_episode_file_name("E12a - The Return.txt", 2).unwrap();
+        assert_eq!(episode.code, "12a");
+        assert_eq!(episode.number, 12);
+        assert_eq!(episode.kind, EpisodeKind::Part);
+        assert_eq!(episode.title, "The Return");
+
+        let untitled = parse_episode_file_name("sp1.txt", 3).unwrap();
+        assert_eq!(untitled.code, "SP1");
+        assert_eq!(untitled.kind, EpisodeKind::Special);
+        assert_eq!(untitled.title, "");
+
+        assert_eq!(parse_episode_file_name("notes.txt", 1), None);
+    }
+
+    #[test]
+    fn parse_episode_file_name_treats_season_zero_as_specials() {
+        let episode = parse_episode_file_name("E4 - Bonus.txt", 0).unwrap();
+        assert_eq!(episode.kind, EpisodeKind::Special);
+        assert_eq!(episode.number, 4);
+    }
+
+    #[test]
+    fn episode_sort_key_orders_parts_specials_and_shorts() {
+        let key = |code: &str| {
+            let (kind, number, code) = parse_episode_code(code).unwrap();
+            episode_sort_key(kind, number, &code)
+        };
+
+        assert!(key("E2") < key("E2a"));
+        assert!(key("E2a") < key("E2b"));
+        assert!(key("E2b") < key("E3"));
+        assert!(key("E999") < key("SP1"));
+        assert!(key("SP1") < key("SP2"));
+        assert!(key("SP999") < key("SH1"));
+    }
+
+    fn series_names(layout: &[&str]) -> Vec<String> {
+        let root = std::env::temp_dir().join(format!("series-{}", uuid::Uuid::new_v4()));
+        for dir in layout {
+            std::fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        let series = find_series_dirs(&root, "default").unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+        series.into_iter().map(|(name, _)| name).collect()
+    }
+
+    #[test]
+    fn find_series_dirs_classifies_upload_layouts() {
+        assert_eq!(series_names(&["S01", "S02"]), vec!["default"]);
+        assert_eq!(series_names(&["Show/S01"]), vec!["Show"]);
+        assert_eq!(
+            series_names(&["Alpha/S01", "Beta/S01"]),
+            vec!["Alpha", "Beta"]
+        );
+    }
+
+    #[test]
+    fn find_series_dirs_looks_through_a_wrapper_directory() {
+        assert_eq!(
+            series_names(&["export/Alpha/S01", "export/Beta/S01", "__MACOSX"]),
+            vec!["Alpha", "Beta"]
+        );
+        assert_eq!(series_names(&["export/Alpha/S01"]), vec!["Alpha"]);
+    }
+
+    #[test]
+    fn is_episode_file_name_ignores_case() {
+        assert!(is_episode_file_name("E12 - Title.txt"));
+        assert!(is_episode_file_name("e12a.txt"));
+        assert!(is_episode_file_name("sp1.txt"));
+        assert!(is_episode_file_name("Sh3.txt"));
+        assert!(!is_episode_file_name("notes.txt"));
+        assert!(!is_episode_file_name("S"));
+    }
+}
@@ -0,0 +1,200 @@
+use crate::analysis::LineScope;
+use crate::models::{InteractionEdge, InteractionGraph, InteractionNode};
+use sqlx::{FromRow, SqlitePool};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write as _;
+
+#[derive(FromRow)]
+struct ScriptLine {
+    episode_id: i64,
+    speaker_id: Option<i64>,
+    speaker_name: Option<String>,
+}
+
+#[derive(Default)]
+struct PairCounts {
+    turns: i64,
+    scenes: i64,
+}
+
+/// Orders a pair so both directions of a conversation share one edge.
+fn pair(a: i64, b: i64) -> (i64, i64) {
+    (a.min(b), a.max(b))
+}
+
+fn add_scene(scene: &mut BTreeSet<i64>, pairs: &mut BTreeMap<(i64, i64), PairCounts>) {
+    let speakers: Vec<i64> = scene.iter().copied().collect();
+    for (i, a) in speakers.iter().enumerate() {
+        for b in &speakers[i + 1..] {
+            pairs.entry(pair(*a, *b)).or_default().scenes += 1;
+        }
+    }
+    scene.clear();
+}
+
+/// Builds a weighted, undirected graph of who talks to whom.
+///
+/// Two speakers share a turn when one's line directly follows the other's.
+/// Transcripts carry no scene markers, so a scene is taken to be a run of
+/// dialogue between direction lines (lines without a speaker); speakers
+/// present in the same scene are linked once per scene. Edges lighter than
+/// `min_weight` are dropped, along with speakers left without an edge.
+pub async fn interaction_graph(
+    pool: &SqlitePool,
+    series: Option<i64>,
+    season: Option<i64>,
+    episode: Option<i64>,
+    min_weight: i64,
+) -> Result<InteractionGraph, sqlx::Error> {
+    let scope = LineScope {
+        series,
+        season,
+        episode,
+        speaker: None,
+    };
+    let lines: Vec<ScriptLine> = scope
+        .bind(sqlx::query_as(&format!(
+            r#"
+            SELECT l.episode_id, l.speaker_id, sp.name AS speaker_name
+            FROM lines l
+            JOIN seasons sn ON l.season_id = sn.id
+            LEFT JOIN speakers sp ON l.speaker_id = sp.id
+            WHERE {filter}
+            ORDER BY l.episode_id, l.line_number
+            "#,
+            filter = LineScope::FILTER,
+        )))
+        .fetch_all(pool)
+        .await?;
+
+    let mut names: HashMap<i64, (String, i64)> = HashMap::new();
+    let mut pairs: BTreeMap<(i64, i64), PairCounts> = BTreeMap::new();
+    let mut scene = BTreeSet::new();
+    let mut previous: Option<&ScriptLine> = None;
+
+    for line in &lines {
+        let new_episode = previous.is_some_and(|p| p.episode_id != line.episode_id);
+        if new_episode || line.speaker_id.is_none() {
+            add_scene(&mut scene, &mut pairs);
+        }
+
+        if let (Some(speaker_id), Some(name)) = (line.speaker_id, &line.speaker_name) {
+            names
+                .entry(speaker_id)
+                .or_insert_with(|| (name.clone(), 0))
+                .1 += 1;
+            scene.insert(speaker_id);
+
+            let answered = previous
+                .filter(|p| p.episode_id == line.episode_id)
+                .and_then(|p| p.speaker_id)
+                .filter(|&id| id != speaker_id);
+            if let Some(other) = answered {
+                pairs.entry(pair(other, speaker_id)).or_default().turns += 1;
+            }
+        }
+
+        previous = Some(line);
+    }
+    add_scene(&mut scene, &mut pairs);
+
+    let edges: Vec<InteractionEdge> = pairs
+        .into_iter()
+        .map(|((source, target), counts)| InteractionEdge {
+            source,
+            target,
+            turns: counts.turns,
+            scenes: counts.scenes,
+            weight: counts.turns + counts.scenes,
+        })
+        .filter(|edge| edge.weight >= min_weight)
+        .collect();
+
+    let connected: BTreeSet<i64> = edges
+        .iter()
+        .flat_map(|edge| [edge.source, edge.target])
+        .collect();
+    let mut nodes: Vec<InteractionNode> = connected
+        .into_iter()
+        .filter_map(|id| {
+            let (name, line_count) = names.remove(&id)?;
+            Some(InteractionNode {
+                id,
+                name,
+                line_count,
+            })
+        })
+        .collect();
+    nodes.sort_by(|a, b| b.line_count.cmp(&a.line_count).then(a.name.cmp(&b.name)));
+
+    Ok(InteractionGraph { nodes, edges })
+}
+
+fn escape_xml(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// Renders the graph as GraphML, which Gephi and most graph tools import.
+pub fn to_graphml(graph: &InteractionGraph) -> String {
+    let mut xml = String::from(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<graphml xmlns="http://graphml.graphdrawing.org/xmlns">
+  <key id="label" for="node" attr.name="label" attr.type="string"/>
+  <key id="line_count" for="node" attr.name="line_count" attr.type="long"/>
+  <key id="weight" for="edge" attr.name="weight" attr.type="double"/>
+  <key id="turns" for="edge" attr.name="turns" attr.type="long"/>
+  <key id="scenes" for="edge" attr.name="scenes" attr.type="long"/>
+  <graph id="interactions" edgedefault="undirected">
+"#,
+    );
+    for node in &graph.nodes {
+        let _ = writeln!(
+            xml,
+            r#"    <node id="s{}"><data key="label">{}</data><data key="line_count">{}</data></node>"#,
+            node.id,
+            escape_xml(&node.name),
+            node.line_count
+        );
+    }
+    for edge in &graph.edges {
+        let _ = writeln!(
+            xml,
+            r#"    <edge source="s{}" target="s{}"><data key="weight">{}</data><data key="turns">{}</data><data key="scenes">{}</data></edge>"#,
+            edge.source, edge.target, edge.weight, edge.turns, edge.scenes
+        );
+    }
+    xml.push_str("  </graph>\n</graphml>\n");
+    xml
+}
+
+fn escape_dot(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Renders the graph in Graphviz DOT.
+pub fn to_dot(graph: &InteractionGraph) -> String {
+    let mut dot = String::from("graph interactions {\n");
+    for node in &graph.nodes {
+        let _ = writeln!(
+            dot,
+            "  s{} [label=\"{}\", line_count={}];",
+            node.id,
+            escape_dot(&node.name),
+            node.line_count
+        );
+    }
+    for edge in &graph.edges {
+        let _ = writeln!(
+            dot,
+            "  s{} -- s{} [weight={}, turns={}, scenes={}];",
+            edge.source, edge.target, edge.weight, edge.turns, edge.scenes
+        );
+    }
+    dot.push_str("}\n");
+    dot
+}
@@ -1,4 +1,22 @@
+pub mod analysis;
+pub mod annotations;
 pub mod api;
+pub mod auth;
+pub mod cards;
+pub mod collocations;
 pub mod db;
 pub mod file_parser;
+pub mod interactions;
+pub mod markov;
+pub mod metadata;
+pub mod metrics;
 pub mod models;
+pub mod quiz;
+pub mod random;
+pub mod recurring;
+pub mod registry;
+pub mod sentiment;
+pub mod similarity;
+pub mod stats;
+pub mod text;
+pub mod workspace;
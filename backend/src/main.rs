@@ -1,34 +1,67 @@
-mod api;
-mod db;
-mod file_parser;
-mod models;
-
 use actix_cors::Cors;
 use actix_web::{web, App, HttpServer};
-use api::init_routes;
+use backend::api::init_routes;
+use backend::auth::TokenSigner;
+use backend::markov::ModelCache;
+use backend::registry::DatabaseRegistry;
+use backend::workspace;
 use dotenv::dotenv;
-use sqlx::SqlitePool;
-use std::collections::HashMap;
 use std::path::Path;
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::Duration;
+
+const DEFAULT_IDLE_TTL_SECS: u64 = 60 * 60;
+const DEFAULT_REAP_INTERVAL_SECS: u64 = 60;
 
-pub type DatabaseRegistry = Arc<Mutex<HashMap<String, SqlitePool>>>;
+fn env_secs(key: &str, default: u64) -> Duration {
+    let secs = std::env::var(key)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default);
+    Duration::from_secs(secs)
+}
 
 use std::fs;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     dotenv().ok();
-    let schema_path = "./schema.sql".to_string();
-    let db_registry: DatabaseRegistry = Arc::new(Mutex::new(HashMap::new()));
+    let db_registry = web::Data::new(DatabaseRegistry::new());
+    let signer = web::Data::new(TokenSigner::load().await?);
+    let markov_models = web::Data::new(ModelCache::new());
 
-    if Path::new("./temp_dbs").exists() {
-        if let Err(err) = fs::remove_dir_all("./temp_dbs") {
+    if Path::new(workspace::SCRATCH_DIR).exists() {
+        if let Err(err) = fs::remove_dir_all(workspace::SCRATCH_DIR) {
             eprintln!("Failed to clean up temp_dbs directory: {}", err);
         }
     }
-    fs::create_dir_all("./temp_dbs")?;
+    fs::create_dir_all(workspace::SCRATCH_DIR)?;
+
+    for (id, pool) in workspace::load_workspaces().await? {
+        match workspace::list_shares(&pool).await {
+            Ok(shares) => {
+                for share in shares {
+                    db_registry.publish_share(share.id, id.clone());
+                }
+            }
+            Err(err) => eprintln!("Failed to load shares for workspace {}: {}", id, err),
+        }
+
+        let path = workspace::workspace_path(&id);
+        db_registry.insert(id, pool, path, false);
+    }
+
+    let idle_ttl = env_secs("DB_IDLE_TTL_SECS", DEFAULT_IDLE_TTL_SECS);
+    let reap_interval = env_secs("DB_REAP_INTERVAL_SECS", DEFAULT_REAP_INTERVAL_SECS);
+    let reaper_registry = db_registry.clone();
+    let reaper_models = markov_models.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(reap_interval);
+        loop {
+            interval.tick().await;
+            reaper_registry.reap_idle(idle_ttl).await;
+            reaper_models.retain_workspaces(|id| reaper_registry.contains(id));
+        }
+    });
 
     HttpServer::new(move || {
         let cors = Cors::default()
@@ -38,8 +71,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
         App::new()
             .wrap(cors)
-            .app_data(web::Data::new(db_registry.clone())) // Registry with SqlitePool
-            .app_data(web::Data::new(schema_path.clone())) // Schema Path
+            .app_data(db_registry.clone()) // Registry with SqlitePool
+            .app_data(signer.clone()) // Bearer token signer
+            .app_data(markov_models.clone()) // Markov models per workspace
             .configure(init_routes)
     })
     .bind("127.0.0.1:8081")?
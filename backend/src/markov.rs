@@ -0,0 +1,266 @@
+use crate::models::Speaker;
+use dashmap::DashMap;
+use rand::Rng;
+use sqlx::SqlitePool;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+pub const MAX_ORDER: usize = 4;
+pub const DEFAULT_ORDER: usize = 2;
+pub const MAX_WORDS: usize = 100;
+pub const MAX_LINES: usize = 20;
+
+/// Draws allowed per requested line before giving up, since short chains
+/// often walk straight back into a line the speaker already said.
+const ATTEMPTS_PER_LINE: usize = 50;
+
+/// Marks both the start and the end of a line.
+const BOUNDARY: u32 = 0;
+
+/// Bounds on the length of generated lines, in words.
+#[derive(Debug, Clone, Copy)]
+pub struct LengthLimits {
+    pub min_words: usize,
+    pub max_words: usize,
+}
+
+/// A word-level Markov chain over one speaker's lines, conditioned on the
+/// previous `order` words (at least one).
+///
+/// Words keep their punctuation, so generated lines read like dialogue
+/// without any detokenizing.
+#[derive(Debug)]
+pub struct MarkovModel {
+    order: usize,
+    words: Vec<String>,
+    /// Each state of `order` words with the words that followed it and how
+    /// often, in the order they were first seen so seeded runs repeat.
+    transitions: HashMap<Vec<u32>, Vec<(u32, u32)>>,
+    /// The training lines, compared against generated lines.
+    originals: HashSet<String>,
+}
+
+/// Lowercases and rejoins words so lines differing only in case or spacing
+/// compare equal.
+fn line_key<'a>(words: impl IntoIterator<Item = &'a str>) -> String {
+    words
+        .into_iter()
+        .map(|word| word.to_lowercase())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Removes bracketed and parenthesised stage directions from a line.
+fn strip_directions(content: &str) -> String {
+    let mut spoken = String::with_capacity(content.len());
+    let mut depth = 0usize;
+    for c in content.chars() {
+        match c {
+            '[' | '(' => depth += 1,
+            ']' | ')' if depth > 0 => depth -= 1,
+            _ if depth == 0 => spoken.push(c),
+            _ => {}
+        }
+    }
+    spoken
+}
+
+impl MarkovModel {
+    pub fn train(lines: &[String], order: usize) -> Self {
+        let mut words = vec![String::new()];
+        let mut ids: HashMap<String, u32> = HashMap::new();
+        let mut transitions: HashMap<Vec<u32>, Vec<(u32, u32)>> = HashMap::new();
+        let mut originals = HashSet::new();
+
+        for line in lines {
+            let spoken = strip_directions(line);
+            let tokens: Vec<&str> = spoken.split_whitespace().collect();
+            if tokens.is_empty() {
+                continue;
+            }
+            originals.insert(line_key(tokens.iter().copied()));
+
+            let mut state = vec![BOUNDARY; order];
+            let sequence = tokens
+                .iter()
+                .map(|token| {
+                    *ids.entry(token.to_string()).or_insert_with(|| {
+                        words.push(token.to_string());
+                        (words.len() - 1) as u32
+                    })
+                })
+                .chain(std::iter::once(BOUNDARY));
+
+            for next in sequence {
+                let followers = transitions.entry(state.clone()).or_default();
+                match followers.iter_mut().find(|(word, _)| *word == next) {
+                    Some((_, count)) => *count += 1,
+                    None => followers.push((next, 1)),
+                }
+                state.remove(0);
+                state.push(next);
+            }
+        }
+
+        Self {
+            order,
+            words,
+            transitions,
+            originals,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.originals.is_empty()
+    }
+
+    fn next_word(&self, state: &[u32], rng: &mut impl Rng) -> Option<u32> {
+        let followers = self.transitions.get(state)?;
+        let total: u32 = followers.iter().map(|(_, count)| count).sum();
+        let mut pick = rng.gen_range(0..total);
+        for (word, count) in followers {
+            if pick < *count {
+                return Some(*word);
+            }
+            pick -= count;
+        }
+        None
+    }
+
+    /// Walks the chain once, or returns `None` if the line runs past
+    /// `max_words`.
+    fn walk(&self, rng: &mut impl Rng, max_words: usize) -> Option<Vec<&str>> {
+        let mut state = vec![BOUNDARY; self.order];
+        let mut line = Vec::new();
+        loop {
+            let next = self.next_word(&state, rng)?;
+            if next == BOUNDARY {
+                return Some(line);
+            }
+            if line.len() == max_words {
+                return None;
+            }
+            line.push(self.words[next as usize].as_str());
+            state.remove(0);
+            state.push(next);
+        }
+    }
+
+    /// Generates up to `count` distinct lines within `limits`, skipping any
+    /// that repeat one of the training lines word for word. Fewer lines come
+    /// back when the model is too small to produce enough new ones.
+    pub fn generate(&self, rng: &mut impl Rng, count: usize, limits: LengthLimits) -> Vec<String> {
+        let mut seen = HashSet::new();
+        let mut lines = Vec::new();
+
+        for _ in 0..count * ATTEMPTS_PER_LINE {
+            if lines.len() == count {
+                break;
+            }
+            let Some(words) = self.walk(rng, limits.max_words) else {
+                continue;
+            };
+            if words.len() < limits.min_words {
+                continue;
+            }
+            let key = line_key(words.iter().copied());
+            if self.originals.contains(&key) || !seen.insert(key) {
+                continue;
+            }
+            lines.push(words.join(" "));
+        }
+
+        lines
+    }
+}
+
+/// Looks a speaker up by id, or by name ignoring case.
+pub async fn find_speaker(
+    pool: &SqlitePool,
+    speaker: &str,
+) -> Result<Option<Speaker>, sqlx::Error> {
+    let speaker = speaker.trim();
+    match speaker.parse::<i64>() {
+        Ok(id) => {
+            sqlx::query_as("SELECT id, name FROM speakers WHERE id = ?")
+                .bind(id)
+                .fetch_optional(pool)
+                .await
+        }
+        Err(_) => {
+            sqlx::query_as(
+                "SELECT id, name FROM speakers WHERE name = ? COLLATE NOCASE ORDER BY id LIMIT 1",
+            )
+            .bind(speaker)
+            .fetch_optional(pool)
+            .await
+        }
+    }
+}
+
+async fn train_speaker(
+    pool: &SqlitePool,
+    speaker_id: i64,
+    order: usize,
+) -> Result<MarkovModel, sqlx::Error> {
+    let lines: Vec<String> =
+        sqlx::query_scalar("SELECT content FROM lines WHERE speaker_id = ? ORDER BY id")
+            .bind(speaker_id)
+            .fetch_all(pool)
+            .await?;
+    Ok(MarkovModel::train(&lines, order))
+}
+
+/// Trained models kept per workspace, speaker and order.
+///
+/// Models are trained on first use and dropped when their workspace's lines
+/// change, so the next request retrains them on the new data.
+#[derive(Default)]
+pub struct ModelCache {
+    models: DashMap<(String, i64, usize), Arc<MarkovModel>>,
+    /// Bumped on every invalidation, so a model trained from lines read
+    /// before an upload finished is not cached.
+    generations: DashMap<String, u64>,
+}
+
+impl ModelCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn generation(&self, workspace: &str) -> u64 {
+        self.generations.get(workspace).map_or(0, |entry| *entry)
+    }
+
+    pub async fn get_or_train(
+        &self,
+        workspace: &str,
+        pool: &SqlitePool,
+        speaker_id: i64,
+        order: usize,
+    ) -> Result<Arc<MarkovModel>, sqlx::Error> {
+        let key = (workspace.to_string(), speaker_id, order);
+        if let Some(model) = self.models.get(&key) {
+            return Ok(model.clone());
+        }
+
+        let generation = self.generation(workspace);
+        let model = Arc::new(train_speaker(pool, speaker_id, order).await?);
+        if self.generation(workspace) == generation {
+            self.models.insert(key, model.clone());
+        }
+        Ok(model)
+    }
+
+    /// Drops every model trained on `workspace`.
+    pub fn invalidate(&self, workspace: &str) {
+        *self.generations.entry(workspace.to_string()).or_default() += 1;
+        self.models.retain(|(id, _, _), _| id != workspace);
+    }
+
+    /// Drops the models of workspaces for which `keep` is false.
+    pub fn retain_workspaces(&self, keep: impl Fn(&str) -> bool) {
+        self.models.retain(|(id, _, _), _| keep(id));
+        self.generations.retain(|id, _| keep(id));
+    }
+}
@@ -0,0 +1,146 @@
+use crate::models::{Metadata, MetadataRecord, MetadataRequest, Sentiment};
+use sqlx::{Sqlite, SqliteConnection, SqlitePool, Transaction};
+
+/// The source of annotations entered through the API. Automatic taggers use
+/// their own source names and never write manual rows.
+pub const MANUAL_SOURCE: &str = "manual";
+
+pub const METADATA_COLUMNS: &str =
+    "id, line_id, source, model_version, sentiment, tone, primary_emotion";
+
+const UPSERT_METADATA_QUERY: &str = r#"
+    INSERT INTO metadata (line_id, source, model_version, sentiment, tone, primary_emotion)
+    VALUES (?, ?, ?, ?, ?, ?)
+    ON CONFLICT(line_id, source) DO UPDATE SET
+        model_version = excluded.model_version,
+        sentiment = excluded.sentiment,
+        tone = excluded.tone,
+        primary_emotion = excluded.primary_emotion
+    RETURNING id, line_id, source, model_version, sentiment, tone, primary_emotion
+"#;
+
+/// Trims a free-text label, treating blank values as unset.
+fn clean_label(label: Option<&str>) -> Option<String> {
+    label
+        .map(str::trim)
+        .filter(|label| !label.is_empty())
+        .map(str::to_string)
+}
+
+pub async fn line_exists(pool: &SqlitePool, line_id: i64) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM lines WHERE id = ?)")
+        .bind(line_id)
+        .fetch_one(pool)
+        .await
+}
+
+/// Returns every metadata row attached to a line, manual annotations first.
+pub async fn fetch_metadata(pool: &SqlitePool, line_id: i64) -> Result<Vec<Metadata>, sqlx::Error> {
+    sqlx::query_as::<_, Metadata>(&format!(
+        "SELECT {} FROM metadata WHERE line_id = ? ORDER BY source <> ?, source",
+        METADATA_COLUMNS
+    ))
+    .bind(line_id)
+    .bind(MANUAL_SOURCE)
+    .fetch_all(pool)
+    .await
+}
+
+/// Creates or replaces the manual annotation on a line.
+pub async fn upsert_metadata(
+    pool: &SqlitePool,
+    line_id: i64,
+    request: &MetadataRequest,
+) -> Result<Metadata, sqlx::Error> {
+    sqlx::query_as::<_, Metadata>(UPSERT_METADATA_QUERY)
+        .bind(line_id)
+        .bind(MANUAL_SOURCE)
+        .bind(None::<String>)
+        .bind(request.sentiment)
+        .bind(clean_label(request.tone.as_deref()))
+        .bind(clean_label(request.primary_emotion.as_deref()))
+        .fetch_one(pool)
+        .await
+}
+
+/// Removes a line's manual annotation, returning false if it had none.
+/// Metadata from automatic taggers is left in place.
+pub async fn delete_metadata(pool: &SqlitePool, line_id: i64) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query("DELETE FROM metadata WHERE line_id = ? AND source = ?")
+        .bind(line_id)
+        .bind(MANUAL_SOURCE)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Reads bulk metadata rows from a CSV file with a header row.
+pub fn parse_csv_records(body: &[u8]) -> Result<Vec<MetadataRecord>, csv::Error> {
+    csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(body)
+        .deserialize()
+        .collect()
+}
+
+/// Returns the distinct ids among `line_ids` that do not name a line.
+pub async fn missing_lines(
+    pool: &SqlitePool,
+    line_ids: impl IntoIterator<Item = i64>,
+) -> Result<Vec<i64>, sqlx::Error> {
+    let mut line_ids: Vec<i64> = line_ids.into_iter().collect();
+    line_ids.sort_unstable();
+    line_ids.dedup();
+
+    let mut missing = Vec::new();
+    for line_id in line_ids {
+        if !line_exists(pool, line_id).await? {
+            missing.push(line_id);
+        }
+    }
+    Ok(missing)
+}
+
+/// Creates or replaces the manual annotation for every record in one transaction.
+pub async fn import_metadata(
+    pool: &SqlitePool,
+    records: &[MetadataRecord],
+) -> Result<usize, sqlx::Error> {
+    let mut transaction: Transaction<'_, Sqlite> = pool.begin().await?;
+
+    for record in records {
+        sqlx::query(UPSERT_METADATA_QUERY)
+            .bind(record.line_id)
+            .bind(MANUAL_SOURCE)
+            .bind(None::<String>)
+            .bind(record.sentiment)
+            .bind(clean_label(record.tone.as_deref()))
+            .bind(clean_label(record.primary_emotion.as_deref()))
+            .execute(&mut *transaction)
+            .await?;
+    }
+
+    transaction.commit().await?;
+    Ok(records.len())
+}
+
+/// Creates or replaces the row a tagger owns for a line.
+pub async fn upsert_tagged(
+    conn: &mut SqliteConnection,
+    line_id: i64,
+    source: &str,
+    model_version: &str,
+    sentiment: Sentiment,
+    primary_emotion: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(UPSERT_METADATA_QUERY)
+        .bind(line_id)
+        .bind(source)
+        .bind(model_version)
+        .bind(sentiment)
+        .bind(None::<String>)
+        .bind(primary_emotion)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,392 @@
+use crate::analysis::LineScope;
+use crate::models::{SeasonMetrics, SpeakerMetrics, TextMetrics};
+use crate::text::{normalize, tokenize};
+use sqlx::{FromRow, SqlitePool};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// The type-token ratio at which MTLD closes a factor (McCarthy & Jarvis, 2010).
+const MTLD_THRESHOLD: f64 = 0.72;
+
+/// Distinct words divided by total words.
+pub fn type_token_ratio(words: &[String]) -> f64 {
+    if words.is_empty() {
+        return 0.0;
+    }
+    let types: HashSet<&String> = words.iter().collect();
+    types.len() as f64 / words.len() as f64
+}
+
+/// Number of words used exactly once.
+pub fn hapax_legomena(words: &[String]) -> usize {
+    let mut counts: HashMap<&String, usize> = HashMap::new();
+    for word in words {
+        *counts.entry(word).or_default() += 1;
+    }
+    counts.values().filter(|count| **count == 1).count()
+}
+
+fn mtld_pass<'a>(words: impl Iterator<Item = &'a String>) -> f64 {
+    let mut factors = 0.0;
+    let mut types = HashSet::new();
+    let mut segment_length = 0;
+    let mut total = 0;
+
+    for word in words {
+        total += 1;
+        segment_length += 1;
+        types.insert(word);
+        if types.len() as f64 / segment_length as f64 <= MTLD_THRESHOLD {
+            factors += 1.0;
+            types.clear();
+            segment_length = 0;
+        }
+    }
+
+    // A trailing partial segment counts as the fraction of a factor it got
+    // through on the way down to the threshold.
+    if segment_length > 0 {
+        let ratio = types.len() as f64 / segment_length as f64;
+        factors += (1.0 - ratio) / (1.0 - MTLD_THRESHOLD);
+    }
+
+    if factors > 0.0 {
+        total as f64 / factors
+    } else {
+        total as f64
+    }
+}
+
+/// Measure of textual lexical diversity: the mean length of word runs that
+/// keep the type-token ratio above 0.72, averaged over a forward and a
+/// backward pass. Unlike the plain ratio, it does not fall as texts grow.
+pub fn mtld(words: &[String]) -> f64 {
+    if words.is_empty() {
+        return 0.0;
+    }
+    (mtld_pass(words.iter()) + mtld_pass(words.iter().rev())) / 2.0
+}
+
+/// Estimates syllables by counting vowel groups, dropping a silent final
+/// "e" but keeping a final "-le" as in "table".
+pub fn count_syllables(word: &str) -> usize {
+    let letters: Vec<char> = word
+        .chars()
+        .filter(|c| c.is_alphabetic())
+        .flat_map(char::to_lowercase)
+        .collect();
+    if letters.is_empty() {
+        return 0;
+    }
+
+    let is_vowel = |c: char| matches!(c, 'a' | 'e' | 'i' | 'o' | 'u' | 'y');
+    let mut syllables = 0;
+    let mut previous_vowel = false;
+    for &c in &letters {
+        let vowel = is_vowel(c);
+        if vowel && !previous_vowel {
+            syllables += 1;
+        }
+        previous_vowel = vowel;
+    }
+
+    let n = letters.len();
+    let consonant_le = n > 2 && letters[n - 2] == 'l' && !is_vowel(letters[n - 3]);
+    let silent_e = n > 2 && letters[n - 1] == 'e' && !is_vowel(letters[n - 2]) && !consonant_le;
+    if silent_e && syllables > 1 {
+        syllables -= 1;
+    }
+
+    syllables.max(1)
+}
+
+/// Counts sentences as runs of terminal punctuation, treating a line that
+/// trails off without one as a sentence too.
+fn count_sentences(line: &str) -> usize {
+    let mut sentences = 0;
+    let mut in_terminator = false;
+    let mut words_since_terminator = false;
+
+    for c in line.chars() {
+        let terminator = matches!(c, '.' | '!' | '?');
+        if terminator && !in_terminator && words_since_terminator {
+            sentences += 1;
+            words_since_terminator = false;
+        }
+        if c.is_alphanumeric() {
+            words_since_terminator = true;
+        }
+        in_terminator = terminator;
+    }
+
+    sentences + usize::from(words_since_terminator)
+}
+
+/// Flesch reading ease: higher scores are easier to read, with plain
+/// conversational English around 60-80.
+pub fn flesch_reading_ease(sentences: usize, words: usize, syllables: usize) -> f64 {
+    if sentences == 0 || words == 0 {
+        return 0.0;
+    }
+    206.835 - 1.015 * (words as f64 / sentences as f64) - 84.6 * (syllables as f64 / words as f64)
+}
+
+/// Computes every metric over the lines in order. MTLD reads the lines as one
+/// continuous text.
+pub fn text_metrics<'a>(lines: impl IntoIterator<Item = &'a str>) -> TextMetrics {
+    let mut words = Vec::new();
+    let mut line_count = 0;
+    let mut sentences = 0;
+    let mut syllables = 0;
+    let mut questions = 0;
+    let mut exclamations = 0;
+
+    for line in lines {
+        line_count += 1;
+        sentences += count_sentences(line);
+        questions += usize::from(line.contains('?'));
+        exclamations += usize::from(line.contains('!'));
+        for token in tokenize(line) {
+            syllables += count_syllables(token.text);
+            words.push(normalize(token.text));
+        }
+    }
+
+    let per_line = |count: usize| {
+        if line_count == 0 {
+            0.0
+        } else {
+            count as f64 / line_count as f64
+        }
+    };
+
+    TextMetrics {
+        line_count,
+        word_count: words.len(),
+        type_token_ratio: type_token_ratio(&words),
+        mtld: mtld(&words),
+        hapax_legomena: hapax_legomena(&words),
+        mean_words_per_line: per_line(words.len()),
+        flesch_reading_ease: flesch_reading_ease(sentences, words.len(), syllables),
+        question_ratio: per_line(questions),
+        exclamation_ratio: per_line(exclamations),
+    }
+}
+
+#[derive(FromRow)]
+struct DialogueLine {
+    speaker_id: i64,
+    speaker_name: String,
+    series_id: i64,
+    season_id: i64,
+    season: i32,
+    content: String,
+}
+
+async fn dialogue_lines(
+    pool: &SqlitePool,
+    series: Option<i64>,
+    season: Option<i64>,
+    speaker: Option<i64>,
+) -> Result<Vec<DialogueLine>, sqlx::Error> {
+    let scope = LineScope {
+        series,
+        season,
+        episode: None,
+        speaker,
+    };
+    scope
+        .bind(sqlx::query_as(&format!(
+            r#"
+            SELECT
+                sp.id AS speaker_id,
+                sp.name AS speaker_name,
+                sn.series_id,
+                sn.id AS season_id,
+                sn.number AS season,
+                l.content
+            FROM lines l
+            JOIN seasons sn ON l.season_id = sn.id
+            JOIN episodes e ON l.episode_id = e.id
+            JOIN speakers sp ON l.speaker_id = sp.id
+            WHERE {filter}
+            ORDER BY sn.series_id, sn.number, e.sort_key, l.line_number
+            "#,
+            filter = LineScope::FILTER,
+        )))
+        .fetch_all(pool)
+        .await
+}
+
+fn season_breakdown(lines: &[&DialogueLine]) -> Vec<SeasonMetrics> {
+    let mut seasons: BTreeMap<(i64, i32, i64), Vec<&str>> = BTreeMap::new();
+    for line in lines {
+        seasons
+            .entry((line.series_id, line.season, line.season_id))
+            .or_default()
+            .push(&line.content);
+    }
+
+    seasons
+        .into_iter()
+        .map(|((series_id, season, season_id), contents)| SeasonMetrics {
+            series_id,
+            season_id,
+            season,
+            metrics: text_metrics(contents),
+        })
+        .collect()
+}
+
+/// Metrics for each speaker with at least `min_lines` lines in scope, overall
+/// and per season, most talkative first.
+pub async fn speaker_metrics(
+    pool: &SqlitePool,
+    series: Option<i64>,
+    season: Option<i64>,
+    speaker: Option<i64>,
+    min_lines: usize,
+) -> Result<Vec<SpeakerMetrics>, sqlx::Error> {
+    let lines = dialogue_lines(pool, series, season, speaker).await?;
+
+    let mut by_speaker: HashMap<i64, Vec<&DialogueLine>> = HashMap::new();
+    for line in &lines {
+        by_speaker.entry(line.speaker_id).or_default().push(line);
+    }
+
+    let mut metrics: Vec<SpeakerMetrics> = by_speaker
+        .into_values()
+        .filter(|lines| lines.len() >= min_lines.max(1))
+        .map(|lines| SpeakerMetrics {
+            speaker_id: lines[0].speaker_id,
+            speaker_name: lines[0].speaker_name.clone(),
+            metrics: text_metrics(lines.iter().map(|line| line.content.as_str())),
+            seasons: season_breakdown(&lines),
+        })
+        .collect();
+
+    metrics.sort_by(|a, b| {
+        b.metrics
+            .line_count
+            .cmp(&a.metrics.line_count)
+            .then_with(|| a.speaker_name.cmp(&b.speaker_name))
+    });
+    Ok(metrics)
+}
+
+/// Metrics for all dialogue in a season, or `None` if it has none.
+pub async fn season_metrics(
+    pool: &SqlitePool,
+    season_id: i64,
+) -> Result<Option<SeasonMetrics>, sqlx::Error> {
+    let lines = dialogue_lines(pool, None, Some(season_id), None).await?;
+    let lines: Vec<&DialogueLine> = lines.iter().collect();
+    Ok(season_breakdown(&lines).pop())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(text: &str) -> Vec<String> {
+        tokenize(text)
+            .iter()
+            .map(|token| normalize(token.text))
+            .collect()
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-3,
+            "expected {}, got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn type_token_ratio_counts_distinct_words() {
+        assert_close(
+            type_token_ratio(&words("The cat sat on the mat")),
+            5.0 / 6.0,
+        );
+        assert_close(type_token_ratio(&words("Whoa, whoa, whoa.")), 1.0 / 3.0);
+        assert_close(type_token_ratio(&[]), 0.0);
+    }
+
+    #[test]
+    fn hapax_legomena_counts_words_used_once() {
+        assert_eq!(hapax_legomena(&words("The cat sat on the mat")), 4);
+        assert_eq!(hapax_legomena(&words("la la la")), 0);
+    }
+
+    #[test]
+    fn mtld_closes_factors_at_the_threshold() {
+        // Every second word drops the ratio to 0.5, so each factor is two
+        // words long in both directions.
+        assert_close(mtld(&words("a a a a")), 2.0);
+        assert_close(mtld(&words("a b a")), 3.0);
+    }
+
+    #[test]
+    fn mtld_counts_partial_factors() {
+        // Forward: "a b a" closes a factor and "c" adds nothing, giving 4.
+        // Backward: "c a b a" ends at a ratio of 0.75, a partial factor of
+        // 0.25 / 0.28, giving 4.48.
+        let expected = (4.0 + 4.0 / (0.25 / 0.28)) / 2.0;
+        assert_close(mtld(&words("a b a c")), expected);
+    }
+
+    #[test]
+    fn mtld_of_text_without_repeats_is_its_length() {
+        assert_close(mtld(&words("every word here differs")), 4.0);
+    }
+
+    #[test]
+    fn syllables_follow_vowel_groups() {
+        assert_eq!(count_syllables("cat"), 1);
+        assert_eq!(count_syllables("the"), 1);
+        assert_eq!(count_syllables("make"), 1);
+        assert_eq!(count_syllables("table"), 2);
+        assert_eq!(count_syllables("reading"), 2);
+        assert_eq!(count_syllables("Beautiful"), 3);
+        assert_eq!(count_syllables("mathematical"), 5);
+        assert_eq!(count_syllables("rhythm"), 1);
+    }
+
+    #[test]
+    fn sentences_end_at_terminal_punctuation() {
+        assert_eq!(count_sentences("Hello. How are you?"), 2);
+        assert_eq!(count_sentences("What?!"), 1);
+        assert_eq!(count_sentences("Wait... what"), 2);
+        assert_eq!(count_sentences("..."), 0);
+    }
+
+    #[test]
+    fn flesch_reading_ease_matches_the_formula() {
+        // 6 words, 1 sentence, 6 syllables.
+        let metrics = text_metrics(["The cat sat on the mat."]);
+        assert_close(metrics.flesch_reading_ease, 206.835 - 1.015 * 6.0 - 84.6);
+        assert_close(flesch_reading_ease(0, 0, 0), 0.0);
+    }
+
+    #[test]
+    fn text_metrics_summarise_lines() {
+        let metrics = text_metrics(["Mathematical!", "Are you okay?", "I am fine."]);
+
+        assert_eq!(metrics.line_count, 3);
+        assert_eq!(metrics.word_count, 7);
+        assert_eq!(metrics.hapax_legomena, 7);
+        assert_close(metrics.type_token_ratio, 1.0);
+        assert_close(metrics.mean_words_per_line, 7.0 / 3.0);
+        assert_close(metrics.question_ratio, 1.0 / 3.0);
+        assert_close(metrics.exclamation_ratio, 1.0 / 3.0);
+    }
+
+    #[test]
+    fn text_metrics_of_nothing_are_zero() {
+        let metrics = text_metrics([]);
+        assert_eq!(metrics.word_count, 0);
+        assert_close(metrics.mtld, 0.0);
+        assert_close(metrics.mean_words_per_line, 0.0);
+    }
+}
@@ -1,22 +1,85 @@
+use crate::auth::Scope;
 use serde::{Deserialize, Serialize};
 use sqlx::{FromRow, Type};
 
 #[derive(Deserialize)]
-pub struct UserQuery {
-    pub user_id: String,
+pub struct TokenRequest {
+    pub scope: Scope,
+    pub ttl_secs: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct InitDbQuery {
+    pub name: Option<String>,
+    pub scratch: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct ImportQuery {
+    pub name: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct WorkspaceRequest {
+    pub name: String,
+}
+
+#[derive(Clone, FromRow, Debug, Deserialize, Serialize)]
+pub struct Workspace {
+    pub id: String,
+    pub name: String,
+    pub created_at: String,
+}
+
+#[derive(Clone, FromRow, Debug, Deserialize, Serialize)]
+pub struct Share {
+    pub id: String,
+    pub created_at: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SessionInfo {
+    pub id: String,
+    pub scratch: bool,
+    pub size_bytes: u64,
+    pub idle_secs: u64,
+}
+
+#[derive(Deserialize)]
+pub struct SeriesQuery {
+    pub series: Option<i64>,
+}
+
+#[derive(Clone, FromRow, Debug, Deserialize, Serialize)]
+pub struct Series {
+    pub id: i64,
+    pub name: String,
 }
 
 #[derive(Clone, FromRow, Debug, Deserialize, Serialize)]
 pub struct Season {
     pub id: i64,
+    pub series_id: i64,
     pub number: i32,
 }
 
+#[derive(Debug, Deserialize, Serialize, Type, Clone, Copy, PartialEq, Eq)]
+#[sqlx(type_name = "TEXT")]
+pub enum EpisodeKind {
+    Regular,
+    Special,
+    Short,
+    Part,
+}
+
 #[derive(Clone, FromRow, Debug, Deserialize, Serialize)]
 pub struct Episode {
     pub id: i64,
     pub season_id: i64,
     pub number: i32,
+    pub code: String,
+    pub kind: EpisodeKind,
+    pub sort_key: i64,
     pub title: String,
 }
 
@@ -26,18 +89,181 @@ pub struct Speaker {
     pub name: String,
 }
 
+/// Where a speaker first or last appears, in broadcast order.
+#[derive(Clone, FromRow, Debug, Serialize)]
+pub struct Appearance {
+    pub series_id: i64,
+    pub series: String,
+    pub season_id: i64,
+    pub season: i32,
+    pub episode_id: i64,
+    pub episode: String,
+    pub title: String,
+}
+
+#[derive(Clone, FromRow, Debug, Serialize)]
+pub struct SeasonLineCount {
+    pub series_id: i64,
+    pub season_id: i64,
+    pub season: i32,
+    pub line_count: i64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SpeakerStats {
+    pub speaker_id: i64,
+    pub speaker_name: String,
+    pub line_count: i64,
+    pub word_count: i64,
+    pub episode_count: i64,
+    pub avg_line_length: f64,
+    pub avg_words_per_line: f64,
+    pub first_appearance: Option<Appearance>,
+    pub last_appearance: Option<Appearance>,
+    pub seasons: Vec<SeasonLineCount>,
+}
+
+#[derive(Deserialize)]
+pub struct StatsQuery {
+    pub series: Option<i64>,
+    pub season: Option<i64>,
+    pub top: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct EpisodeStatsQuery {
+    pub top: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct SpeakerMatrixQuery {
+    pub series: Option<i64>,
+    pub season: Option<i64>,
+    pub speakers: Option<i64>,
+}
+
+/// A speaker's part of the dialogue. `share` is their fraction of all
+/// dialogue words.
+#[derive(Clone, FromRow, Debug, Serialize)]
+pub struct SpeakerShare {
+    pub speaker_id: i64,
+    pub speaker_name: String,
+    pub line_count: i64,
+    pub word_count: i64,
+    #[sqlx(skip)]
+    pub share: f64,
+}
+
+/// Dialogue totals for a set of lines. Direction lines are those without a
+/// speaker; `direction_ratio` is direction lines per dialogue line.
+#[derive(Clone, Debug, Serialize)]
+pub struct DialogueStats {
+    pub line_count: i64,
+    pub dialogue_line_count: i64,
+    pub direction_line_count: i64,
+    pub direction_ratio: f64,
+    pub word_count: i64,
+    pub dialogue_word_count: i64,
+    pub speaker_count: i64,
+    pub vocabulary_size: i64,
+    pub top_speakers: Vec<SpeakerShare>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct EpisodeStats {
+    pub episode: Episode,
+    #[serde(flatten)]
+    pub stats: DialogueStats,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CorpusStats {
+    pub series_count: i64,
+    pub season_count: i64,
+    pub episode_count: i64,
+    #[serde(flatten)]
+    pub stats: DialogueStats,
+}
+
+#[derive(Clone, FromRow, Debug, Serialize)]
+pub struct MatrixEpisode {
+    pub id: i64,
+    pub series_id: i64,
+    pub season_id: i64,
+    pub season: i32,
+    pub code: String,
+    pub title: String,
+}
+
+/// Lines per episode and speaker, for heatmaps. `line_counts[i][j]` is the
+/// number of lines `speakers[j]` has in `episodes[i]`.
+#[derive(Clone, Debug, Serialize)]
+pub struct SpeakerMatrix {
+    pub episodes: Vec<MatrixEpisode>,
+    pub speakers: Vec<Speaker>,
+    pub line_counts: Vec<Vec<i64>>,
+}
+
+#[derive(Deserialize)]
+pub struct MetricsQuery {
+    pub series: Option<i64>,
+    pub season: Option<i64>,
+    pub min_lines: Option<usize>,
+}
+
+/// Lexical richness and readability of a body of dialogue.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TextMetrics {
+    pub line_count: usize,
+    pub word_count: usize,
+    pub type_token_ratio: f64,
+    pub mtld: f64,
+    pub hapax_legomena: usize,
+    pub mean_words_per_line: f64,
+    pub flesch_reading_ease: f64,
+    pub question_ratio: f64,
+    pub exclamation_ratio: f64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SeasonMetrics {
+    pub series_id: i64,
+    pub season_id: i64,
+    pub season: i32,
+    #[serde(flatten)]
+    pub metrics: TextMetrics,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SpeakerMetrics {
+    pub speaker_id: i64,
+    pub speaker_name: String,
+    #[serde(flatten)]
+    pub metrics: TextMetrics,
+    pub seasons: Vec<SeasonMetrics>,
+}
+
 #[derive(Clone, FromRow, Debug, Deserialize, Serialize)]
 pub struct Line {
     pub id: i64,
+    pub series_id: i64,
     pub season_id: i64,
     pub episode_id: i64,
     pub speaker_id: Option<i64>,
     pub speaker_name: Option<String>,
     pub line_number: i32,
     pub content: String,
+    #[sqlx(default)]
+    pub sentiment: Option<Sentiment>,
+    #[sqlx(default)]
+    pub tone: Option<String>,
+    #[sqlx(default)]
+    pub primary_emotion: Option<String>,
+    #[sqlx(default)]
+    pub metadata_source: Option<String>,
 }
 
-#[derive(Debug, Deserialize, Serialize, Type, Clone, Copy)]
+#[derive(Debug, Deserialize, Serialize, Type, Clone, Copy, PartialEq, Eq)]
 #[sqlx(type_name = "TEXT")]
 pub enum Sentiment {
     Positive,
@@ -45,27 +271,449 @@ pub enum Sentiment {
     Negative,
 }
 
+impl Sentiment {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Sentiment::Positive => "Positive",
+            Sentiment::Neutral => "Neutral",
+            Sentiment::Negative => "Negative",
+        }
+    }
+}
+
 #[derive(Clone, FromRow, Debug, Deserialize, Serialize)]
 pub struct Metadata {
     pub id: i64,
     pub line_id: i64,
-    pub sentiment: Sentiment,
-    pub tone: String,
-    pub primary_emotion: String,
+    pub source: String,
+    pub model_version: Option<String>,
+    pub sentiment: Option<Sentiment>,
+    pub tone: Option<String>,
+    pub primary_emotion: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MetadataRequest {
+    pub sentiment: Option<Sentiment>,
+    pub tone: Option<String>,
+    pub primary_emotion: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct AnalyzeQuery {
+    pub retag: Option<bool>,
+}
+
+/// One row of a bulk metadata upload, from either a JSON array or a CSV file
+/// with the same column names.
+#[derive(Debug, Deserialize)]
+pub struct MetadataRecord {
+    pub line_id: i64,
+    pub sentiment: Option<Sentiment>,
+    pub tone: Option<String>,
+    pub primary_emotion: Option<String>,
+}
+
+#[derive(Clone, FromRow, Debug, Deserialize, Serialize)]
+pub struct Tag {
+    pub id: i64,
+    pub name: String,
+    #[sqlx(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line_count: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct TagRequest {
+    pub name: String,
+}
+
+#[derive(Clone, FromRow, Debug, Deserialize, Serialize)]
+pub struct Note {
+    pub id: i64,
+    pub line_id: i64,
+    pub body: String,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+#[derive(Deserialize)]
+pub struct NoteRequest {
+    pub body: String,
+}
+
+#[derive(Clone, FromRow, Debug, Deserialize, Serialize)]
+pub struct Collection {
+    pub id: i64,
+    pub name: String,
+    pub description: Option<String>,
+    pub created_at: String,
+    pub line_count: i64,
+}
+
+#[derive(Deserialize)]
+pub struct CollectionRequest {
+    pub name: String,
+    pub description: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct CollectionUpdate {
+    pub name: Option<String>,
+    pub description: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct CollectionLinesRequest {
+    pub line_ids: Vec<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct CollectionLineRequest {
+    pub line_id: i64,
+    pub position: Option<i64>,
+}
+
+/// A collection entry flattened for spreadsheets.
+#[derive(Clone, FromRow, Debug, Serialize)]
+pub struct CollectionExportRow {
+    pub position: i64,
+    pub line_id: i64,
+    pub series: String,
+    pub season: i32,
+    pub episode: String,
+    pub episode_title: String,
+    pub speaker: Option<String>,
+    pub line_number: i32,
+    pub content: String,
+}
+
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    pub format: Option<String>,
 }
 
 #[derive(Deserialize)]
 pub struct SearchPhrasesQuery {
     pub phrase: Option<String>,
+    pub series: Option<i64>,
     pub season: Option<i64>,
     pub episode: Option<i64>,
     pub speaker: Option<i64>,
     pub context: Option<i32>,
+    pub sentiment: Option<Sentiment>,
+    pub tone: Option<String>,
+    pub emotion: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum NgramMode {
+    #[default]
+    Frequency,
+    Distinctive,
+}
+
+#[derive(Deserialize)]
+pub struct NgramQuery {
+    pub n: Option<usize>,
+    pub limit: Option<usize>,
+    pub min_count: Option<i64>,
+    pub remove_stopwords: Option<bool>,
+    pub mode: Option<NgramMode>,
+    pub series: Option<i64>,
+    pub season: Option<i64>,
+    pub episode: Option<i64>,
+    pub speaker: Option<i64>,
+}
+
+#[derive(Clone, FromRow, Debug, Serialize)]
+pub struct NgramCount {
+    pub ngram: String,
+    pub count: i64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DistinctiveTerm {
+    pub ngram: String,
+    pub count: i64,
+    pub corpus_count: i64,
+    pub log_likelihood: f64,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum KwicSort {
+    #[default]
+    Position,
+    Left,
+    Right,
+}
+
+#[derive(Deserialize)]
+pub struct KwicQuery {
+    pub q: String,
+    pub window: Option<usize>,
+    pub sort: Option<KwicSort>,
+    pub limit: Option<usize>,
+    pub format: Option<String>,
+    pub series: Option<i64>,
+    pub season: Option<i64>,
+    pub episode: Option<i64>,
+    pub speaker: Option<i64>,
+}
+
+/// One occurrence of a keyword with the words around it. Context may run into
+/// neighbouring lines of the same episode; line breaks are shown as ` / `.
+#[derive(Clone, Debug, Serialize)]
+pub struct KwicLine {
+    pub line_id: i64,
+    pub series_id: i64,
+    pub season_id: i64,
+    pub episode_id: i64,
+    pub line_number: i32,
+    pub speaker_name: Option<String>,
+    pub left: String,
+    pub keyword: String,
+    pub right: String,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum AssociationMeasure {
+    Pmi,
+    #[default]
+    TScore,
+    Dice,
+}
+
+#[derive(Deserialize)]
+pub struct CollocationQuery {
+    pub window: Option<usize>,
+    pub measure: Option<AssociationMeasure>,
+    pub min_count: Option<i64>,
+    pub limit: Option<usize>,
+    pub remove_stopwords: Option<bool>,
+    pub series: Option<i64>,
+    pub season: Option<i64>,
+    pub episode: Option<i64>,
+    pub speaker: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct CooccurrenceQuery {
+    pub term: String,
+    pub window: Option<usize>,
+    pub measure: Option<AssociationMeasure>,
+    pub min_count: Option<i64>,
+    pub limit: Option<usize>,
+    pub remove_stopwords: Option<bool>,
+    pub series: Option<i64>,
+    pub season: Option<i64>,
+    pub episode: Option<i64>,
+    pub speaker: Option<i64>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct AssociationScores {
+    pub pmi: f64,
+    pub t_score: f64,
+    pub dice: f64,
+}
+
+/// A word pair seen within the window, `first` before `second`.
+#[derive(Clone, Debug, Serialize)]
+pub struct Collocation {
+    pub first: String,
+    pub second: String,
+    pub count: i64,
+    #[serde(flatten)]
+    pub scores: AssociationScores,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Cooccurrence {
+    pub term: String,
+    pub count: i64,
+    pub frequency: i64,
+    #[serde(flatten)]
+    pub scores: AssociationScores,
+}
+
+#[derive(Deserialize)]
+pub struct SimilarLinesQuery {
+    pub limit: Option<usize>,
+    pub same_speaker: Option<bool>,
+    pub exclude_episode: Option<bool>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SimilarLine {
+    #[serde(flatten)]
+    pub line: Line,
+    pub score: f64,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RecurringSort {
+    #[default]
+    Frequency,
+    Spread,
+}
+
+#[derive(Deserialize)]
+pub struct RecurringQuery {
+    pub limit: Option<i64>,
+    pub min_occurrences: Option<i64>,
+    pub min_seasons: Option<i64>,
+    pub sort: Option<RecurringSort>,
+}
+
+#[derive(Clone, FromRow, Debug, Serialize)]
+pub struct RecurringCluster {
+    pub id: i64,
+    pub text: String,
+    pub occurrence_count: i64,
+    pub episode_count: i64,
+    pub season_count: i64,
+    #[sqlx(skip)]
+    pub occurrences: Vec<Line>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Type, Clone, Copy, PartialEq, Eq, Default)]
+#[sqlx(type_name = "TEXT")]
+pub enum QuizDifficulty {
+    Easy,
+    #[default]
+    Medium,
+    Hard,
+}
+
+#[derive(Deserialize)]
+pub struct QuizSessionRequest {
+    pub difficulty: Option<QuizDifficulty>,
+}
+
+#[derive(Clone, FromRow, Debug, Serialize)]
+pub struct QuizSession {
+    pub id: String,
+    pub difficulty: QuizDifficulty,
+    pub score: i64,
+    pub answered: i64,
+    pub correct: i64,
+    pub created_at: String,
+}
+
+#[derive(Clone, FromRow, Debug, Serialize)]
+pub struct QuizChoice {
+    pub speaker_id: i64,
+    pub speaker_name: String,
+}
+
+/// A question as shown to the player; the speaker is only revealed once the
+/// question is answered.
+#[derive(Clone, Debug, Serialize)]
+pub struct QuizQuestion {
+    pub id: i64,
+    pub session_id: String,
+    pub difficulty: QuizDifficulty,
+    pub content: String,
+    pub choices: Vec<QuizChoice>,
+}
+
+#[derive(Deserialize)]
+pub struct QuizAnswerRequest {
+    pub speaker_id: i64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct QuizAnswer {
+    pub question_id: i64,
+    pub correct: bool,
+    pub points: i64,
+    pub speaker: QuizChoice,
+    pub line_id: i64,
+    pub session: QuizSession,
+}
+
+#[derive(Deserialize)]
+pub struct CardQuery {
+    pub background: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct GenerateQuery {
+    /// A speaker id or name.
+    pub speaker: String,
+    pub order: Option<usize>,
+    pub seed: Option<u64>,
+    pub n: Option<usize>,
+    pub min_words: Option<usize>,
+    pub max_words: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GeneratedLines {
+    pub speaker: Speaker,
+    pub order: usize,
+    pub seed: u64,
+    pub lines: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct InteractionQuery {
+    pub series: Option<i64>,
+    pub season: Option<i64>,
+    pub episode: Option<i64>,
+    pub min_weight: Option<i64>,
+    pub format: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct InteractionNode {
+    pub id: i64,
+    pub name: String,
+    pub line_count: i64,
+}
+
+/// An undirected speaker pair. `turns` counts lines answered by the other
+/// speaker and `scenes` the scenes both spoke in; `weight` is their sum.
+#[derive(Clone, Debug, Serialize)]
+pub struct InteractionEdge {
+    pub source: i64,
+    pub target: i64,
+    pub turns: i64,
+    pub scenes: i64,
+    pub weight: i64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct InteractionGraph {
+    pub nodes: Vec<InteractionNode>,
+    pub edges: Vec<InteractionEdge>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RandomMode {
+    #[default]
+    Random,
+    QuoteOfTheDay,
 }
 
 #[derive(Deserialize)]
 pub struct RandomLineQuery {
+    pub series: Option<i64>,
     pub season: Option<i64>,
     pub episode: Option<i64>,
     pub speaker: Option<i64>,
+    pub seed: Option<u64>,
+    pub mode: Option<RandomMode>,
+    pub date: Option<String>,
+    pub n: Option<usize>,
+    pub min_words: Option<i64>,
+    pub max_words: Option<i64>,
+    pub require_speaker: Option<bool>,
+    pub exclude_asides: Option<bool>,
 }
@@ -0,0 +1,391 @@
+use crate::analysis::stopwords;
+use crate::models::{QuizAnswer, QuizChoice, QuizDifficulty, QuizQuestion, QuizSession};
+use crate::random::{self, LineFilter};
+use rand::seq::SliceRandom;
+use sqlx::{FromRow, SqlitePool};
+use std::collections::HashSet;
+use uuid::Uuid;
+
+const CHOICES: usize = 4;
+/// Lines drawn per question when looking for one of the right difficulty.
+const CANDIDATE_LINES: usize = 25;
+const MIN_QUESTION_WORDS: i64 = 3;
+const EASY_DISTINCTIVENESS: f64 = 0.5;
+const HARD_DISTINCTIVENESS: f64 = 0.2;
+
+const SESSION_COLUMNS: &str = "id, difficulty, score, answered, correct, created_at";
+
+impl QuizDifficulty {
+    /// Points awarded for a correct answer.
+    pub fn points(self) -> i64 {
+        match self {
+            QuizDifficulty::Easy => 1,
+            QuizDifficulty::Medium => 2,
+            QuizDifficulty::Hard => 3,
+        }
+    }
+
+    /// The difficulty of a line given how distinctive it is of its speaker.
+    fn for_distinctiveness(distinctiveness: f64) -> QuizDifficulty {
+        if distinctiveness >= EASY_DISTINCTIVENESS {
+            QuizDifficulty::Easy
+        } else if distinctiveness >= HARD_DISTINCTIVENESS {
+            QuizDifficulty::Medium
+        } else {
+            QuizDifficulty::Hard
+        }
+    }
+
+    /// How far a line's distinctiveness is from this difficulty's band.
+    fn distance(self, distinctiveness: f64) -> f64 {
+        let (low, high) = match self {
+            QuizDifficulty::Easy => (EASY_DISTINCTIVENESS, f64::INFINITY),
+            QuizDifficulty::Medium => (HARD_DISTINCTIVENESS, EASY_DISTINCTIVENESS),
+            QuizDifficulty::Hard => (f64::NEG_INFINITY, HARD_DISTINCTIVENESS),
+        };
+        (low - distinctiveness).max(distinctiveness - high).max(0.0)
+    }
+}
+
+pub enum QuestionOutcome {
+    SessionNotFound,
+    /// The workspace has no usable line or too few speakers.
+    NoQuestion,
+    Created(QuizQuestion),
+}
+
+pub enum AnswerOutcome {
+    NotFound,
+    AlreadyAnswered,
+    InvalidChoice,
+    Answered(QuizAnswer),
+}
+
+#[derive(FromRow)]
+struct CandidateLine {
+    id: i64,
+    episode_id: i64,
+    speaker_id: i64,
+    content: String,
+}
+
+#[derive(FromRow)]
+struct StoredQuestion {
+    session_id: String,
+    line_id: i64,
+    speaker_id: i64,
+    choices: String,
+    difficulty: QuizDifficulty,
+    answer_speaker_id: Option<i64>,
+}
+
+pub async fn create_session(
+    pool: &SqlitePool,
+    difficulty: QuizDifficulty,
+) -> Result<QuizSession, sqlx::Error> {
+    sqlx::query_as(&format!(
+        "INSERT INTO quiz_sessions (id, difficulty) VALUES (?, ?) RETURNING {}",
+        SESSION_COLUMNS
+    ))
+    .bind(Uuid::new_v4().to_string())
+    .bind(difficulty)
+    .fetch_one(pool)
+    .await
+}
+
+pub async fn fetch_session(
+    pool: &SqlitePool,
+    session_id: &str,
+) -> Result<Option<QuizSession>, sqlx::Error> {
+    sqlx::query_as(&format!(
+        "SELECT {} FROM quiz_sessions WHERE id = ?",
+        SESSION_COLUMNS
+    ))
+    .bind(session_id)
+    .fetch_optional(pool)
+    .await
+}
+
+/// How strongly a line's words point to its speaker, from 0 to 1.
+///
+/// For each word, this is the share of its uses elsewhere in the corpus that
+/// belong to the same speaker; the line's own uses are left out so that rare
+/// words do not look distinctive just because they appear once. Stopwords
+/// are skipped unless the line has nothing else.
+async fn distinctiveness(pool: &SqlitePool, line: &CandidateLine) -> Result<f64, sqlx::Error> {
+    let terms: Vec<String> =
+        sqlx::query_scalar("SELECT term FROM tokens WHERE line_id = ? ORDER BY position")
+            .bind(line.id)
+            .fetch_all(pool)
+            .await?;
+    let stopwords = stopwords();
+    let mut query_terms: HashSet<&String> = terms
+        .iter()
+        .filter(|term| !stopwords.contains(term.as_str()))
+        .collect();
+    if query_terms.is_empty() {
+        query_terms = terms.iter().collect();
+    }
+
+    let counts: Vec<(i64, i64)> = sqlx::query_as(
+        r#"
+        SELECT
+            COALESCE(SUM(l.speaker_id = ?), 0),
+            COUNT(*)
+        FROM tokens t
+        JOIN lines l ON l.id = t.line_id
+        WHERE t.term IN (SELECT value FROM json_each(?)) AND t.line_id <> ?
+        GROUP BY t.term
+        "#,
+    )
+    .bind(line.speaker_id)
+    .bind(serde_json::to_string(&query_terms).unwrap_or_default())
+    .bind(line.id)
+    .fetch_all(pool)
+    .await?;
+
+    if counts.is_empty() {
+        return Ok(0.0);
+    }
+    let total: f64 = counts
+        .iter()
+        .map(|(speaker, all)| *speaker as f64 / *all as f64)
+        .sum();
+    Ok(total / counts.len() as f64)
+}
+
+/// Picks up to `CHOICES - 1` wrong answers: other speakers in the same
+/// episode first, then the speakers whose line counts are closest to the
+/// real speaker's.
+async fn distractors(
+    pool: &SqlitePool,
+    line: &CandidateLine,
+    rng: &mut rand::rngs::StdRng,
+) -> Result<Vec<i64>, sqlx::Error> {
+    let mut same_episode: Vec<i64> = sqlx::query_scalar(
+        "SELECT DISTINCT speaker_id FROM lines WHERE episode_id = ? AND speaker_id IS NOT NULL AND speaker_id <> ?",
+    )
+    .bind(line.episode_id)
+    .bind(line.speaker_id)
+    .fetch_all(pool)
+    .await?;
+    same_episode.shuffle(rng);
+    same_episode.truncate(CHOICES - 1);
+
+    if same_episode.len() < CHOICES - 1 {
+        let similar: Vec<i64> = sqlx::query_scalar(
+            r#"
+            WITH counts AS (
+                SELECT speaker_id, COUNT(*) AS line_count
+                FROM lines
+                WHERE speaker_id IS NOT NULL
+                GROUP BY speaker_id
+            )
+            SELECT c.speaker_id
+            FROM counts c
+            WHERE c.speaker_id <> ?
+            ORDER BY ABS(c.line_count - (SELECT line_count FROM counts WHERE speaker_id = ?)), c.speaker_id
+            LIMIT ?
+            "#,
+        )
+        .bind(line.speaker_id)
+        .bind(line.speaker_id)
+        .bind(CHOICES as i64 * 2)
+        .fetch_all(pool)
+        .await?;
+
+        for speaker_id in similar {
+            if same_episode.len() == CHOICES - 1 {
+                break;
+            }
+            if !same_episode.contains(&speaker_id) {
+                same_episode.push(speaker_id);
+            }
+        }
+    }
+
+    Ok(same_episode)
+}
+
+/// Asks a new question in a session. Candidate lines are drawn at random and
+/// the first whose distinctiveness suits the session's difficulty is used,
+/// or else the closest one. Lines already asked in the session are skipped.
+pub async fn next_question(
+    pool: &SqlitePool,
+    session_id: &str,
+) -> Result<QuestionOutcome, sqlx::Error> {
+    let Some(session) = fetch_session(pool, session_id).await? else {
+        return Ok(QuestionOutcome::SessionNotFound);
+    };
+
+    let asked: HashSet<i64> =
+        sqlx::query_scalar("SELECT line_id FROM quiz_questions WHERE session_id = ?")
+            .bind(session_id)
+            .fetch_all(pool)
+            .await?
+            .into_iter()
+            .collect();
+
+    let mut rng = random::rng(None);
+    let filter = LineFilter {
+        min_words: Some(MIN_QUESTION_WORDS),
+        require_speaker: true,
+        exclude_asides: true,
+        ..LineFilter::default()
+    };
+    let ids = random::sample_line_ids(pool, &filter, &mut rng, CANDIDATE_LINES).await?;
+
+    let mut best: Option<(f64, f64, CandidateLine)> = None;
+    for id in ids.into_iter().filter(|id| !asked.contains(id)) {
+        let line: CandidateLine = sqlx::query_as(
+            "SELECT id, episode_id, speaker_id, content FROM lines WHERE id = ? AND speaker_id IS NOT NULL",
+        )
+        .bind(id)
+        .fetch_one(pool)
+        .await?;
+        let score = distinctiveness(pool, &line).await?;
+        let distance = session.difficulty.distance(score);
+
+        if best
+            .as_ref()
+            .is_none_or(|(closest, _, _)| distance < *closest)
+        {
+            best = Some((distance, score, line));
+        }
+        if distance == 0.0 {
+            break;
+        }
+    }
+    let Some((_, score, line)) = best else {
+        return Ok(QuestionOutcome::NoQuestion);
+    };
+
+    let mut choices = distractors(pool, &line, &mut rng).await?;
+    if choices.is_empty() {
+        return Ok(QuestionOutcome::NoQuestion);
+    }
+    choices.push(line.speaker_id);
+    choices.shuffle(&mut rng);
+
+    let difficulty = QuizDifficulty::for_distinctiveness(score);
+    let question_id: i64 = sqlx::query_scalar(
+        r#"
+        INSERT INTO quiz_questions (session_id, line_id, speaker_id, choices, difficulty, distinctiveness)
+        VALUES (?, ?, ?, ?, ?, ?)
+        RETURNING id
+        "#,
+    )
+    .bind(session_id)
+    .bind(line.id)
+    .bind(line.speaker_id)
+    .bind(serde_json::to_string(&choices).unwrap_or_default())
+    .bind(difficulty)
+    .bind(score)
+    .fetch_one(pool)
+    .await?;
+
+    Ok(QuestionOutcome::Created(QuizQuestion {
+        id: question_id,
+        session_id: session_id.to_string(),
+        difficulty,
+        content: line.content,
+        choices: speaker_choices(pool, &choices).await?,
+    }))
+}
+
+/// Looks up speaker names, keeping the order of `speaker_ids`.
+async fn speaker_choices(
+    pool: &SqlitePool,
+    speaker_ids: &[i64],
+) -> Result<Vec<QuizChoice>, sqlx::Error> {
+    let mut choices: Vec<QuizChoice> = sqlx::query_as(
+        "SELECT id AS speaker_id, name AS speaker_name FROM speakers WHERE id IN (SELECT value FROM json_each(?))",
+    )
+    .bind(serde_json::to_string(speaker_ids).unwrap_or_default())
+    .fetch_all(pool)
+    .await?;
+    choices.sort_by_key(|choice| speaker_ids.iter().position(|id| *id == choice.speaker_id));
+    Ok(choices)
+}
+
+/// Checks an answer and updates the session score. Each question can be
+/// answered once, with one of the speakers it offered.
+///
+/// The answer is recorded by a conditional update, so of two concurrent
+/// answers to the same question only one is scored.
+pub async fn answer_question(
+    pool: &SqlitePool,
+    question_id: i64,
+    speaker_id: i64,
+) -> Result<AnswerOutcome, sqlx::Error> {
+    let question: Option<StoredQuestion> = sqlx::query_as(
+        "SELECT session_id, line_id, speaker_id, choices, difficulty, answer_speaker_id FROM quiz_questions WHERE id = ?",
+    )
+    .bind(question_id)
+    .fetch_optional(pool)
+    .await?;
+    let Some(question) = question else {
+        return Ok(AnswerOutcome::NotFound);
+    };
+    if question.answer_speaker_id.is_some() {
+        return Ok(AnswerOutcome::AlreadyAnswered);
+    }
+    let choices: Vec<i64> = serde_json::from_str(&question.choices).unwrap_or_default();
+    if !choices.contains(&speaker_id) {
+        return Ok(AnswerOutcome::InvalidChoice);
+    }
+
+    let correct = speaker_id == question.speaker_id;
+    let points = if correct {
+        question.difficulty.points()
+    } else {
+        0
+    };
+
+    // Writing first makes the transaction take the write lock straight away
+    // instead of upgrading a read lock, which fails under contention.
+    let mut transaction = pool.begin().await?;
+    let recorded = sqlx::query(
+        "UPDATE quiz_questions SET answer_speaker_id = ?, is_correct = ?, answered_at = CURRENT_TIMESTAMP WHERE id = ? AND answer_speaker_id IS NULL",
+    )
+    .bind(speaker_id)
+    .bind(correct)
+    .bind(question_id)
+    .execute(&mut *transaction)
+    .await?;
+    if recorded.rows_affected() == 0 {
+        return Ok(AnswerOutcome::AlreadyAnswered);
+    }
+
+    let session: QuizSession = sqlx::query_as(&format!(
+        r#"
+        UPDATE quiz_sessions
+        SET score = score + ?, answered = answered + 1, correct = correct + ?
+        WHERE id = ?
+        RETURNING {}
+        "#,
+        SESSION_COLUMNS
+    ))
+    .bind(points)
+    .bind(i64::from(correct))
+    .bind(&question.session_id)
+    .fetch_one(&mut *transaction)
+    .await?;
+
+    let speaker: QuizChoice =
+        sqlx::query_as("SELECT id AS speaker_id, name AS speaker_name FROM speakers WHERE id = ?")
+            .bind(question.speaker_id)
+            .fetch_one(&mut *transaction)
+            .await?;
+
+    transaction.commit().await?;
+
+    Ok(AnswerOutcome::Answered(QuizAnswer {
+        question_id,
+        correct,
+        points,
+        speaker,
+        line_id: question.line_id,
+        session,
+    }))
+}
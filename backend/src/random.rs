@@ -0,0 +1,179 @@
+use crate::analysis::LineScope;
+use crate::stats::WORD_COUNT_SQL;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use sqlx::query::QueryScalar;
+use sqlx::sqlite::SqliteArguments;
+use sqlx::{Sqlite, SqlitePool};
+use std::collections::HashSet;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const MAX_SAMPLE: usize = 100;
+
+/// Which lines may be drawn. Unset fields do not filter.
+#[derive(Debug, Clone, Default)]
+pub struct LineFilter {
+    pub series: Option<i64>,
+    pub season: Option<i64>,
+    pub episode: Option<i64>,
+    pub speaker: Option<i64>,
+    pub min_words: Option<i64>,
+    pub max_words: Option<i64>,
+    /// Skips direction lines, which have no speaker.
+    pub require_speaker: bool,
+    /// Skips lines whose text opens with a bracketed or parenthesised aside,
+    /// such as `[sighs]`, even when a speaker says them.
+    pub exclude_asides: bool,
+}
+
+impl LineFilter {
+    fn scope(&self) -> LineScope {
+        LineScope {
+            series: self.series,
+            season: self.season,
+            episode: self.episode,
+            speaker: self.speaker,
+        }
+    }
+}
+
+type IdQuery<'q> = QueryScalar<'q, Sqlite, i64, SqliteArguments<'q>>;
+
+fn filter_sql() -> String {
+    format!(
+        r#"
+        {scope}
+        AND (? IS NULL OR {words} >= ?)
+        AND (? IS NULL OR {words} <= ?)
+        AND (NOT ? OR l.speaker_id IS NOT NULL)
+        AND (NOT ? OR (TRIM(l.content) NOT LIKE '[%' AND TRIM(l.content) NOT LIKE '(%'))
+        "#,
+        scope = LineScope::FILTER,
+        words = WORD_COUNT_SQL
+    )
+}
+
+fn bind_filter<'q>(query: IdQuery<'q>, filter: &LineFilter) -> IdQuery<'q> {
+    let mut query = filter.scope().bind(query);
+    for value in [filter.min_words, filter.max_words] {
+        query = query.bind(value).bind(value);
+    }
+    query
+        .bind(filter.require_speaker)
+        .bind(filter.exclude_asides)
+}
+
+/// A generator that replays the same draws for the same seed, or a fresh one
+/// without a seed. Seeded draws are stable for a given build and data set.
+pub fn rng(seed: Option<u64>) -> StdRng {
+    match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    }
+}
+
+/// Today's date in UTC as `YYYY-MM-DD`.
+pub fn today() -> String {
+    let days = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs() / 86_400) as i64;
+
+    // Civil-from-days, after Howard Hinnant's date algorithms.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Derives the quote-of-the-day seed from a date key with FNV-1a, which,
+/// unlike the standard hasher, is fixed across Rust releases.
+pub fn seed_for_date(date: &str) -> u64 {
+    date.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Draws up to `n` distinct matching line ids.
+///
+/// Each draw picks a random id between the smallest and largest line id and
+/// takes the first matching line at or after it, wrapping around to the
+/// start. This uses the primary key instead of sorting the whole table, at
+/// the cost of favouring lines that follow long runs of non-matching ids. If
+/// repeated draws keep landing on lines already taken, the remaining matches
+/// are read and shuffled so the sample is still filled.
+pub async fn sample_line_ids(
+    pool: &SqlitePool,
+    filter: &LineFilter,
+    rng: &mut StdRng,
+    n: usize,
+) -> Result<Vec<i64>, sqlx::Error> {
+    let n = n.clamp(1, MAX_SAMPLE);
+    let (min_id, max_id): (Option<i64>, Option<i64>) =
+        sqlx::query_as("SELECT MIN(id), MAX(id) FROM lines")
+            .fetch_one(pool)
+            .await?;
+    let (Some(min_id), Some(max_id)) = (min_id, max_id) else {
+        return Ok(Vec::new());
+    };
+
+    let filter_sql = filter_sql();
+    let at_or_after = format!(
+        "SELECT l.id FROM lines l JOIN seasons sn ON l.season_id = sn.id WHERE l.id >= ? AND {} ORDER BY l.id LIMIT 1",
+        filter_sql
+    );
+    let before = format!(
+        "SELECT l.id FROM lines l JOIN seasons sn ON l.season_id = sn.id WHERE l.id < ? AND {} ORDER BY l.id LIMIT 1",
+        filter_sql
+    );
+
+    let mut sample = Vec::with_capacity(n);
+    let mut taken = HashSet::new();
+    let mut misses = 0;
+    while sample.len() < n && misses < 2 * n + 8 {
+        let start = rng.gen_range(min_id..=max_id);
+        let mut found = bind_filter(sqlx::query_scalar(&at_or_after).bind(start), filter)
+            .fetch_optional(pool)
+            .await?;
+        if found.is_none() {
+            found = bind_filter(sqlx::query_scalar(&before).bind(start), filter)
+                .fetch_optional(pool)
+                .await?;
+        }
+
+        match found {
+            None => return Ok(sample),
+            Some(id) if taken.insert(id) => sample.push(id),
+            Some(_) => misses += 1,
+        }
+    }
+
+    if sample.len() < n {
+        let all = format!(
+            "SELECT l.id FROM lines l JOIN seasons sn ON l.season_id = sn.id WHERE {} ORDER BY l.id",
+            filter_sql
+        );
+        let mut remaining: Vec<i64> = bind_filter(sqlx::query_scalar(&all), filter)
+            .fetch_all(pool)
+            .await?
+            .into_iter()
+            .filter(|id| !taken.contains(id))
+            .collect();
+        remaining.shuffle(rng);
+        sample.extend(remaining.into_iter().take(n - sample.len()));
+    }
+
+    Ok(sample)
+}
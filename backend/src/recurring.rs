@@ -0,0 +1,263 @@
+use crate::models::{RecurringCluster, RecurringSort};
+use crate::text::{normalize, tokenize};
+use sqlx::{FromRow, SqlitePool};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+
+// MinHash signatures of 32 values split into 8 LSH bands of 4 rows. Two texts
+// with Jaccard similarity 0.8 share a band with probability ~0.99, while
+// texts at 0.4 rarely do.
+const SIGNATURE_SIZE: usize = 32;
+const BANDS: usize = 8;
+const ROWS: usize = SIGNATURE_SIZE / BANDS;
+const SHINGLE_CHARS: usize = 3;
+/// Estimated Jaccard similarity above which two texts are the same line.
+const SIMILARITY_THRESHOLD: f64 = 0.8;
+/// Buckets larger than this are compared against their first member only,
+/// so a common band cannot make the job quadratic.
+const MAX_BUCKET_PAIRS: usize = 64;
+
+#[derive(FromRow)]
+struct SpokenLine {
+    id: i64,
+    season_id: i64,
+    episode_id: i64,
+    content: String,
+}
+
+/// Reduces a line to its lowercase words, so punctuation, spacing and case do
+/// not separate repeats.
+fn normalized_text(content: &str) -> String {
+    tokenize(content)
+        .iter()
+        .map(|token| normalize(token.text))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn hash_value<T: Hash>(value: T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// MinHash signature over character shingles; words are too coarse for short
+/// lines, where one changed word would halve the similarity.
+fn signature(text: &str) -> [u64; SIGNATURE_SIZE] {
+    let chars: Vec<char> = text.chars().collect();
+    let shingles: HashSet<u64> = if chars.len() <= SHINGLE_CHARS {
+        HashSet::from([hash_value(text)])
+    } else {
+        chars.windows(SHINGLE_CHARS).map(hash_value).collect()
+    };
+
+    let mut signature = [u64::MAX; SIGNATURE_SIZE];
+    for (i, slot) in signature.iter_mut().enumerate() {
+        // Each row uses its own multiply-add permutation of the shingle hash.
+        let a = hash_value((i, "a")) | 1;
+        let b = hash_value((i, "b"));
+        for shingle in &shingles {
+            *slot = (*slot).min(shingle.wrapping_mul(a).wrapping_add(b));
+        }
+    }
+    signature
+}
+
+fn estimated_similarity(a: &[u64; SIGNATURE_SIZE], b: &[u64; SIGNATURE_SIZE]) -> f64 {
+    let agreeing = a.iter().zip(b).filter(|(x, y)| x == y).count();
+    agreeing as f64 / SIGNATURE_SIZE as f64
+}
+
+fn find(parents: &mut [usize], mut i: usize) -> usize {
+    while parents[i] != i {
+        parents[i] = parents[parents[i]];
+        i = parents[i];
+    }
+    i
+}
+
+fn union(parents: &mut [usize], a: usize, b: usize) {
+    let (a, b) = (find(parents, a), find(parents, b));
+    if a != b {
+        parents[a.max(b)] = a.min(b);
+    }
+}
+
+/// Groups distinct texts whose signatures agree closely, returning a cluster
+/// index for each text.
+fn cluster_texts(texts: &[&str]) -> Vec<usize> {
+    let signatures: Vec<[u64; SIGNATURE_SIZE]> = texts.iter().map(|text| signature(text)).collect();
+    let mut parents: Vec<usize> = (0..texts.len()).collect();
+
+    for band in 0..BANDS {
+        let mut buckets: HashMap<u64, Vec<usize>> = HashMap::new();
+        for (i, signature) in signatures.iter().enumerate() {
+            let rows = &signature[band * ROWS..(band + 1) * ROWS];
+            buckets.entry(hash_value(rows)).or_default().push(i);
+        }
+
+        for members in buckets.values().filter(|members| members.len() > 1) {
+            let all_pairs = members.len() * (members.len() - 1) / 2 <= MAX_BUCKET_PAIRS;
+            for (n, &a) in members.iter().enumerate() {
+                let others = if all_pairs {
+                    &members[n + 1..]
+                } else {
+                    &members[..1]
+                };
+                for &b in others.iter().filter(|&&b| b != a) {
+                    if estimated_similarity(&signatures[a], &signatures[b]) >= SIMILARITY_THRESHOLD
+                    {
+                        union(&mut parents, a, b);
+                    }
+                }
+            }
+        }
+    }
+
+    (0..texts.len()).map(|i| find(&mut parents, i)).collect()
+}
+
+/// Rebuilds the recurring-line clusters and returns how many were found.
+///
+/// Spoken lines are grouped by their normalized text, then near-identical
+/// texts are merged with MinHash and locality-sensitive hashing. A cluster is
+/// kept when its lines come from at least two episodes. The text shown for a
+/// cluster is its most common wording, the earliest on a tie.
+pub async fn rebuild_clusters(pool: &SqlitePool) -> Result<usize, sqlx::Error> {
+    let lines: Vec<SpokenLine> = sqlx::query_as(
+        r#"
+        SELECT l.id, l.season_id, l.episode_id, l.content
+        FROM lines l
+        JOIN seasons sn ON sn.id = l.season_id
+        JOIN episodes e ON e.id = l.episode_id
+        WHERE l.speaker_id IS NOT NULL
+        ORDER BY sn.series_id, sn.number, e.sort_key, l.line_number
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    // Lines are kept as indexes so a cluster's members can be put back into
+    // broadcast order.
+    let mut by_text: HashMap<String, Vec<usize>> = HashMap::new();
+    for (index, line) in lines.iter().enumerate() {
+        let text = normalized_text(&line.content);
+        if !text.is_empty() {
+            by_text.entry(text).or_default().push(index);
+        }
+    }
+
+    let texts: Vec<&str> = by_text.keys().map(String::as_str).collect();
+    let mut clusters: HashMap<usize, Vec<&str>> = HashMap::new();
+    for (text, cluster) in texts.iter().zip(cluster_texts(&texts)) {
+        clusters.entry(cluster).or_default().push(text);
+    }
+
+    let mut transaction = pool.begin().await?;
+    sqlx::query("DELETE FROM recurring_lines")
+        .execute(&mut *transaction)
+        .await?;
+    sqlx::query("DELETE FROM recurring_clusters")
+        .execute(&mut *transaction)
+        .await?;
+
+    let mut stored = 0;
+    for wordings in clusters.values() {
+        let mut indexes: Vec<usize> = wordings
+            .iter()
+            .flat_map(|text| by_text[*text].iter().copied())
+            .collect();
+        indexes.sort_unstable();
+        let members: Vec<&SpokenLine> = indexes.iter().map(|&index| &lines[index]).collect();
+        let episodes: HashSet<i64> = members.iter().map(|line| line.episode_id).collect();
+        if episodes.len() < 2 {
+            continue;
+        }
+        let seasons: HashSet<i64> = members.iter().map(|line| line.season_id).collect();
+
+        let mut wording_counts: HashMap<&str, usize> = HashMap::new();
+        for line in &members {
+            *wording_counts.entry(line.content.trim()).or_default() += 1;
+        }
+        // Reversed so that ties go to the earliest wording.
+        let Some(text) = members
+            .iter()
+            .rev()
+            .map(|line| line.content.trim())
+            .max_by_key(|text| wording_counts[text])
+        else {
+            continue;
+        };
+
+        let cluster_id: i64 = sqlx::query_scalar(
+            r#"
+            INSERT INTO recurring_clusters (text, occurrence_count, episode_count, season_count)
+            VALUES (?, ?, ?, ?)
+            RETURNING id
+            "#,
+        )
+        .bind(text)
+        .bind(members.len() as i64)
+        .bind(episodes.len() as i64)
+        .bind(seasons.len() as i64)
+        .fetch_one(&mut *transaction)
+        .await?;
+
+        for line in &members {
+            sqlx::query("INSERT INTO recurring_lines (line_id, cluster_id) VALUES (?, ?)")
+                .bind(line.id)
+                .bind(cluster_id)
+                .execute(&mut *transaction)
+                .await?;
+        }
+        stored += 1;
+    }
+
+    transaction.commit().await?;
+    Ok(stored)
+}
+
+/// Lists stored clusters, most repeated first or, with [`RecurringSort::Spread`],
+/// those spanning the most seasons first. Occurrences are not loaded.
+pub async fn list_clusters(
+    pool: &SqlitePool,
+    min_occurrences: i64,
+    min_seasons: i64,
+    sort: RecurringSort,
+    limit: i64,
+) -> Result<Vec<RecurringCluster>, sqlx::Error> {
+    let order = match sort {
+        RecurringSort::Frequency => "occurrence_count DESC, season_count DESC",
+        RecurringSort::Spread => "season_count DESC, episode_count DESC, occurrence_count DESC",
+    };
+    sqlx::query_as(&format!(
+        r#"
+        SELECT id, text, occurrence_count, episode_count, season_count
+        FROM recurring_clusters
+        WHERE occurrence_count >= ? AND season_count >= ?
+        ORDER BY {}, id
+        LIMIT ?
+        "#,
+        order
+    ))
+    .bind(min_occurrences)
+    .bind(min_seasons)
+    .bind(limit)
+    .fetch_all(pool)
+    .await
+}
+
+/// Maps each line in the given clusters to its cluster.
+pub async fn cluster_members(
+    pool: &SqlitePool,
+    cluster_ids: &[i64],
+) -> Result<HashMap<i64, i64>, sqlx::Error> {
+    let rows: Vec<(i64, i64)> = sqlx::query_as(
+        "SELECT line_id, cluster_id FROM recurring_lines WHERE cluster_id IN (SELECT value FROM json_each(?))",
+    )
+    .bind(serde_json::to_string(cluster_ids).unwrap_or_default())
+    .fetch_all(pool)
+    .await?;
+    Ok(rows.into_iter().collect())
+}
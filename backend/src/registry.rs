@@ -0,0 +1,243 @@
+use crate::db::{open_database, remove_database_files};
+use crate::workspace;
+use dashmap::DashMap;
+use sqlx::SqlitePool;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// A registered user database and the bookkeeping needed to expire it.
+pub struct DbEntry {
+    pub pool: SqlitePool,
+    pub path: PathBuf,
+    pub scratch: bool,
+    opened_at: Instant,
+    last_access_ms: AtomicU64,
+    active_requests: AtomicUsize,
+}
+
+impl DbEntry {
+    fn new(pool: SqlitePool, path: PathBuf, scratch: bool) -> Self {
+        Self {
+            pool,
+            path,
+            scratch,
+            opened_at: Instant::now(),
+            last_access_ms: AtomicU64::new(0),
+            active_requests: AtomicUsize::new(0),
+        }
+    }
+
+    fn touch(&self) {
+        let elapsed = self.opened_at.elapsed().as_millis() as u64;
+        self.last_access_ms.fetch_max(elapsed, Ordering::Relaxed);
+    }
+
+    /// How long the database has gone unused. Always zero while a request
+    /// holds a [`DbLease`] on it.
+    pub fn idle_for(&self) -> Duration {
+        if self.active_requests.load(Ordering::Acquire) > 0 {
+            return Duration::ZERO;
+        }
+        let last_access = Duration::from_millis(self.last_access_ms.load(Ordering::Relaxed));
+        self.opened_at.elapsed().saturating_sub(last_access)
+    }
+}
+
+/// Keeps a database from being reaped for as long as a request holds it.
+///
+/// Dropping the lease counts as a final access, so the idle clock starts
+/// when the request finishes rather than when it began.
+pub struct DbLease {
+    entry: Arc<DbEntry>,
+}
+
+impl DbLease {
+    fn acquire(entry: Arc<DbEntry>) -> Self {
+        entry.active_requests.fetch_add(1, Ordering::AcqRel);
+        entry.touch();
+        Self { entry }
+    }
+
+    pub fn pool(&self) -> &SqlitePool {
+        &self.entry.pool
+    }
+}
+
+impl Drop for DbLease {
+    fn drop(&mut self) {
+        self.entry.touch();
+        self.entry.active_requests.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// The single owner of every open user database pool.
+///
+/// Lookups only take a shard read lock, so concurrent requests for different
+/// users never wait on each other. Pools leave the registry through
+/// [`DatabaseRegistry::close`] or [`DatabaseRegistry::reap_idle`], which close
+/// them before any file is removed.
+#[derive(Default)]
+pub struct DatabaseRegistry {
+    entries: DashMap<String, Arc<DbEntry>>,
+    shares: DashMap<String, String>,
+}
+
+impl DatabaseRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&self, id: String, pool: SqlitePool, path: PathBuf, scratch: bool) {
+        self.entries
+            .insert(id, Arc::new(DbEntry::new(pool, path, scratch)));
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.entries.contains_key(id)
+    }
+
+    /// Returns the pool for `id` and records the access.
+    pub fn get(&self, id: &str) -> Option<SqlitePool> {
+        let entry = self.entries.get(id)?;
+        entry.touch();
+        Some(entry.pool.clone())
+    }
+
+    /// Returns the pool for `id`, reopening a workspace that was closed for
+    /// being idle.
+    pub async fn get_or_reopen(&self, id: &str) -> Option<SqlitePool> {
+        let lease = self.lease(id).await?;
+        Some(lease.pool().clone())
+    }
+
+    /// Like [`DatabaseRegistry::get_or_reopen`], but the database cannot be
+    /// reaped until the returned lease is dropped.
+    pub async fn lease(&self, id: &str) -> Option<DbLease> {
+        if id.is_empty() {
+            return None;
+        }
+        // The lease is taken under the shard lock, so the reaper's
+        // `remove_if` sees it before deciding the entry is idle.
+        if let Some(entry) = self.entries.get(id) {
+            return Some(DbLease::acquire(entry.clone()));
+        }
+        if !workspace::is_workspace(id) {
+            return None;
+        }
+
+        let db_path = workspace::workspace_path(id);
+        let pool = match open_database(&db_path).await {
+            Ok(pool) => pool,
+            Err(err) => {
+                eprintln!("Failed to reopen workspace {}: {}", id, err);
+                return None;
+            }
+        };
+
+        // Another request may have reopened the workspace while we connected.
+        let mut inserted = false;
+        let lease = {
+            let entry = self.entries.entry(id.to_string()).or_insert_with(|| {
+                inserted = true;
+                Arc::new(DbEntry::new(pool.clone(), db_path, false))
+            });
+            DbLease::acquire(entry.clone())
+        };
+        if !inserted {
+            pool.close().await;
+        }
+        Some(lease)
+    }
+
+    /// Publishes `share_id` as a read-only handle on `workspace_id`.
+    pub fn publish_share(&self, share_id: String, workspace_id: String) {
+        self.shares.insert(share_id, workspace_id);
+    }
+
+    pub fn revoke_share(&self, share_id: &str) {
+        self.shares.remove(share_id);
+    }
+
+    pub fn revoke_shares_for(&self, workspace_id: &str) {
+        self.shares.retain(|_, target| target != workspace_id);
+    }
+
+    /// Returns the workspace published under `share_id`.
+    pub fn share_target(&self, share_id: &str) -> Option<String> {
+        self.shares.get(share_id).map(|target| target.clone())
+    }
+
+    pub fn snapshot(&self) -> Vec<(String, Arc<DbEntry>)> {
+        self.entries
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect()
+    }
+
+    /// Removes `id` from the registry and waits for its pool to close.
+    pub async fn close(&self, id: &str) -> Option<Arc<DbEntry>> {
+        let (_, entry) = self.entries.remove(id)?;
+        entry.pool.close().await;
+        Some(entry)
+    }
+
+    /// Closes every database idle for longer than `ttl`. Databases leased by
+    /// an in-flight request are never idle.
+    ///
+    /// Scratch databases are deleted from disk. Workspace files are kept and
+    /// reopened on their next request.
+    pub async fn reap_idle(&self, ttl: Duration) {
+        let expired: Vec<String> = self
+            .entries
+            .iter()
+            .filter(|entry| entry.idle_for() > ttl)
+            .map(|entry| entry.key().clone())
+            .collect();
+
+        for id in expired {
+            let Some((_, entry)) = self
+                .entries
+                .remove_if(&id, |_, entry| entry.idle_for() > ttl)
+            else {
+                continue;
+            };
+
+            entry.pool.close().await;
+            if entry.scratch {
+                if let Err(err) = remove_database_files(&entry.path).await {
+                    eprintln!("Failed to remove expired database {}: {}", id, err);
+                }
+            }
+            println!(
+                "Expired idle database {} after {}s",
+                id,
+                entry.idle_for().as_secs()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn leased_databases_are_not_reaped() {
+        let registry = DatabaseRegistry::new();
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        registry.insert("db".to_string(), pool, PathBuf::from("unused"), false);
+
+        let lease = registry.lease("db").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        registry.reap_idle(Duration::ZERO).await;
+        assert!(registry.contains("db"));
+        assert!(!lease.pool().is_closed());
+
+        drop(lease);
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        registry.reap_idle(Duration::ZERO).await;
+        assert!(!registry.contains("db"));
+    }
+}
@@ -0,0 +1,333 @@
+use crate::metadata;
+use crate::models::Sentiment;
+use crate::text::{normalize, tokenize};
+use sqlx::SqlitePool;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+/// The metadata source name for rows written by this tagger.
+pub const LEXICON_SOURCE: &str = "lexicon";
+/// Bumped whenever the lexicons or scoring rules change, so stale rows can be
+/// found and re-tagged.
+pub const LEXICON_MODEL_VERSION: &str = "lexicon-1";
+
+const VALENCE_LEXICON: &str = include_str!("../lexicon/valence.tsv");
+const EMOTION_LEXICON: &str = include_str!("../lexicon/emotions.tsv");
+
+/// Plutchik's basic emotions, in the order used to break ties.
+pub const EMOTIONS: [&str; 8] = [
+    "joy",
+    "trust",
+    "anticipation",
+    "surprise",
+    "fear",
+    "sadness",
+    "anger",
+    "disgust",
+];
+
+// Scoring constants follow VADER (Hutto & Gilbert, 2014).
+const BOOSTER_INCREMENT: f64 = 0.293;
+const CAPS_INCREMENT: f64 = 0.733;
+const NEGATION_SCALAR: f64 = -0.74;
+const EXCLAMATION_INCREMENT: f64 = 0.292;
+const MAX_EXCLAMATIONS: usize = 4;
+const QUESTION_INCREMENT: f64 = 0.18;
+const MAX_QUESTION_BOOST: f64 = 0.96;
+const NORMALIZATION_ALPHA: f64 = 15.0;
+const NEUTRAL_THRESHOLD: f64 = 0.05;
+
+const NEGATIONS: &[&str] = &[
+    "not",
+    "no",
+    "never",
+    "nothing",
+    "nobody",
+    "none",
+    "neither",
+    "nor",
+    "nowhere",
+    "cannot",
+    "without",
+    "ain't",
+    "aren't",
+    "can't",
+    "couldn't",
+    "didn't",
+    "doesn't",
+    "don't",
+    "hadn't",
+    "hasn't",
+    "haven't",
+    "isn't",
+    "shouldn't",
+    "wasn't",
+    "weren't",
+    "won't",
+    "wouldn't",
+];
+
+const BOOSTERS: &[(&str, f64)] = &[
+    ("absolutely", BOOSTER_INCREMENT),
+    ("completely", BOOSTER_INCREMENT),
+    ("extremely", BOOSTER_INCREMENT),
+    ("incredibly", BOOSTER_INCREMENT),
+    ("really", BOOSTER_INCREMENT),
+    ("so", BOOSTER_INCREMENT),
+    ("super", BOOSTER_INCREMENT),
+    ("totally", BOOSTER_INCREMENT),
+    ("too", BOOSTER_INCREMENT),
+    ("very", BOOSTER_INCREMENT),
+    ("way", BOOSTER_INCREMENT),
+    ("barely", -BOOSTER_INCREMENT),
+    ("hardly", -BOOSTER_INCREMENT),
+    ("kinda", -BOOSTER_INCREMENT),
+    ("slightly", -BOOSTER_INCREMENT),
+    ("somewhat", -BOOSTER_INCREMENT),
+    ("sorta", -BOOSTER_INCREMENT),
+];
+
+/// Suffixes stripped, in order, when a word is not in a lexicon as written.
+const SUFFIXES: &[&str] = &["ies", "es", "s", "ed", "d", "ing", "ly"];
+
+struct Lexicon {
+    valence: HashMap<String, f64>,
+    emotions: HashMap<String, Vec<&'static str>>,
+}
+
+fn lexicon_entries(source: &'static str) -> impl Iterator<Item = (&'static str, &'static str)> {
+    source
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once('\t'))
+}
+
+fn lexicon() -> &'static Lexicon {
+    static LEXICON: OnceLock<Lexicon> = OnceLock::new();
+    LEXICON.get_or_init(|| {
+        let valence = lexicon_entries(VALENCE_LEXICON)
+            .filter_map(|(word, score)| Some((word.to_string(), score.trim().parse().ok()?)))
+            .collect();
+
+        let emotions = lexicon_entries(EMOTION_LEXICON)
+            .map(|(word, names)| {
+                let names = names
+                    .split(',')
+                    .filter_map(|name| EMOTIONS.iter().copied().find(|e| *e == name.trim()))
+                    .collect();
+                (word.to_string(), names)
+            })
+            .collect();
+
+        Lexicon { valence, emotions }
+    })
+}
+
+/// Looks a word up as written, then with common inflections removed.
+fn lookup<'a, T>(table: &'a HashMap<String, T>, word: &str) -> Option<&'a T> {
+    table.get(word).or_else(|| {
+        SUFFIXES.iter().find_map(|suffix| {
+            let stem = word.strip_suffix(suffix).filter(|stem| stem.len() > 2)?;
+            if *suffix == "ies" {
+                return table.get(&format!("{}y", stem));
+            }
+            table.get(stem)
+        })
+    })
+}
+
+/// The scores a line received from the lexicons.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Analysis {
+    /// Normalised valence in `[-1, 1]`.
+    pub compound: f64,
+    pub sentiment: Sentiment,
+    pub primary_emotion: Option<&'static str>,
+}
+
+fn is_shouting(word: &str) -> bool {
+    word.chars().filter(|c| c.is_alphabetic()).count() > 1
+        && !word.chars().any(|c| c.is_lowercase())
+}
+
+fn punctuation_boost(text: &str) -> f64 {
+    let exclamations = text.matches('!').count().min(MAX_EXCLAMATIONS);
+    let questions = text.matches('?').count();
+
+    let question_boost = match questions {
+        0 | 1 => 0.0,
+        2 | 3 => questions as f64 * QUESTION_INCREMENT,
+        _ => MAX_QUESTION_BOOST,
+    };
+
+    exclamations as f64 * EXCLAMATION_INCREMENT + question_boost
+}
+
+/// Scores a line of dialogue with the bundled valence and emotion lexicons.
+pub fn analyze(text: &str) -> Analysis {
+    let lexicon = lexicon();
+    let tokens = tokenize(text);
+    let words: Vec<String> = tokens.iter().map(|token| normalize(token.text)).collect();
+    let mixed_case = tokens.iter().any(|token| !is_shouting(token.text));
+
+    let mut scores = Vec::with_capacity(words.len());
+    let mut emotion_counts = [0usize; EMOTIONS.len()];
+    let mut but_index = None;
+
+    for (i, word) in words.iter().enumerate() {
+        if word == "but" {
+            but_index.get_or_insert(i);
+        }
+
+        let preceding = &words[i.saturating_sub(3)..i];
+        let negated = preceding.iter().any(|w| NEGATIONS.contains(&w.as_str()));
+
+        // A negated emotion word ("not scared") says little about the emotion
+        // actually expressed, so it is not counted.
+        if !negated {
+            for name in lookup(&lexicon.emotions, word).into_iter().flatten() {
+                if let Some(slot) = EMOTIONS.iter().position(|e| e == name) {
+                    emotion_counts[slot] += 1;
+                }
+            }
+        }
+
+        let Some(&base) = lookup(&lexicon.valence, word) else {
+            scores.push(0.0);
+            continue;
+        };
+
+        let mut valence = base;
+        if mixed_case && is_shouting(tokens[i].text) {
+            valence += CAPS_INCREMENT * valence.signum();
+        }
+
+        // Closer boosters count for more, as in VADER.
+        for (distance, previous) in preceding.iter().rev().enumerate() {
+            if let Some((_, increment)) = BOOSTERS.iter().find(|(b, _)| b == previous) {
+                let decay = 1.0 - 0.05 * distance as f64;
+                valence += increment * decay * valence.signum();
+            }
+        }
+
+        if negated {
+            valence *= NEGATION_SCALAR;
+        }
+
+        scores.push(valence);
+    }
+
+    // "but" shifts the weight of a sentence onto the clause that follows it.
+    if let Some(split) = but_index {
+        for (i, score) in scores.iter_mut().enumerate() {
+            *score *= if i < split { 0.5 } else { 1.5 };
+        }
+    }
+
+    let mut sum: f64 = scores.iter().sum();
+    if sum != 0.0 {
+        sum += punctuation_boost(text) * sum.signum();
+    }
+
+    let compound = (sum / (sum * sum + NORMALIZATION_ALPHA).sqrt()).clamp(-1.0, 1.0);
+    let sentiment = if compound >= NEUTRAL_THRESHOLD {
+        Sentiment::Positive
+    } else if compound <= -NEUTRAL_THRESHOLD {
+        Sentiment::Negative
+    } else {
+        Sentiment::Neutral
+    };
+
+    let primary_emotion = emotion_counts
+        .iter()
+        .enumerate()
+        .filter(|(_, count)| **count > 0)
+        .max_by(|(a_slot, a), (b_slot, b)| a.cmp(b).then(b_slot.cmp(a_slot)))
+        .map(|(slot, _)| EMOTIONS[slot]);
+
+    Analysis {
+        compound,
+        sentiment,
+        primary_emotion,
+    }
+}
+
+/// Tags lines with the lexicon analyzer and returns how many were written.
+///
+/// Lines already tagged by the current model version are skipped unless
+/// `retag` is set. Only the tagger's own rows are written; manual annotations
+/// are left alone.
+pub async fn tag_lines(pool: &SqlitePool, retag: bool) -> Result<usize, sqlx::Error> {
+    let lines: Vec<(i64, String)> = sqlx::query_as(
+        r#"
+        SELECT l.id, l.content
+        FROM lines l
+        LEFT JOIN metadata m ON m.line_id = l.id AND m.source = ?
+        WHERE ? OR m.id IS NULL OR m.model_version IS NOT ?
+        "#,
+    )
+    .bind(LEXICON_SOURCE)
+    .bind(retag)
+    .bind(LEXICON_MODEL_VERSION)
+    .fetch_all(pool)
+    .await?;
+
+    let mut transaction = pool.begin().await?;
+    for (line_id, content) in &lines {
+        let analysis = analyze(content);
+        metadata::upsert_tagged(
+            &mut transaction,
+            *line_id,
+            LEXICON_SOURCE,
+            LEXICON_MODEL_VERSION,
+            analysis.sentiment,
+            analysis.primary_emotion,
+        )
+        .await?;
+    }
+    transaction.commit().await?;
+
+    Ok(lines.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compound(text: &str) -> f64 {
+        analyze(text).compound
+    }
+
+    #[test]
+    fn negation_flips_valence() {
+        assert!(compound("good") > 0.0);
+        assert!(compound("not good") < 0.0);
+        assert!(compound("I don't hate it") > 0.0);
+    }
+
+    #[test]
+    fn boosters_strengthen_and_decay_with_distance() {
+        assert!(compound("very good") > compound("good"));
+        assert!(compound("very good") > compound("very uh good"));
+        assert!(compound("slightly good") < compound("good"));
+    }
+
+    #[test]
+    fn emphasis_strengthens_valence() {
+        assert!(compound("GOOD!") > compound("good"));
+        assert!(compound("that is GOOD") > compound("that is good"));
+    }
+
+    #[test]
+    fn clause_after_but_dominates() {
+        assert!(compound("bad but great") > 0.0);
+        assert!(compound("great but bad") < 0.0);
+    }
+
+    #[test]
+    fn inflections_fall_back_to_their_stem() {
+        assert_eq!(analyze("cheers").sentiment, Sentiment::Positive);
+        assert_eq!(analyze("enjoying").primary_emotion, Some("joy"));
+    }
+}
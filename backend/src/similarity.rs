@@ -0,0 +1,147 @@
+use crate::analysis::stopwords;
+use sqlx::{FromRow, SqlitePool};
+use std::collections::HashMap;
+
+// Okapi BM25 parameters, at their usual defaults.
+const K1: f64 = 1.2;
+const B: f64 = 0.75;
+
+#[derive(FromRow)]
+struct SourceLine {
+    episode_id: i64,
+    speaker_id: Option<i64>,
+}
+
+#[derive(FromRow)]
+struct CandidateTerm {
+    line_id: i64,
+    term: String,
+    term_count: i64,
+    line_length: i64,
+}
+
+/// Restricts which lines may be returned as similar.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SimilarityFilter {
+    pub same_speaker: bool,
+    pub exclude_episode: bool,
+}
+
+/// Ranks lines by BM25 against the terms of `line_id`, best match first.
+///
+/// The source line is the query: each of its terms counts once per use, and
+/// stopwords are ignored unless the line has nothing else. Only lines sharing
+/// at least one query term are scored. Returns line ids with their scores;
+/// the list is empty if the line does not exist or has no words.
+pub async fn similar_lines(
+    pool: &SqlitePool,
+    line_id: i64,
+    filter: SimilarityFilter,
+    limit: usize,
+) -> Result<Vec<(i64, f64)>, sqlx::Error> {
+    let Some(source) =
+        sqlx::query_as::<_, SourceLine>("SELECT episode_id, speaker_id FROM lines WHERE id = ?")
+            .bind(line_id)
+            .fetch_optional(pool)
+            .await?
+    else {
+        return Ok(Vec::new());
+    };
+
+    let terms: Vec<String> =
+        sqlx::query_scalar("SELECT term FROM tokens WHERE line_id = ? ORDER BY position")
+            .bind(line_id)
+            .fetch_all(pool)
+            .await?;
+
+    let stopwords = stopwords();
+    let mut query_terms: HashMap<String, i64> = HashMap::new();
+    for term in terms
+        .iter()
+        .filter(|term| !stopwords.contains(term.as_str()))
+    {
+        *query_terms.entry(term.clone()).or_default() += 1;
+    }
+    if query_terms.is_empty() {
+        for term in &terms {
+            *query_terms.entry(term.clone()).or_default() += 1;
+        }
+    }
+    if query_terms.is_empty() {
+        return Ok(Vec::new());
+    }
+    let term_json =
+        serde_json::to_string(&query_terms.keys().collect::<Vec<_>>()).unwrap_or_default();
+
+    let (line_total, token_total): (i64, i64) =
+        sqlx::query_as("SELECT COUNT(DISTINCT line_id), COUNT(*) FROM tokens")
+            .fetch_one(pool)
+            .await?;
+    let average_length = token_total as f64 / line_total.max(1) as f64;
+
+    let document_frequencies: HashMap<String, i64> = sqlx::query_as(
+        r#"
+        SELECT term, COUNT(DISTINCT line_id)
+        FROM tokens
+        WHERE term IN (SELECT value FROM json_each(?))
+        GROUP BY term
+        "#,
+    )
+    .bind(&term_json)
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .collect();
+
+    let candidates: Vec<CandidateTerm> = sqlx::query_as(
+        r#"
+        WITH matches AS (
+            SELECT t.line_id, t.term, COUNT(*) AS term_count
+            FROM tokens t
+            JOIN lines l ON l.id = t.line_id
+            WHERE t.term IN (SELECT value FROM json_each(?))
+                AND t.line_id <> ?
+                AND (NOT ? OR l.speaker_id IS ?)
+                AND (NOT ? OR l.episode_id <> ?)
+            GROUP BY t.line_id, t.term
+        )
+        SELECT
+            m.line_id,
+            m.term,
+            m.term_count,
+            (SELECT COUNT(*) FROM tokens d WHERE d.line_id = m.line_id) AS line_length
+        FROM matches m
+        "#,
+    )
+    .bind(&term_json)
+    .bind(line_id)
+    .bind(filter.same_speaker)
+    .bind(source.speaker_id)
+    .bind(filter.exclude_episode)
+    .bind(source.episode_id)
+    .fetch_all(pool)
+    .await?;
+
+    let mut scores: HashMap<i64, f64> = HashMap::new();
+    for candidate in &candidates {
+        let document_frequency = document_frequencies
+            .get(&candidate.term)
+            .copied()
+            .unwrap_or(1) as f64;
+        let idf = (1.0
+            + (line_total as f64 - document_frequency + 0.5) / (document_frequency + 0.5))
+            .ln();
+        let tf = candidate.term_count as f64;
+        let length_norm = 1.0 - B + B * candidate.line_length as f64 / average_length;
+        let weight = query_terms.get(&candidate.term).copied().unwrap_or(1) as f64;
+
+        *scores.entry(candidate.line_id).or_default() +=
+            weight * idf * tf * (K1 + 1.0) / (tf + K1 * length_norm);
+    }
+
+    let mut ranked: Vec<(i64, f64)> = scores.into_iter().collect();
+    ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+    ranked.truncate(limit);
+
+    Ok(ranked)
+}
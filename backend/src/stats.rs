@@ -0,0 +1,407 @@
+use crate::analysis::LineScope;
+use crate::models::{
+    Appearance, CorpusStats, DialogueStats, Episode, EpisodeStats, MatrixEpisode, SeasonLineCount,
+    Speaker, SpeakerMatrix, SpeakerShare, SpeakerStats,
+};
+use sqlx::{FromRow, SqlitePool};
+use std::collections::HashMap;
+
+/// Counts the words of line `l` from its stored tokens, so word totals agree
+/// with the tokenizer used by the vocabulary and metrics endpoints.
+pub const WORD_COUNT_SQL: &str = "(SELECT COUNT(*) FROM tokens wc WHERE wc.line_id = l.id)";
+
+#[derive(FromRow)]
+struct SpeakerTotals {
+    speaker_id: i64,
+    speaker_name: String,
+    line_count: i64,
+    word_count: i64,
+    episode_count: i64,
+    avg_line_length: f64,
+}
+
+#[derive(FromRow)]
+struct RankedAppearance {
+    speaker_id: i64,
+    is_first: bool,
+    is_last: bool,
+    #[sqlx(flatten)]
+    appearance: Appearance,
+}
+
+#[derive(FromRow)]
+struct SpeakerSeason {
+    speaker_id: i64,
+    #[sqlx(flatten)]
+    season: SeasonLineCount,
+}
+
+/// Aggregates line statistics per speaker, most talkative first. Both filters
+/// are optional; with a speaker id the result has at most one entry.
+pub async fn speaker_stats(
+    pool: &SqlitePool,
+    series: Option<i64>,
+    speaker: Option<i64>,
+) -> Result<Vec<SpeakerStats>, sqlx::Error> {
+    let scope = LineScope {
+        series,
+        speaker,
+        ..LineScope::default()
+    };
+    let totals: Vec<SpeakerTotals> = scope
+        .bind(sqlx::query_as(&format!(
+            r#"
+        SELECT
+            sp.id AS speaker_id,
+            sp.name AS speaker_name,
+            COUNT(l.id) AS line_count,
+            SUM({words}) AS word_count,
+            COUNT(DISTINCT l.episode_id) AS episode_count,
+            AVG(LENGTH(l.content)) AS avg_line_length
+        FROM lines l
+        JOIN seasons sn ON l.season_id = sn.id
+        JOIN speakers sp ON l.speaker_id = sp.id
+        WHERE {filter}
+        GROUP BY sp.id
+        ORDER BY line_count DESC, sp.name
+        "#,
+            words = WORD_COUNT_SQL,
+            filter = LineScope::FILTER,
+        )))
+        .fetch_all(pool)
+        .await?;
+
+    let appearances: Vec<RankedAppearance> = scope
+        .bind(sqlx::query_as(&format!(
+            r#"
+        WITH ranked AS (
+            SELECT
+                l.speaker_id,
+                sr.id AS series_id,
+                sr.name AS series,
+                sn.id AS season_id,
+                sn.number AS season,
+                e.id AS episode_id,
+                e.code AS episode,
+                e.title,
+                ROW_NUMBER() OVER (
+                    PARTITION BY l.speaker_id
+                    ORDER BY sr.id, sn.number, e.sort_key
+                ) AS first_rank,
+                ROW_NUMBER() OVER (
+                    PARTITION BY l.speaker_id
+                    ORDER BY sr.id DESC, sn.number DESC, e.sort_key DESC
+                ) AS last_rank
+            FROM lines l
+            JOIN episodes e ON l.episode_id = e.id
+            JOIN seasons sn ON l.season_id = sn.id
+            JOIN series sr ON sn.series_id = sr.id
+            WHERE l.speaker_id IS NOT NULL AND {filter}
+        )
+        SELECT
+            speaker_id, series_id, series, season_id, season, episode_id, episode, title,
+            first_rank = 1 AS is_first,
+            last_rank = 1 AS is_last
+        FROM ranked
+        WHERE first_rank = 1 OR last_rank = 1
+        "#,
+            filter = LineScope::FILTER,
+        )))
+        .fetch_all(pool)
+        .await?;
+
+    let seasons: Vec<SpeakerSeason> = scope
+        .bind(sqlx::query_as(&format!(
+            r#"
+        SELECT
+            l.speaker_id,
+            sn.series_id,
+            sn.id AS season_id,
+            sn.number AS season,
+            COUNT(*) AS line_count
+        FROM lines l
+        JOIN seasons sn ON l.season_id = sn.id
+        WHERE l.speaker_id IS NOT NULL AND {filter}
+        GROUP BY l.speaker_id, sn.id
+        ORDER BY sn.series_id, sn.number
+        "#,
+            filter = LineScope::FILTER,
+        )))
+        .fetch_all(pool)
+        .await?;
+
+    let mut first = HashMap::new();
+    let mut last = HashMap::new();
+    for ranked in appearances {
+        if ranked.is_first {
+            first.insert(ranked.speaker_id, ranked.appearance.clone());
+        }
+        if ranked.is_last {
+            last.insert(ranked.speaker_id, ranked.appearance);
+        }
+    }
+
+    let mut per_season: HashMap<i64, Vec<SeasonLineCount>> = HashMap::new();
+    for row in seasons {
+        per_season
+            .entry(row.speaker_id)
+            .or_default()
+            .push(row.season);
+    }
+
+    Ok(totals
+        .into_iter()
+        .map(|totals| SpeakerStats {
+            speaker_id: totals.speaker_id,
+            avg_words_per_line: totals.word_count as f64 / totals.line_count.max(1) as f64,
+            first_appearance: first.remove(&totals.speaker_id),
+            last_appearance: last.remove(&totals.speaker_id),
+            seasons: per_season.remove(&totals.speaker_id).unwrap_or_default(),
+            speaker_name: totals.speaker_name,
+            line_count: totals.line_count,
+            word_count: totals.word_count,
+            episode_count: totals.episode_count,
+            avg_line_length: totals.avg_line_length,
+        })
+        .collect())
+}
+
+#[derive(FromRow)]
+struct LineTotals {
+    line_count: i64,
+    dialogue_line_count: i64,
+    direction_line_count: i64,
+    word_count: i64,
+    dialogue_word_count: i64,
+    speaker_count: i64,
+}
+
+/// Computes dialogue totals and the `top` speakers by share of dialogue for
+/// the lines in scope.
+async fn dialogue_stats(
+    pool: &SqlitePool,
+    scope: LineScope,
+    top: i64,
+) -> Result<DialogueStats, sqlx::Error> {
+    let totals: LineTotals = scope
+        .bind(sqlx::query_as(&format!(
+            r#"
+        SELECT
+            COUNT(*) AS line_count,
+            COALESCE(SUM(l.speaker_id IS NOT NULL), 0) AS dialogue_line_count,
+            COALESCE(SUM(l.speaker_id IS NULL), 0) AS direction_line_count,
+            COALESCE(SUM({words}), 0) AS word_count,
+            COALESCE(SUM(CASE WHEN l.speaker_id IS NOT NULL THEN {words} ELSE 0 END), 0)
+                AS dialogue_word_count,
+            COUNT(DISTINCT l.speaker_id) AS speaker_count
+        FROM lines l
+        JOIN seasons sn ON l.season_id = sn.id
+        WHERE {filter}
+        "#,
+            words = WORD_COUNT_SQL,
+            filter = LineScope::FILTER,
+        )))
+        .fetch_one(pool)
+        .await?;
+
+    let vocabulary_size: i64 = scope
+        .bind(sqlx::query_scalar(&format!(
+            r#"
+        SELECT COUNT(DISTINCT t.term)
+        FROM tokens t
+        JOIN lines l ON t.line_id = l.id
+        JOIN seasons sn ON l.season_id = sn.id
+        WHERE {filter}
+        "#,
+            filter = LineScope::FILTER,
+        )))
+        .fetch_one(pool)
+        .await?;
+
+    let mut top_speakers: Vec<SpeakerShare> = scope
+        .bind(sqlx::query_as(&format!(
+            r#"
+        SELECT
+            sp.id AS speaker_id,
+            sp.name AS speaker_name,
+            COUNT(*) AS line_count,
+            SUM({words}) AS word_count
+        FROM lines l
+        JOIN seasons sn ON l.season_id = sn.id
+        JOIN speakers sp ON l.speaker_id = sp.id
+        WHERE {filter}
+        GROUP BY sp.id
+        ORDER BY word_count DESC, line_count DESC, sp.name
+        LIMIT ?
+        "#,
+            words = WORD_COUNT_SQL,
+            filter = LineScope::FILTER,
+        )))
+        .bind(top)
+        .fetch_all(pool)
+        .await?;
+
+    for speaker in &mut top_speakers {
+        speaker.share = speaker.word_count as f64 / totals.dialogue_word_count.max(1) as f64;
+    }
+
+    Ok(DialogueStats {
+        direction_ratio: totals.direction_line_count as f64
+            / totals.dialogue_line_count.max(1) as f64,
+        line_count: totals.line_count,
+        dialogue_line_count: totals.dialogue_line_count,
+        direction_line_count: totals.direction_line_count,
+        word_count: totals.word_count,
+        dialogue_word_count: totals.dialogue_word_count,
+        speaker_count: totals.speaker_count,
+        vocabulary_size,
+        top_speakers,
+    })
+}
+
+/// Statistics for one episode, or `None` if it does not exist.
+pub async fn episode_stats(
+    pool: &SqlitePool,
+    episode_id: i64,
+    top: i64,
+) -> Result<Option<EpisodeStats>, sqlx::Error> {
+    let episode: Option<Episode> = sqlx::query_as(
+        "SELECT id, season_id, number, code, kind, sort_key, title FROM episodes WHERE id = ?",
+    )
+    .bind(episode_id)
+    .fetch_optional(pool)
+    .await?;
+    let Some(episode) = episode else {
+        return Ok(None);
+    };
+
+    let scope = LineScope {
+        episode: Some(episode_id),
+        ..LineScope::default()
+    };
+    let stats = dialogue_stats(pool, scope, top).await?;
+    Ok(Some(EpisodeStats { episode, stats }))
+}
+
+/// Statistics for the whole workspace, optionally narrowed to a series or
+/// season.
+pub async fn corpus_stats(
+    pool: &SqlitePool,
+    series: Option<i64>,
+    season: Option<i64>,
+    top: i64,
+) -> Result<CorpusStats, sqlx::Error> {
+    let (series_count, season_count, episode_count): (i64, i64, i64) = sqlx::query_as(
+        r#"
+        SELECT COUNT(DISTINCT sn.series_id), COUNT(DISTINCT sn.id), COUNT(e.id)
+        FROM seasons sn
+        LEFT JOIN episodes e ON e.season_id = sn.id
+        WHERE (? IS NULL OR sn.series_id = ?) AND (? IS NULL OR sn.id = ?)
+        "#,
+    )
+    .bind(series)
+    .bind(series)
+    .bind(season)
+    .bind(season)
+    .fetch_one(pool)
+    .await?;
+
+    let scope = LineScope {
+        series,
+        season,
+        ..LineScope::default()
+    };
+    let stats = dialogue_stats(pool, scope, top).await?;
+    Ok(CorpusStats {
+        series_count,
+        season_count,
+        episode_count,
+        stats,
+    })
+}
+
+/// Builds the episode-by-speaker line count matrix for the `speakers` most
+/// talkative speakers in scope, with episodes in broadcast order.
+pub async fn speaker_matrix(
+    pool: &SqlitePool,
+    series: Option<i64>,
+    season: Option<i64>,
+    speakers: i64,
+) -> Result<SpeakerMatrix, sqlx::Error> {
+    let episodes: Vec<MatrixEpisode> = sqlx::query_as(
+        r#"
+        SELECT e.id, sn.series_id, sn.id AS season_id, sn.number AS season, e.code, e.title
+        FROM episodes e
+        JOIN seasons sn ON e.season_id = sn.id
+        WHERE (? IS NULL OR sn.series_id = ?) AND (? IS NULL OR sn.id = ?)
+        ORDER BY sn.series_id, sn.number, e.sort_key
+        "#,
+    )
+    .bind(series)
+    .bind(series)
+    .bind(season)
+    .bind(season)
+    .fetch_all(pool)
+    .await?;
+
+    let scope = LineScope {
+        series,
+        season,
+        ..LineScope::default()
+    };
+    let speakers: Vec<Speaker> = scope
+        .bind(sqlx::query_as(&format!(
+            r#"
+        SELECT sp.id, sp.name
+        FROM lines l
+        JOIN seasons sn ON l.season_id = sn.id
+        JOIN speakers sp ON l.speaker_id = sp.id
+        WHERE {filter}
+        GROUP BY sp.id
+        ORDER BY COUNT(*) DESC, sp.name
+        LIMIT ?
+        "#,
+            filter = LineScope::FILTER,
+        )))
+        .bind(speakers)
+        .fetch_all(pool)
+        .await?;
+
+    let counts: Vec<(i64, i64, i64)> = scope
+        .bind(sqlx::query_as(&format!(
+            r#"
+        SELECT l.episode_id, l.speaker_id, COUNT(*)
+        FROM lines l
+        JOIN seasons sn ON l.season_id = sn.id
+        WHERE l.speaker_id IS NOT NULL AND {filter}
+        GROUP BY l.episode_id, l.speaker_id
+        "#,
+            filter = LineScope::FILTER,
+        )))
+        .fetch_all(pool)
+        .await?;
+
+    let rows: HashMap<i64, usize> = episodes
+        .iter()
+        .enumerate()
+        .map(|(row, episode)| (episode.id, row))
+        .collect();
+    let columns: HashMap<i64, usize> = speakers
+        .iter()
+        .enumerate()
+        .map(|(column, speaker)| (speaker.id, column))
+        .collect();
+
+    let mut line_counts = vec![vec![0; speakers.len()]; episodes.len()];
+    for (episode_id, speaker_id, count) in counts {
+        if let (Some(&row), Some(&column)) = (rows.get(&episode_id), columns.get(&speaker_id)) {
+            line_counts[row][column] = count;
+        }
+    }
+
+    Ok(SpeakerMatrix {
+        episodes,
+        speakers,
+        line_counts,
+    })
+}
@@ -0,0 +1,117 @@
+use sqlx::{Connection, SqliteConnection};
+
+/// A word in a line of dialogue, with its byte offsets in the original text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Token<'a> {
+    pub text: &'a str,
+    pub start: usize,
+    pub end: usize,
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric()
+}
+
+fn is_joiner(c: char) -> bool {
+    matches!(c, '\'' | '\u{2019}' | '-')
+}
+
+/// Splits text into words. Apostrophes and hyphens count as part of a word only
+/// between two word characters, so "don't" and "half-baked" stay whole while
+/// quotes and dashes around words are dropped.
+pub fn tokenize(text: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        if is_word_char(c) {
+            start.get_or_insert(i);
+            continue;
+        }
+
+        let joins_word = start.is_some()
+            && is_joiner(c)
+            && chars.peek().is_some_and(|&(_, next)| is_word_char(next));
+        if joins_word {
+            continue;
+        }
+
+        if let Some(begin) = start.take() {
+            tokens.push(Token {
+                text: &text[begin..i],
+                start: begin,
+                end: i,
+            });
+        }
+    }
+
+    if let Some(begin) = start {
+        tokens.push(Token {
+            text: &text[begin..],
+            start: begin,
+            end: text.len(),
+        });
+    }
+
+    tokens
+}
+
+/// Lowercases a word and folds typographic apostrophes, so lookups do not
+/// depend on how a transcript was typed.
+pub fn normalize(word: &str) -> String {
+    word.to_lowercase().replace('\u{2019}', "'")
+}
+
+/// Replaces the stored tokens of a line with those of `content`.
+pub async fn store_tokens(
+    conn: &mut SqliteConnection,
+    line_id: i64,
+    content: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM tokens WHERE line_id = ?")
+        .bind(line_id)
+        .execute(&mut *conn)
+        .await?;
+
+    for (position, token) in tokenize(content).iter().enumerate() {
+        sqlx::query(
+            "INSERT INTO tokens (line_id, position, term, start_offset, end_offset) VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(line_id)
+        .bind(position as i64)
+        .bind(normalize(token.text))
+        .bind(token.start as i64)
+        .bind(token.end as i64)
+        .execute(&mut *conn)
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Tokenizes lines stored before the token table existed, returning how many
+/// lines were indexed.
+pub async fn index_untokenized_lines(conn: &mut SqliteConnection) -> Result<usize, sqlx::Error> {
+    let lines: Vec<(i64, String)> = sqlx::query_as(
+        "SELECT id, content FROM lines l WHERE NOT EXISTS (SELECT 1 FROM tokens t WHERE t.line_id = l.id)",
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let pending: Vec<&(i64, String)> = lines
+        .iter()
+        .filter(|(_, content)| !tokenize(content).is_empty())
+        .collect();
+    if pending.is_empty() {
+        return Ok(0);
+    }
+
+    let mut transaction = conn.begin().await?;
+    for (line_id, content) in &pending {
+        store_tokens(&mut transaction, *line_id, content).await?;
+    }
+    transaction.commit().await?;
+
+    Ok(pending.len())
+}
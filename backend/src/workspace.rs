@@ -0,0 +1,196 @@
+use crate::db::{open_database, remove_database_files, setup_database, upgrade_imported_database};
+use crate::models::{Share, Workspace};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use rand::RngCore;
+use sqlx::{Connection, SqliteConnection, SqlitePool};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use uuid::Uuid;
+
+pub const WORKSPACES_DIR: &str = "./workspaces";
+pub const SCRATCH_DIR: &str = "./temp_dbs";
+
+pub const DEFAULT_WORKSPACE_NAME: &str = "Untitled workspace";
+const MAX_NAME_LEN: usize = 255;
+
+const WORKSPACE_INFO_QUERY: &str =
+    "SELECT ? AS id, name, created_at FROM workspace_info WHERE id = 1";
+
+pub fn workspace_path(id: &str) -> PathBuf {
+    Path::new(WORKSPACES_DIR).join(format!("{}.sqlite", id))
+}
+
+/// Returns true if `id` names a persistent workspace rather than a scratch database.
+pub fn is_workspace(id: &str) -> bool {
+    Uuid::parse_str(id).is_ok() && workspace_path(id).exists()
+}
+
+/// Trims a requested workspace name and rejects empty or oversized names.
+pub fn validate_name(name: &str) -> Result<String, String> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err("Workspace name must not be empty".to_string());
+    }
+    if name.len() > MAX_NAME_LEN {
+        return Err(format!(
+            "Workspace name must be at most {} bytes",
+            MAX_NAME_LEN
+        ));
+    }
+    Ok(name.to_string())
+}
+
+pub async fn create_workspace(
+    name: &str,
+) -> Result<(Workspace, SqlitePool), Box<dyn std::error::Error>> {
+    let id = Uuid::new_v4().to_string();
+    let (pool, _path) = setup_database(Path::new(WORKSPACES_DIR), &id).await?;
+
+    sqlx::query("INSERT INTO workspace_info (id, name) VALUES (1, ?)")
+        .bind(name)
+        .execute(&pool)
+        .await?;
+
+    let workspace = fetch_workspace(&id, &pool).await?;
+    Ok((workspace, pool))
+}
+
+/// Turns an exported database file into a new workspace.
+///
+/// The file is checked and migrated to the current schema before it is moved
+/// into place. Share IDs are not carried over, and `name` replaces the exported
+/// workspace name when given.
+pub async fn import_workspace(
+    file_path: &Path,
+    name: Option<&str>,
+) -> Result<(Workspace, SqlitePool), Box<dyn std::error::Error>> {
+    upgrade_imported_database(file_path).await?;
+
+    let id = Uuid::new_v4().to_string();
+    let db_path = workspace_path(&id);
+    fs::create_dir_all(WORKSPACES_DIR).await?;
+    if fs::rename(file_path, &db_path).await.is_err() {
+        fs::copy(file_path, &db_path).await?;
+        fs::remove_file(file_path).await?;
+    }
+
+    let pool = open_database(&db_path).await?;
+    sqlx::query(
+        r#"
+        INSERT INTO workspace_info (id, name) VALUES (1, COALESCE(?, ?))
+        ON CONFLICT(id) DO UPDATE SET name = COALESCE(?, workspace_info.name)
+        "#,
+    )
+    .bind(name)
+    .bind(DEFAULT_WORKSPACE_NAME)
+    .bind(name)
+    .execute(&pool)
+    .await?;
+    sqlx::query("DELETE FROM shares").execute(&pool).await?;
+
+    let workspace = fetch_workspace(&id, &pool).await?;
+    Ok((workspace, pool))
+}
+
+pub async fn fetch_workspace(id: &str, pool: &SqlitePool) -> Result<Workspace, sqlx::Error> {
+    sqlx::query_as::<_, Workspace>(WORKSPACE_INFO_QUERY)
+        .bind(id)
+        .fetch_one(pool)
+        .await
+}
+
+/// Reads a workspace's details over a short-lived connection, for workspaces
+/// whose pool has been closed for being idle.
+pub async fn read_workspace(id: &str) -> Result<Workspace, sqlx::Error> {
+    let url = format!("sqlite://{}", workspace_path(id).to_string_lossy());
+    let mut conn = SqliteConnection::connect(&url).await?;
+    let workspace = sqlx::query_as::<_, Workspace>(WORKSPACE_INFO_QUERY)
+        .bind(id)
+        .fetch_one(&mut conn)
+        .await;
+    conn.close().await?;
+    workspace
+}
+
+pub async fn rename_workspace(
+    id: &str,
+    pool: &SqlitePool,
+    name: &str,
+) -> Result<Workspace, sqlx::Error> {
+    sqlx::query("UPDATE workspace_info SET name = ? WHERE id = 1")
+        .bind(name)
+        .execute(pool)
+        .await?;
+
+    fetch_workspace(id, pool).await
+}
+
+/// Publishes the workspace under a new random share ID.
+pub async fn create_share(pool: &SqlitePool) -> Result<Share, sqlx::Error> {
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let share_id = URL_SAFE_NO_PAD.encode(bytes);
+
+    sqlx::query_as::<_, Share>("INSERT INTO shares (id) VALUES (?) RETURNING id, created_at")
+        .bind(share_id)
+        .fetch_one(pool)
+        .await
+}
+
+pub async fn list_shares(pool: &SqlitePool) -> Result<Vec<Share>, sqlx::Error> {
+    sqlx::query_as::<_, Share>("SELECT id, created_at FROM shares ORDER BY created_at")
+        .fetch_all(pool)
+        .await
+}
+
+/// Removes a share ID, returning false if the workspace never published it.
+pub async fn revoke_share(pool: &SqlitePool, share_id: &str) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query("DELETE FROM shares WHERE id = ?")
+        .bind(share_id)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Removes a workspace's database file. Its pool must already be closed.
+pub async fn delete_workspace(id: &str) -> std::io::Result<()> {
+    remove_database_files(&workspace_path(id)).await
+}
+
+/// Lists the ids of every workspace database on disk.
+pub async fn workspace_ids() -> std::io::Result<Vec<String>> {
+    fs::create_dir_all(WORKSPACES_DIR).await?;
+
+    let mut ids = Vec::new();
+    let mut entries = fs::read_dir(WORKSPACES_DIR).await?;
+
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if let Some(id) = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_suffix(".sqlite"))
+            .filter(|id| Uuid::parse_str(id).is_ok())
+        {
+            ids.push(id.to_string());
+        }
+    }
+
+    Ok(ids)
+}
+
+/// Opens every workspace database found on disk so it can be registered at startup.
+pub async fn load_workspaces() -> Result<Vec<(String, SqlitePool)>, Box<dyn std::error::Error>> {
+    let mut workspaces = Vec::new();
+
+    for id in workspace_ids().await? {
+        match setup_database(Path::new(WORKSPACES_DIR), &id).await {
+            Ok((pool, _path)) => workspaces.push((id, pool)),
+            Err(err) => eprintln!("Failed to open workspace {}: {}", id, err),
+        }
+    }
+
+    println!("Loaded {} workspace(s)", workspaces.len());
+    Ok(workspaces)
+}